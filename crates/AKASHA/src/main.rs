@@ -1,16 +1,21 @@
+use anahata_engine::track;
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use eframe::egui;
 use eframe::egui::ColorImage;
 use egui::{TextureHandle, TextureOptions};
 use image::imageops::{self, FilterType};
-use image::{load_from_memory, ImageReader};
-use metaflac::{Block, Tag};
+use image::load_from_memory;
+use my_common::analysis::AnalysisCache;
+use my_common::key::Key;
+use my_common::metadata::{self, TrackMetadata};
+use my_common::session::{LibrarySession, SessionStore};
 use nats::Connection;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fs, thread};
 
@@ -19,11 +24,29 @@ enum UiMessage {
     Increment,
     Decrement,
     Select(u32),
+    /// A deck finished `after` and wants whatever follows it
+    Next {
+        player: u32,
+        after: PathBuf,
+    },
+    DeckKey {
+        player: u32,
+        key: Key,
+        path: PathBuf,
+    },
+    /// What the library scan worked out for a track without a key
+    Scanned {
+        path: PathBuf,
+        key: Option<Key>,
+        bpm: Option<f64>,
+    },
 }
 
 struct SelectMessage {
     player: u32,
     file_path: String,
+    /// The answer to the deck's `akasha.N.next`, the deck plays it when in
+    next: bool,
 }
 
 enum File {
@@ -31,11 +54,22 @@ enum File {
     Dir(PathBuf),
 }
 
+impl File {
+    fn path(&self) -> &PathBuf {
+        match self {
+            File::FlacFile(flac) => &flac.path,
+            File::Dir(path) => path,
+        }
+    }
+}
+
 struct FlacFile {
     path: PathBuf,
     title: Option<String>,
     artist: Option<String>,
     album: Option<String>,
+    key: Option<Key>,
+    bpm: Option<f64>,
     inline_album_art: Option<egui::TextureHandle>,
     large_album_art: Option<egui::TextureHandle>,
 }
@@ -47,6 +81,9 @@ fn main() {
 
     let nats_client = nats::connect("nats://localhost:4222").unwrap();
     let nats_client_for_thread = nats_client.clone();
+    let nats_client_for_keys = nats_client.clone();
+    let key_sender = ui_sender.clone();
+    let scan_sender = ui_sender.clone();
 
     thread::spawn(move || {
         for message in nats_client_for_thread
@@ -69,6 +106,19 @@ fn main() {
                 }
             }
 
+            if message.subject.ends_with(".next") {
+                if let Some(player) = message
+                    .subject
+                    .split('.')
+                    .nth(1)
+                    .and_then(|n| n.parse::<u32>().ok())
+                {
+                    let after = PathBuf::from(String::from_utf8_lossy(&message.data).into_owned());
+                    ui_sender.send(UiMessage::Next { player, after }).unwrap();
+                    continue;
+                }
+            }
+
             // Handle other messages as before
             match message.data.as_slice() {
                 b"Clockwise" => ui_sender.send(UiMessage::Increment).unwrap(),
@@ -78,11 +128,39 @@ fn main() {
         }
     });
 
+    // Decks announce the key of whatever they just loaded as "<camelot>,<path>"
+    thread::spawn(move || {
+        for message in nats_client_for_keys
+            .subscribe("anahata.*.key")
+            .unwrap()
+            .messages()
+        {
+            let player = message
+                .subject
+                .split('.')
+                .nth(1)
+                .and_then(|n| n.parse::<u32>().ok());
+            let content = String::from_utf8_lossy(&message.data);
+            let parsed = content
+                .split_once(',')
+                .and_then(|(key, path)| Some((Key::parse(key)?, PathBuf::from(path))));
+            if let (Some(player), Some((key, path))) = (player, parsed) {
+                key_sender
+                    .send(UiMessage::DeckKey { player, key, path })
+                    .unwrap();
+            }
+        }
+    });
+
     thread::spawn(move || {
         while let Ok(select_message) = select_receiver.recv() {
             nats_client
                 .publish(
-                    &format!("anahata.{}.select", select_message.player),
+                    &if select_message.next {
+                        format!("anahata.{}.select.next", select_message.player)
+                    } else {
+                        format!("anahata.{}.select", select_message.player)
+                    },
                     select_message.file_path.as_bytes(),
                 )
                 .unwrap();
@@ -97,6 +175,7 @@ fn main() {
                 cc,
                 ui_receiver,
                 select_sender,
+                scan_sender,
             )))
         }),
     );
@@ -110,6 +189,13 @@ struct FileSelectorApp {
     current_dir: PathBuf,
     parent_exists: bool,
     album_art_cache: HashMap<u64, (TextureHandle, TextureHandle)>,
+    deck_keys: HashMap<u32, Key>,
+    analysis_cache: Option<AnalysisCache>,
+    sessions: Option<SessionStore>,
+    saved_session: Option<LibrarySession>,
+    scan_sender: Sender<UiMessage>,
+    // Set to stop the scan of a folder we have left
+    scan_cancel: Arc<AtomicBool>,
 }
 
 impl FileSelectorApp {
@@ -117,9 +203,23 @@ impl FileSelectorApp {
         cc: &eframe::CreationContext<'_>,
         ui_receiver: Receiver<UiMessage>,
         select_sender: Sender<SelectMessage>,
+        scan_sender: Sender<UiMessage>,
     ) -> Self {
         let mut album_art_cache = HashMap::new();
-        let mut files: Vec<File> = fs::read_dir("./music")
+        // Filled in by the decks when they load a track and by our scan
+        let analysis_cache = AnalysisCache::open_default().ok();
+        // Back on the entry it had selected after a restart. There is no way
+        // up out of a folder, so a session in a subfolder reopens at the top
+        // on the folder it was in
+        let sessions = SessionStore::open_default()
+            .map_err(|e| eprintln!("Sessions unavailable, not restoring: {}", e))
+            .ok();
+        let restored = sessions
+            .as_ref()
+            .and_then(|sessions| sessions.load::<LibrarySession>("akasha"))
+            .filter(|session| session.dir.is_dir());
+        let current_dir = PathBuf::from("./music");
+        let files: Vec<File> = fs::read_dir(&current_dir)
             .unwrap()
             .filter_map(|entry| {
                 let entry = entry.unwrap();
@@ -129,6 +229,7 @@ impl FileSelectorApp {
                         &path,
                         &cc.egui_ctx,
                         &mut album_art_cache,
+                        analysis_cache.as_ref(),
                     )))
                 } else if path.is_dir() {
                     Some(File::Dir(path))
@@ -137,15 +238,77 @@ impl FileSelectorApp {
                 }
             })
             .collect();
+        let selected_index = restored
+            .and_then(|session| {
+                if session.dir == current_dir {
+                    session.selected
+                } else {
+                    Some(session.dir)
+                }
+            })
+            .and_then(|selected| {
+                files
+                    .iter()
+                    .position(|file| selected.starts_with(file.path()))
+            })
+            .unwrap_or(0);
 
-        Self {
+        let mut app = Self {
             ui_receiver,
             select_sender,
             files,
-            selected_index: 0,
+            selected_index,
             album_art_cache,
+            deck_keys: HashMap::new(),
+            analysis_cache,
+            sessions,
+            saved_session: None,
+            scan_sender,
+            scan_cancel: Arc::new(AtomicBool::new(false)),
             parent_exists: false,
-            current_dir: PathBuf::from("./music"),
+            current_dir,
+        };
+        app.start_scan();
+        app
+    }
+
+    // Works out keys for the tracks in the listing that have none yet
+    fn start_scan(&mut self) {
+        self.scan_cancel.store(true, Ordering::Relaxed);
+        self.scan_cancel = Arc::new(AtomicBool::new(false));
+        let paths: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter_map(|file| match file {
+                File::FlacFile(flac) if flac.key.is_none() => Some(flac.path.clone()),
+                _ => None,
+            })
+            .collect();
+        if paths.is_empty() {
+            return;
+        }
+        let cancel = self.scan_cancel.clone();
+        let sender = self.scan_sender.clone();
+        thread::spawn(move || scan_keys(paths, cancel, sender));
+    }
+
+    // Only writes when the folder or the selection has changed
+    fn save_session(&mut self) {
+        let Some(sessions) = &self.sessions else {
+            return;
+        };
+        let session = LibrarySession {
+            dir: self.current_dir.clone(),
+            selected: self
+                .files
+                .get(self.selected_index)
+                .map(|file| file.path().clone()),
+        };
+        if self.saved_session.as_ref() != Some(&session) {
+            if let Err(e) = sessions.save("akasha", &session) {
+                eprintln!("Failed to save session: {}", e);
+            }
+            self.saved_session = Some(session);
         }
     }
     fn navigate_to(&mut self, path: PathBuf, ctx: &egui::Context) {
@@ -155,6 +318,7 @@ impl FileSelectorApp {
         self.files = files;
         self.album_art_cache = cache;
         self.selected_index = 0;
+        self.start_scan();
     }
 
     fn load_directory(
@@ -175,6 +339,7 @@ impl FileSelectorApp {
                         &path,
                         ctx,
                         &mut album_art_cache,
+                        self.analysis_cache.as_ref(),
                     )));
                 }
             }
@@ -203,14 +368,72 @@ impl eframe::App for FileSelectorApp {
                         self.selected_index -= 1;
                     }
                 }
+                UiMessage::DeckKey { player, key, path } => {
+                    self.deck_keys.insert(player, key);
+                    // Remember detected keys for tracks that were not tagged
+                    for file in self.files.iter_mut() {
+                        if let File::FlacFile(flac) = file {
+                            if flac.path == path && flac.key.is_none() {
+                                flac.key = Some(key);
+                                flac.bpm = self
+                                    .analysis_cache
+                                    .as_ref()
+                                    .and_then(|cache| cache.lookup(&path))
+                                    .and_then(|analysis| analysis.bpm);
+                            }
+                        }
+                    }
+                }
+                UiMessage::Scanned { path, key, bpm } => {
+                    for file in self.files.iter_mut() {
+                        if let File::FlacFile(flac) = file {
+                            if flac.path == path {
+                                flac.key = flac.key.or(key);
+                                flac.bpm = flac.bpm.or(bpm);
+                            }
+                        }
+                    }
+                }
+                UiMessage::Next { player, after } => {
+                    // The next track in the listing, the listing wraps around
+                    let flacs: Vec<&FlacFile> = self
+                        .files
+                        .iter()
+                        .filter_map(|file| match file {
+                            File::FlacFile(flac) => Some(flac),
+                            File::Dir(_) => None,
+                        })
+                        .collect();
+                    let next = flacs
+                        .iter()
+                        .position(|flac| flac.path == after)
+                        .map(|i| flacs[(i + 1) % flacs.len()]);
+                    match next {
+                        Some(flac) => {
+                            let file_path = flac.path.to_string_lossy().to_string();
+                            if let Err(err) = self.select_sender.send(SelectMessage {
+                                player,
+                                file_path,
+                                next: true,
+                            }) {
+                                eprintln!("Failed to send selection: {}", err);
+                            }
+                        }
+                        None => {
+                            eprintln!("{} is not in this folder, no next track", after.display())
+                        }
+                    }
+                }
                 UiMessage::Select(player) => {
                     if let Some(selected_file) = self.files.get(self.selected_index) {
                         match selected_file {
                             File::FlacFile(flac) => {
                                 let file_path = flac.path.to_string_lossy().to_string();
-                                if let Err(err) =
-                                    self.select_sender.send(SelectMessage { player, file_path })
-                                {
+                                if let Err(err) = self.select_sender.send(SelectMessage {
+                                    player,
+                                    file_path,
+                                    next: false,
+                                }) {
                                     eprintln!("Failed to send selection: {}", err);
                                 }
                             }
@@ -223,6 +446,7 @@ impl eframe::App for FileSelectorApp {
                 }
             }
         }
+        self.save_session();
         ctx.request_repaint_after(Duration::from_millis(12));
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -263,6 +487,21 @@ impl eframe::App for FileSelectorApp {
                                 .clone()
                                 .unwrap_or_else(|| "Unknown Artist".to_string())
                         );
+                        let compatible = flac.key.is_some_and(|key| {
+                            self.deck_keys.values().any(|deck| deck.is_compatible(&key))
+                        });
+                        let key_label = flac
+                            .key
+                            .map(|key| key.camelot())
+                            .unwrap_or_else(|| "--".to_string());
+                        if compatible {
+                            ui.colored_label(egui::Color32::GREEN, key_label);
+                        } else {
+                            ui.label(key_label);
+                        }
+                        if let Some(bpm) = flac.bpm {
+                            ui.label(format!("{:.1}", bpm));
+                        }
                         if i == self.selected_index {
                             ui.colored_label(egui::Color32::YELLOW, label);
                         } else {
@@ -290,52 +529,64 @@ impl eframe::App for FileSelectorApp {
 
 impl FlacFile {
     fn from_path(
-        path: &PathBuf,
+        path: &Path,
         cc: &egui::Context,
         cache: &mut HashMap<u64, (TextureHandle, TextureHandle)>,
+        analysis_cache: Option<&AnalysisCache>,
     ) -> Self {
-        let tag = Tag::read_from_path(path).ok();
-        let (title, artist, album) = if let Some(tag) = &tag {
-            (
-                tag.get_vorbis("TITLE")
-                    .and_then(|mut iter| iter.next().map(|s| s.to_string())),
-                tag.get_vorbis("ARTIST")
-                    .and_then(|mut iter| iter.next().map(|s| s.to_string())),
-                tag.get_vorbis("ALBUM")
-                    .and_then(|mut iter| iter.next().map(|s| s.to_string())),
-            )
-        } else {
-            (None, None, None)
-        };
-        let (inline_album_art, large_album_art) = if let Some(tag) = tag {
-            tag.blocks()
-                .filter_map(|block| match block {
-                    Block::Picture(pic) => {
-                        if pic.picture_type == metaflac::block::PictureType::CoverFront {
-                            Some(load_album_art(&pic.data, cc, cache))
-                        } else {
-                            None
-                        }
-                    }
-                    _ => None,
-                })
-                .next()
-                .unwrap_or((None, None))
-        } else {
-            (None, None)
-        };
+        let metadata = TrackMetadata::read(path).unwrap_or_default();
+        let analysis = analysis_cache.and_then(|cache| cache.lookup(path));
+        let (inline_album_art, large_album_art) = metadata
+            .cover
+            .as_deref()
+            .map_or((None, None), |data| load_album_art(data, cc, cache));
 
         FlacFile {
-            path: path.clone(),
-            title,
-            artist,
-            album,
+            path: path.to_path_buf(),
+            title: metadata.title,
+            artist: metadata.artist,
+            album: metadata.album,
+            key: metadata
+                .key
+                .or_else(|| analysis.as_ref().and_then(|a| a.key)),
+            bpm: analysis.and_then(|a| a.bpm).or(metadata.bpm),
             inline_album_art,
             large_album_art,
         }
     }
 }
 
+// One track at a time, decoding and analysing every track at once would
+// take the cores away from the decks. Results land in the analysis cache
+// too, so decks loading these tracks later skip the analysis.
+fn scan_keys(paths: Vec<PathBuf>, cancel: Arc<AtomicBool>, sender: Sender<UiMessage>) {
+    let cache = match AnalysisCache::open_default() {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Not scanning keys, analysis cache unavailable: {}", e);
+            return;
+        }
+    };
+    for path in paths {
+        if cancel.load(Ordering::Relaxed) {
+            return;
+        }
+        match track::analyse_file(&path, &cache) {
+            Ok(analysis) => {
+                let scanned = UiMessage::Scanned {
+                    path,
+                    key: analysis.key,
+                    bpm: analysis.bpm,
+                };
+                if sender.send(scanned).is_err() {
+                    return;
+                }
+            }
+            Err(e) => eprintln!("Failed to scan {}: {}", path.display(), e),
+        }
+    }
+}
+
 fn load_album_art(
     data: &[u8],
     cc: &egui::Context,
@@ -347,18 +598,10 @@ fn load_album_art(
         return (Some(inline_texture.clone()), Some(large_texture.clone()));
     }
     // Cache miss
-    let cursor = Cursor::new(data);
-    let reader = ImageReader::new(cursor).with_guessed_format();
-    let image = match reader {
-        Ok(r) => match r.decode() {
-            Ok(img) => img.into_rgba8(),
-            Err(e) => {
-                println!("Failed to decode image: {:?}", e);
-                return (None, None);
-            }
-        },
+    let image = match metadata::decode_cover(data) {
+        Ok(image) => image,
         Err(e) => {
-            println!("Failed to guess image format: {:?}", e);
+            println!("Failed to decode image: {:?}", e);
             return (None, None);
         }
     };
@@ -1,7 +1,41 @@
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicU8, Ordering};
 
 pub static ANAHATA_NO: AtomicU32 = AtomicU32::new(0);
 pub static IS_PLAYING: AtomicBool = AtomicBool::new(false);
 pub static CURRENT_POSITION: AtomicU64 = AtomicU64::new(0);
 pub static DURATION: AtomicU64 = AtomicU64::new(0);
 pub static PLAYHEAD: AtomicU64 = AtomicU64::new(0);
+// Zoom steps requested over NATS, consumed by the GUI
+pub static PENDING_ZOOM: AtomicI32 = AtomicI32::new(0);
+// A track is being decoded in the background
+pub static IS_LOADING: AtomicBool = AtomicBool::new(false);
+// Refuse to load over a playing track unless the load is forced
+pub static LOAD_LOCK: AtomicBool = AtomicBool::new(false);
+// Play starts and skips snap to the beatgrid
+pub static QUANTIZE: AtomicBool = AtomicBool::new(false);
+// A quantized start waiting in the process callback for the master's next
+// beat, after dropping the frames rendered from before the snap
+pub static START_ON_BEAT: AtomicBool = AtomicBool::new(false);
+pub static STALE_FRAMES: AtomicU64 = AtomicU64::new(0);
+// The engaged loop in frames, kept by the playback thread, 0 and 0 for none
+pub static LOOP_START: AtomicU64 = AtomicU64::new(0);
+pub static LOOP_END: AtomicU64 = AtomicU64::new(0);
+// my_common::deck::EndOfTrack as u8, stop by default
+pub static END_OF_TRACK: AtomicU8 = AtomicU8::new(0);
+// Process cycles that found the ring buffer empty while playing
+pub static UNDERRUNS: AtomicU64 = AtomicU64::new(0);
+// Reported by JACK through the notification handler
+pub static XRUNS: AtomicU64 = AtomicU64::new(0);
+// JACK DSP load as f32 bits, updated every process cycle
+pub static DSP_LOAD: AtomicU32 = AtomicU32::new(0);
+// Beatgrid tempo and fractional beat number at the next frame the callback
+// plays, as f64 bits, NaN without a beatgrid. Written by the process
+// callback for the timebase callback, see transport::BeatClock.
+pub static TEMPO_BPM: AtomicU64 = AtomicU64::new(f64::NAN.to_bits());
+pub static BEAT_POSITION: AtomicU64 = AtomicU64::new(f64::NAN.to_bits());
+// The deck follows a timecode record, see follow_timecode
+pub static TIMECODE: AtomicBool = AtomicBool::new(false);
+// Decoded in the process callback as f64 bits, the position NaN until the
+// decoder has a lock
+pub static TIMECODE_SPEED: AtomicU64 = AtomicU64::new(0);
+pub static TIMECODE_POSITION: AtomicU64 = AtomicU64::new(f64::NAN.to_bits());
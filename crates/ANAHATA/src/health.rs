@@ -0,0 +1,117 @@
+use crate::globals::*;
+use my_common::deck::DeckHealth;
+use procfs::process::Process;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+/// Reads the glitch counters and this process's CPU and memory use.
+pub struct HealthSampler {
+    // CPU ticks used so far and when they were read
+    last: Option<(u64, Instant)>,
+}
+
+impl HealthSampler {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// CPU use is averaged since the previous call, the first one reads 0.
+    pub fn sample(&mut self) -> DeckHealth {
+        let mut health = DeckHealth {
+            underruns: UNDERRUNS.load(Ordering::Relaxed),
+            xruns: XRUNS.load(Ordering::Relaxed),
+            dsp_load: f32::from_bits(DSP_LOAD.load(Ordering::Relaxed)),
+            ..Default::default()
+        };
+        // Without /proc the counters are still worth having
+        if let Ok(stat) = Process::myself().and_then(|process| process.stat()) {
+            let ticks = stat.utime + stat.stime;
+            let now = Instant::now();
+            if let Some((last_ticks, last_time)) = self.last {
+                let elapsed = now.duration_since(last_time).as_secs_f64();
+                if elapsed > 0.0 {
+                    let seconds =
+                        ticks.saturating_sub(last_ticks) as f64 / procfs::ticks_per_second() as f64;
+                    health.cpu_percent = (seconds / elapsed * 100.0) as f32;
+                }
+            }
+            self.last = Some((ticks, now));
+            health.rss_bytes = stat.rss * procfs::page_size();
+            health.threads = stat.num_threads;
+        }
+        health
+    }
+}
+
+/// Tells which process cycles count as underruns, ones that found the ring
+/// buffer short while playing. The first cycle after pressing play can
+/// beat the playback thread and doesn't count.
+#[derive(Debug, Default)]
+pub struct UnderrunCounter {
+    was_playing: bool,
+}
+
+impl UnderrunCounter {
+    pub fn stopped(&mut self) {
+        self.was_playing = false;
+    }
+
+    /// A cycle that played, `underran` if it ran out of frames.
+    pub fn played(&mut self, underran: bool) {
+        if underran && self.was_playing {
+            UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+        self.was_playing = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The only test touching the counters, tests run in parallel
+    #[test]
+    fn counts_underruns_once_playing() {
+        let mut counter = UnderrunCounter::default();
+        let before = HealthSampler::new().sample().underruns;
+
+        counter.played(true);
+        assert_eq!(before, UNDERRUNS.load(Ordering::Relaxed), "first cycle");
+        counter.played(false);
+        counter.played(true);
+        counter.played(true);
+        assert_eq!(before + 2, UNDERRUNS.load(Ordering::Relaxed));
+
+        counter.stopped();
+        counter.played(true);
+        assert_eq!(
+            before + 2,
+            UNDERRUNS.load(Ordering::Relaxed),
+            "after a stop"
+        );
+
+        XRUNS.fetch_add(3, Ordering::Relaxed);
+        let health = HealthSampler::new().sample();
+        assert_eq!(before + 2, health.underruns);
+        assert_eq!(3, health.xruns);
+    }
+
+    #[test]
+    fn samples_this_process() {
+        let mut sampler = HealthSampler::new();
+        let first = sampler.sample();
+        assert_eq!(0.0, first.cpu_percent, "nothing to average over yet");
+        assert!(first.rss_bytes > 0);
+        assert!(first.threads >= 1);
+
+        // Burn some CPU so the second sample has something to show
+        let start = Instant::now();
+        let mut x = 0u64;
+        while start.elapsed().as_millis() < 50 {
+            x = std::hint::black_box(x.wrapping_add(1));
+        }
+        let second = sampler.sample();
+        assert!(second.cpu_percent >= 0.0);
+        assert!(second.cpu_percent <= 100.0 * second.threads as f32);
+    }
+}
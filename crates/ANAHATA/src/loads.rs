@@ -0,0 +1,142 @@
+//! Which background load a deck is waiting for. Every load gets a
+//! generation and only the latest one goes on the deck. The load lock is
+//! checked when a load is asked for and again when it's ready, the deck may
+//! have started playing while it decoded.
+//!
+//! At the end of a track in `next` mode the deck asks AKASHA for the one
+//! after it. Only that load's answer plays once it's in, and only if nothing
+//! else was loaded in the meantime.
+
+/// What to do with a load that has finished decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finished {
+    /// Another load was asked for since, drop it without a word
+    Stale,
+    /// The deck is playing with the load lock on, report it
+    Locked,
+    /// Put it on the deck, and start playing if `play`
+    Ready { play: bool },
+}
+
+#[derive(Debug, Default)]
+pub struct Loads {
+    generation: u64,
+    // Asked AKASHA for the next track and haven't had an answer
+    awaiting_next: bool,
+    // The load answering it, played when it's ready
+    play_when_ready: Option<u64>,
+}
+
+impl Loads {
+    /// The generation for a new load, replacing any still decoding. `None`
+    /// if `locked`, the deck playing with the load lock on, refuses it. A
+    /// load the DJ asked for cancels waiting on AKASHA for the next track.
+    pub fn start(&mut self, force: bool, locked: bool) -> Option<u64> {
+        self.awaiting_next = false;
+        self.play_when_ready = None;
+        if locked && !force {
+            return None;
+        }
+        self.generation += 1;
+        Some(self.generation)
+    }
+
+    /// The track ended and AKASHA was asked for the one after it.
+    pub fn ask_next(&mut self) {
+        self.awaiting_next = true;
+    }
+
+    /// AKASHA's answer, loaded to play once it's in. `None` if the deck
+    /// didn't ask or something else was loaded since.
+    pub fn start_next(&mut self, locked: bool) -> Option<u64> {
+        if !self.awaiting_next {
+            return None;
+        }
+        let generation = self.start(false, locked)?;
+        self.play_when_ready = Some(generation);
+        Some(generation)
+    }
+
+    pub fn finish(&mut self, generation: u64, force: bool, locked: bool) -> Finished {
+        if generation != self.generation {
+            return Finished::Stale;
+        }
+        // Whatever happens to this load, the next one doesn't play by itself
+        let play = self.play_when_ready.take() == Some(generation);
+        if locked && !force {
+            Finished::Locked
+        } else {
+            Finished::Ready { play }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_the_latest_load_counts() {
+        let mut loads = Loads::default();
+        let first = loads.start(false, false).unwrap();
+        let second = loads.start(false, false).unwrap();
+        assert_eq!(Finished::Stale, loads.finish(first, false, false));
+        assert_eq!(
+            Finished::Ready { play: false },
+            loads.finish(second, false, false)
+        );
+    }
+
+    #[test]
+    fn the_lock_holds_until_the_load_is_in() {
+        let mut loads = Loads::default();
+        assert_eq!(None, loads.start(false, true));
+        let forced = loads.start(true, true).unwrap();
+        assert_eq!(
+            Finished::Ready { play: false },
+            loads.finish(forced, true, true)
+        );
+
+        // Started while stopped, the deck was resumed before it decoded
+        let generation = loads.start(false, false).unwrap();
+        assert_eq!(Finished::Locked, loads.finish(generation, false, true));
+        assert_eq!(
+            Finished::Stale,
+            loads.finish(forced, true, true),
+            "a refused load still replaced the one before"
+        );
+    }
+
+    #[test]
+    fn only_the_asked_for_next_track_plays() {
+        let mut loads = Loads::default();
+        assert_eq!(None, loads.start_next(false), "never asked");
+
+        loads.ask_next();
+        let next = loads.start_next(false).unwrap();
+        assert_eq!(None, loads.start_next(false), "one answer per question");
+        assert_eq!(
+            Finished::Ready { play: true },
+            loads.finish(next, false, false)
+        );
+
+        // The answer failed to load, the DJ's next load must not start by itself
+        loads.ask_next();
+        let failed = loads.start_next(false).unwrap();
+        loads.finish(failed, false, false);
+        let manual = loads.start(false, false).unwrap();
+        assert_eq!(
+            Finished::Ready { play: false },
+            loads.finish(manual, false, false)
+        );
+
+        // AKASHA had no answer, or answered after the DJ loaded something
+        loads.ask_next();
+        let manual = loads.start(false, false).unwrap();
+        assert_eq!(None, loads.start_next(false));
+        assert_eq!(
+            Finished::Ready { play: false },
+            loads.finish(manual, false, false)
+        );
+    }
+}
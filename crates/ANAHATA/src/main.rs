@@ -1,57 +1,248 @@
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender, TryRecvError, TrySendError};
 use eframe::egui;
-use jack::{AudioOut, Client, ClientOptions, Control, ProcessScope};
-use memmap2::Mmap;
+use jack::{AudioIn, AudioOut, Client, ClientOptions, Control, ProcessScope};
+use my_common::analysis::{Analysis, AnalysisCache, WaveformBin};
+use my_common::connections::{self, Rules};
+use my_common::deck::{
+    self, DeckHealth, DeckState, DeckWaveform, EndOfTrack, Jump, END_WARNING_SECONDS,
+};
+use my_common::key::Key;
+use my_common::metadata::{self, TrackMetadata};
+use my_common::session::{DeckSession, SessionStore};
+use my_common::waveform::{downsample, DETAIL_SAMPLES_PER_BIN};
 use nats;
-use rayon::prelude::*;
 use rtrb::RingBuffer;
-use std::fs::File;
-use std::path::PathBuf;
-use std::sync::atomic::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
-use symphonia::core::audio::{AudioBufferRef, Signal};
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
 
 mod globals;
+mod health;
+mod loads;
+mod rt;
+mod transport;
 use crate::globals::*;
+use crate::health::{HealthSampler, UnderrunCounter};
+use crate::loads::{Finished, Loads};
+use crate::rt::{CallbackGuard, MemoryLock};
+use crate::transport::{BeatAnchors, TransportMode, TransportSync};
+use anahata_engine::backend::{AudioBackend, RingBackend};
+use anahata_engine::deck::{Deck, Frame};
+use anahata_engine::error::LoadError;
+use anahata_engine::render::{self, RenderOptions};
+use anahata_engine::timecode::{self, Decoder, TimecodeDef};
+use anahata_engine::track::{analyse, load_track, LoadedTrack};
 
 #[derive(Debug)]
 enum PlayerCommand {
-    ChangeSong(PathBuf),
-    SkipForward,
-    SkipBackward,
+    ChangeSong {
+        path: PathBuf,
+        /// Load even if the deck is playing with the load lock on
+        force: bool,
+        /// AKASHA's answer to `akasha.N.next`, played once it's in
+        next: bool,
+    },
+    Loaded {
+        generation: u64,
+        path: PathBuf,
+        /// As asked for, the load lock is checked again now
+        force: bool,
+        result: Result<Box<LoadedTrack>, LoadError>,
+    },
+    /// Load what was on the deck before a restart and carry on from there
+    Restore {
+        path: PathBuf,
+        playhead: u64,
+        play: bool,
+    },
+    /// Skips snap to the beatgrid when quantizing, nudges never do
+    Jump {
+        jump: Jump,
+        snap: bool,
+    },
+    /// Start playing on a beat, sent instead of setting `IS_PLAYING` when
+    /// quantizing
+    Play,
+    /// Seek to a frame, on the beat like a skip when quantizing
+    Seek(u64),
+    /// Seek to a hot cue, counting from 0
+    Cue(usize),
+    /// Add a hot cue at the playhead, on the nearest beat when quantizing
+    SetCue,
+    LoopIn,
+    LoopOut,
+    LoopExit,
+    BeatGrid {
+        path: Option<PathBuf>,
+        bpm: f64,
+        beat_times: Vec<f64>,
+    },
+    /// The beatgrid and hot cues the worker holds for `path`, after an
+    /// analysis, a beatgrid from DRISHTI or a new cue
+    Beats {
+        path: PathBuf,
+        bpm: Option<f64>,
+        beat_times: Vec<f64>,
+        cues: Vec<u64>,
+    },
 }
+/// Work the playback thread hands off. It runs at real-time priority and
+/// threads inherit that, so decoding, analysis and anything touching the
+/// disk or serialising happen on the worker thread instead. It is also the
+/// only thread the feeder sends to, see `hand_off`.
+enum Job {
+    Load {
+        path: PathBuf,
+        generation: u64,
+        force: bool,
+    },
+    /// Keep the loaded track resident, unlocking the one before
+    Lock(Arc<Vec<Frame>>),
+    /// A track went on the deck. The worker holds its analysis from here
+    /// on, shows and publishes it, and works it out again if it's stale.
+    Loaded {
+        path: PathBuf,
+        hash: Option<String>,
+        cached: Option<Analysis>,
+        song: Arc<Vec<Frame>>,
+        tagged_key: Option<Key>,
+        sample_rate: f32,
+    },
+    /// Our own analysis of `path` is done
+    Analysed {
+        path: PathBuf,
+        analysis: Analysis,
+    },
+    /// A beatgrid from DRISHTI for the loaded track
+    BeatGrid {
+        path: PathBuf,
+        bpm: f64,
+        beat_times: Vec<f64>,
+    },
+    /// A hot cue set on `path`, kept with its analysis
+    Cue {
+        path: PathBuf,
+        at: u64,
+    },
+    Metadata(Box<TrackMetadata>),
+    Track(TrackState),
+    LoadError {
+        path: PathBuf,
+        error: LoadError,
+    },
+    Publish(Outgoing),
+}
+
 #[derive(Debug)]
 enum MetaCommand {
-    Metadata(String, String),
+    Metadata(Box<TrackMetadata>),
     Waveform(Vec<WaveformBin>),
+    Key(Option<Key>),
+    Detail(Vec<WaveformBin>),
+    BeatGrid(Vec<f64>),
+    Error(String),
+    Health(DeckHealth),
 }
 
+// Tracks are played as is, JACK is expected to run at this rate
+const PLAYBACK_SAMPLE_RATE: u32 = 48000;
+
+const ZOOM_FACTOR: f32 = 1.25;
+const COVER_SIZE: u32 = 96;
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+const GLITCH_HIGHLIGHT: Duration = Duration::from_secs(10);
+// Room for bursts of jobs from the feeder, which drops what doesn't fit
+const JOB_QUEUE: usize = 256;
+const MIN_PIXELS_PER_SECOND: f32 = 2.0;
+const MAX_PIXELS_PER_SECOND: f32 = 2000.0;
+const RULER_STEPS: [f64; 12] = [
+    0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0,
+];
+
 struct PlayerApp {
-    current_title: String,
-    current_artist: String,
+    metadata: TrackMetadata,
+    cover: Option<egui::TextureHandle>,
+    current_key: Option<Key>,
+    // Last failed load, cleared when a track loads
+    load_error: Option<String>,
     waveform: Vec<WaveformBin>,
+    // Level 0 is the finest, every level after it halves the resolution
+    detail_levels: Vec<Vec<WaveformBin>>,
+    beat_times: Vec<f64>,
+    pixels_per_second: f32,
+    // Where a drag on the detailed waveform has got to, the seeks it sends
+    // take a round trip through NATS to move the playhead
+    scrub: Option<f64>,
+    health: DeckHealth,
+    // When the underrun or xrun count last went up
+    last_glitch: Option<Instant>,
     meta_rx: Receiver<MetaCommand>,
-    smooth_offset: f32,
-    last_update: std::time::Instant,
+    // Seeks go out as `anahata.N.seek` so they land in the event log
+    publish_tx: Sender<Outgoing>,
 }
 
 impl PlayerApp {
-    fn new(cc: &eframe::CreationContext, meta_rx: Receiver<MetaCommand>) -> Self {
+    fn new(
+        cc: &eframe::CreationContext,
+        meta_rx: Receiver<MetaCommand>,
+        publish_tx: Sender<Outgoing>,
+    ) -> Self {
         Self {
-            current_title: String::from("Unknown"),
-            current_artist: String::from("Unknown"),
+            metadata: TrackMetadata::default(),
+            cover: None,
+            current_key: None,
+            load_error: None,
             waveform: Vec::new(),
+            detail_levels: Vec::new(),
+            beat_times: Vec::new(),
+            pixels_per_second: 100.0,
+            scrub: None,
+            health: DeckHealth::default(),
+            last_glitch: None,
             meta_rx,
-            smooth_offset: 0.0,
-            last_update: std::time::Instant::now(),
+            publish_tx,
         }
     }
+    fn draw_diagnostics(&self, ui: &mut egui::Ui) {
+        // Red for a while after a glitch so it is noticed with the panel shut
+        let recent = self
+            .last_glitch
+            .is_some_and(|at| at.elapsed() < GLITCH_HIGHLIGHT);
+        let title = egui::RichText::new("Diagnostics").color(if recent {
+            egui::Color32::RED
+        } else {
+            egui::Color32::GRAY
+        });
+        egui::CollapsingHeader::new(title)
+            .id_salt("diagnostics")
+            .show(ui, |ui| {
+                let health = &self.health;
+                egui::Grid::new("health").num_columns(2).show(ui, |ui| {
+                    ui.label("Deck underruns");
+                    ui.label(health.underruns.to_string());
+                    ui.end_row();
+                    ui.label("JACK xruns");
+                    ui.label(health.xruns.to_string());
+                    ui.end_row();
+                    ui.label("JACK DSP load");
+                    ui.label(format!("{:.1}%", health.dsp_load));
+                    ui.end_row();
+                    ui.label("CPU");
+                    ui.label(format!("{:.1}%", health.cpu_percent));
+                    ui.end_row();
+                    ui.label("Memory");
+                    ui.label(format!("{:.0} MB", health.rss_bytes as f64 / 1e6));
+                    ui.end_row();
+                    ui.label("Threads");
+                    ui.label(health.threads.to_string());
+                    ui.end_row();
+                });
+            });
+    }
+
     fn viridis_color(amplitude: f32) -> egui::Color32 {
         let amp = amplitude.clamp(0.0, 1.0);
 
@@ -86,146 +277,171 @@ impl PlayerApp {
             egui::Color32::from_rgb((255.0) as u8, (255.0) as u8, (50.0 - t * 50.0) as u8)
         }
     }
+    fn seek(&self, position_ms: u64) {
+        let subject = format!("anahata.{}.seek", ANAHATA_NO.load(Ordering::Relaxed));
+        // A full queue drops a step of the drag rather than stall the UI
+        let _ = self
+            .publish_tx
+            .try_send(Outgoing::event(subject, position_ms.to_string()));
+    }
+
     fn draw_detailed_waveform(&mut self, ui: &mut egui::Ui) {
         let waveform_height = 400.0;
-        let pixels_per_second = 10.0;
-        let duration_secs = DURATION.load(Ordering::Relaxed) as f64 / 1000.0;
-        let total_width = (duration_secs * pixels_per_second as f64) as f32;
+        let ruler_height = 16.0;
+        let sample_rate = 48000.0;
 
-        egui::ScrollArea::horizontal()
-            .auto_shrink([false, false])
-            .show(ui, |ui| {
-                let detailed_response = ui.allocate_response(
-                    egui::vec2(total_width, waveform_height),
-                    egui::Sense::click_and_drag(),
-                );
+        let response = ui.allocate_response(
+            egui::vec2(ui.available_width(), ruler_height + waveform_height),
+            egui::Sense::click_and_drag(),
+        );
 
-                if !self.waveform.is_empty() {
-                    let rect = detailed_response.rect;
-                    let painter = ui.painter();
-                    let width_per_bin = total_width / self.waveform.len() as f32;
-                    let playhead_x = ui.clip_rect().center().x;
-
-                    // Calculate the offset needed to align the current position with the playhead
-                    let current_position = CURRENT_POSITION.load(Ordering::Relaxed) as f64;
-                    let total_duration = DURATION.load(Ordering::Relaxed) as f64;
-
-                    // For the initial state (position = 0), align the left edge exactly with the playhead
-                    let view_offset = if current_position == 0.0 {
-                        playhead_x - rect.left()
-                    } else {
-                        let progress = current_position / total_duration;
-                        let target_x = rect.left() + (total_width * progress as f32);
-                        playhead_x - target_x
-                    };
-
-                    let now = std::time::Instant::now();
-                    if now.duration_since(self.last_update).as_millis() >= 16 {
-                        self.smooth_offset = view_offset;
-                        self.last_update = now;
-                    }
+        // Zoom steps come from NATS (controller) and the mouse wheel
+        let mut zoom_steps = PENDING_ZOOM.swap(0, Ordering::Relaxed) as f32;
+        if response.hovered() {
+            zoom_steps += ui.input(|i| i.raw_scroll_delta.y) / 50.0;
+        }
+        if zoom_steps != 0.0 {
+            self.pixels_per_second = (self.pixels_per_second * ZOOM_FACTOR.powf(zoom_steps))
+                .clamp(MIN_PIXELS_PER_SECOND, MAX_PIXELS_PER_SECOND);
+        }
 
-                    // Draw waveform bins
-                    for (i, bin) in self.waveform.iter().enumerate() {
-                        let bin_x = rect.left() + (i as f32 * width_per_bin) + self.smooth_offset;
-
-                        if bin_x >= ui.clip_rect().left() - width_per_bin
-                            && bin_x <= ui.clip_rect().right() + width_per_bin
-                        {
-                            let center_y = rect.center().y;
-
-                            // Scale RMS values
-                            let low_rms = (bin.low.rms_left + bin.low.rms_right) * 0.5;
-                            let mid_rms = 0.4 + (bin.mid.rms_left + bin.mid.rms_right) * 0.3;
-                            let high_rms = 0.7 + (bin.high.rms_left + bin.high.rms_right) * 0.3;
-
-                            let low_color = Self::viridis_color(low_rms);
-                            let mid_color = Self::viridis_color(mid_rms);
-                            let high_color = Self::viridis_color(high_rms);
-
-                            // Draw frequency bands
-                            painter.line_segment(
-                                [
-                                    egui::pos2(
-                                        bin_x,
-                                        center_y - bin.low.rms_left * waveform_height * 0.6,
-                                    ),
-                                    egui::pos2(
-                                        bin_x,
-                                        center_y + bin.low.rms_right * waveform_height * 0.6,
-                                    ),
-                                ],
-                                egui::Stroke::new(3.0, low_color),
-                            );
-
-                            painter.line_segment(
-                                [
-                                    egui::pos2(
-                                        bin_x,
-                                        center_y - bin.mid.rms_left * waveform_height * 0.7,
-                                    ),
-                                    egui::pos2(
-                                        bin_x,
-                                        center_y + bin.mid.rms_right * waveform_height * 0.7,
-                                    ),
-                                ],
-                                egui::Stroke::new(2.0, mid_color),
-                            );
-
-                            painter.line_segment(
-                                [
-                                    egui::pos2(
-                                        bin_x,
-                                        center_y - bin.high.rms_left * waveform_height * 0.30,
-                                    ),
-                                    egui::pos2(
-                                        bin_x,
-                                        center_y + bin.high.rms_right * waveform_height * 0.30,
-                                    ),
-                                ],
-                                egui::Stroke::new(1.0, high_color),
-                            );
-                        }
-                    }
+        if self.detail_levels.is_empty() {
+            return;
+        }
+
+        let rect = response.rect;
+        let ruler = egui::Rect::from_min_size(rect.min, egui::vec2(rect.width(), ruler_height));
+        let wave_rect = egui::Rect::from_min_max(egui::pos2(rect.left(), ruler.bottom()), rect.max);
+        let painter = ui.painter_at(rect);
+        let playhead_x = rect.center().x;
 
-                    // Draw playhead
+        if !response.dragged() {
+            self.scrub = None;
+        }
+        let position = self
+            .scrub
+            .unwrap_or(PLAYHEAD.load(Ordering::Relaxed) as f64 / sample_rate);
+        let pixels_per_second = self.pixels_per_second as f64;
+        let time_at = |x: f32| position + (x - playhead_x) as f64 / pixels_per_second;
+        let x_at = |t: f64| playhead_x + ((t - position) * pixels_per_second) as f32;
+        let (start, end) = (time_at(rect.left()), time_at(rect.right()));
+
+        // Coarsest level that still has at least one bin per pixel
+        let bin_seconds = DETAIL_SAMPLES_PER_BIN as f64 / sample_rate;
+        let level = (0..self.detail_levels.len())
+            .rev()
+            .find(|&level| bin_seconds * (1u64 << level) as f64 * pixels_per_second <= 1.0)
+            .unwrap_or(0);
+        let bins = &self.detail_levels[level];
+        let level_seconds = bin_seconds * (1u64 << level) as f64;
+
+        let center_y = wave_rect.center().y;
+        let height = wave_rect.height();
+        let mut x = wave_rect.left().floor();
+        while x < wave_rect.right() {
+            let t = time_at(x);
+            if let Some(bin) = (t >= 0.0)
+                .then(|| bins.get((t / level_seconds) as usize))
+                .flatten()
+            {
+                // Scale RMS values
+                let low_rms = (bin.low.rms_left + bin.low.rms_right) * 0.5;
+                let mid_rms = 0.4 + (bin.mid.rms_left + bin.mid.rms_right) * 0.3;
+                let high_rms = 0.7 + (bin.high.rms_left + bin.high.rms_right) * 0.3;
+
+                for (band, scale, width, rms) in [
+                    (&bin.low, 0.6, 3.0, low_rms),
+                    (&bin.mid, 0.7, 2.0, mid_rms),
+                    (&bin.high, 0.3, 1.0, high_rms),
+                ] {
                     painter.line_segment(
                         [
-                            egui::pos2(playhead_x, rect.top()),
-                            egui::pos2(playhead_x, rect.bottom()),
+                            egui::pos2(x, center_y - band.rms_left * height * scale),
+                            egui::pos2(x, center_y + band.rms_right * height * scale),
                         ],
-                        egui::Stroke::new(2.0, egui::Color32::WHITE),
+                        egui::Stroke::new(width, Self::viridis_color(rms)),
                     );
+                }
+            }
+            x += 1.0;
+        }
 
-                    // Handle drag interactions
-                    if detailed_response.dragged() {
-                        let drag_delta = detailed_response.drag_delta();
-                        let time_per_pixel = duration_secs / total_width as f64;
-                        let time_delta = -drag_delta.x as f64 * time_per_pixel;
+        // Beat and bar ticks, assuming the first beat is a downbeat
+        let first_visible = self.beat_times.partition_point(|&beat| beat < start);
+        for (i, &beat) in self.beat_times.iter().enumerate().skip(first_visible) {
+            if beat > end {
+                break;
+            }
+            let x = x_at(beat);
+            let is_bar = i % 4 == 0;
+            let (width, alpha) = if is_bar { (1.5, 140) } else { (0.5, 60) };
+            painter.line_segment(
+                [
+                    egui::pos2(x, wave_rect.top()),
+                    egui::pos2(x, wave_rect.bottom()),
+                ],
+                egui::Stroke::new(width, egui::Color32::from_white_alpha(alpha)),
+            );
+            if is_bar {
+                painter.text(
+                    egui::pos2(x + 2.0, wave_rect.top()),
+                    egui::Align2::LEFT_TOP,
+                    format!("{}", i / 4 + 1),
+                    egui::FontId::monospace(10.0),
+                    egui::Color32::from_white_alpha(160),
+                );
+            }
+        }
 
-                        let new_pos = (current_position + time_delta * 1000.0) as u64;
-                        let new_pos = new_pos.clamp(0, DURATION.load(Ordering::Relaxed));
+        // Time ruler, at least ~70px between labels
+        let step = RULER_STEPS
+            .iter()
+            .copied()
+            .find(|step| step * pixels_per_second >= 70.0)
+            .unwrap_or(600.0);
+        let mut t = (start.max(0.0) / step).ceil() * step;
+        while t <= end {
+            let x = x_at(t);
+            painter.line_segment(
+                [
+                    egui::pos2(x, ruler.bottom() - 5.0),
+                    egui::pos2(x, ruler.bottom()),
+                ],
+                egui::Stroke::new(1.0, egui::Color32::GRAY),
+            );
+            let label = if step < 1.0 {
+                format!("{}:{:04.1}", (t / 60.0) as u64, t % 60.0)
+            } else {
+                format!("{}:{:02}", (t / 60.0) as u64, (t % 60.0) as u64)
+            };
+            painter.text(
+                egui::pos2(x + 2.0, ruler.top()),
+                egui::Align2::LEFT_TOP,
+                label,
+                egui::FontId::monospace(10.0),
+                egui::Color32::GRAY,
+            );
+            t += step;
+        }
 
-                        let sample_rate = 48000.0;
-                        let new_samples = ((new_pos as f64 / 1000.0) * sample_rate).round() as u64;
+        // Draw playhead
+        painter.line_segment(
+            [
+                egui::pos2(playhead_x, rect.top()),
+                egui::pos2(playhead_x, rect.bottom()),
+            ],
+            egui::Stroke::new(2.0, egui::Color32::WHITE),
+        );
 
-                        PLAYHEAD.store(new_samples, Ordering::Relaxed);
-                        CURRENT_POSITION.store(new_pos, Ordering::Relaxed);
-                    }
+        // Dragging scrubs, at the current zoom level
+        if response.dragged() && response.drag_delta().x != 0.0 {
+            let duration_secs = DURATION.load(Ordering::Relaxed) as f64 / 1000.0;
+            let time_delta = -response.drag_delta().x as f64 / pixels_per_second;
+            let new_time = (position + time_delta).clamp(0.0, duration_secs);
 
-                    // Debug visualization
-                    if current_position == 0.0 {
-                        // Draw start marker
-                        painter.line_segment(
-                            [
-                                egui::pos2(rect.left() + self.smooth_offset, rect.top()),
-                                egui::pos2(rect.left() + self.smooth_offset, rect.bottom()),
-                            ],
-                            egui::Stroke::new(1.0, egui::Color32::RED),
-                        );
-                    }
-                }
-            });
+            self.scrub = Some(new_time);
+            self.seek((new_time * 1000.0).round() as u64);
+        }
     }
 
     fn draw_overview_waveform(&self, ui: &mut egui::Ui) {
@@ -313,38 +529,85 @@ impl PlayerApp {
             if response.clicked() || response.dragged() {
                 if let Some(pos) = response.interact_pointer_pos() {
                     let fraction = (pos.x - rect.left()) / rect.width();
-                    let new_pos = (fraction * duration) as u64;
-                    let new_samples = ((new_pos as f64 / 1000.0) * 48000.0) as u64;
-
-                    PLAYHEAD.store(new_samples, Ordering::Relaxed);
-                    CURRENT_POSITION.store(new_pos, Ordering::Relaxed);
+                    self.seek((fraction * duration) as u64);
                 }
             }
         }
     }
 }
 
+// Same decoding as AKASHA's listing, at the size of the header
+fn cover_texture(ctx: &egui::Context, data: &[u8]) -> Option<egui::TextureHandle> {
+    let image = match metadata::decode_cover(data) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to decode cover art: {}", e);
+            return None;
+        }
+    };
+    let image = image::imageops::resize(
+        &image,
+        COVER_SIZE,
+        COVER_SIZE,
+        image::imageops::FilterType::Lanczos3,
+    );
+    let color_image = egui::ColorImage::from_rgba_unmultiplied(
+        [image.width() as usize, image.height() as usize],
+        &image,
+    );
+    Some(ctx.load_texture("cover", color_image, egui::TextureOptions::default()))
+}
+
 impl eframe::App for PlayerApp {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         // Check for metadata updates
-        if let Ok(cmd) = self.meta_rx.try_recv() {
+        while let Ok(cmd) = self.meta_rx.try_recv() {
             match cmd {
-                MetaCommand::Metadata(title, artist) => {
-                    self.current_title = title;
-                    self.current_artist = artist;
+                MetaCommand::Metadata(metadata) => {
+                    self.cover = metadata
+                        .cover
+                        .as_deref()
+                        .and_then(|data| cover_texture(ctx, data));
+                    self.metadata = *metadata;
+                    self.load_error = None;
                 }
                 MetaCommand::Waveform(wf) => self.waveform = wf,
+                MetaCommand::Key(key) => self.current_key = key,
+                MetaCommand::Detail(detail) => {
+                    let mut levels = vec![detail];
+                    while levels.last().is_some_and(|level| level.len() > 1) {
+                        let coarser = downsample(levels.last().unwrap());
+                        levels.push(coarser);
+                    }
+                    self.detail_levels = levels;
+                }
+                MetaCommand::BeatGrid(beat_times) => self.beat_times = beat_times,
+                MetaCommand::Error(error) => self.load_error = Some(error),
+                MetaCommand::Health(health) => {
+                    if health.underruns > self.health.underruns || health.xruns > self.health.xruns
+                    {
+                        self.last_glitch = Some(Instant::now());
+                    }
+                    self.health = health;
+                }
             }
         }
 
         egui::CentralPanel::default().show(ctx, |ui| {
             let number = ANAHATA_NO.load(Ordering::Relaxed);
+            // Blink twice a second near the end of the track
+            let blink = end_warning() && (ctx.input(|i| i.time) * 2.0).fract() < 0.5;
+            let header_color = if blink {
+                egui::Color32::RED
+            } else {
+                egui::Color32::WHITE
+            };
             ui.horizontal(|ui| {
                 ui.heading(
                     egui::RichText::new(format!("ANAHATA-{}", number))
                         .size(50.0)
                         .strong()
-                        .color(egui::Color32::WHITE),
+                        .color(header_color),
                 );
                 ui.horizontal(|ui| {
                     ui.vertical(|ui| {
@@ -359,24 +622,195 @@ impl eframe::App for PlayerApp {
                             DURATION.load(Ordering::Relaxed) % 60,
                         ));
                     });
+                    if let Some(cover) = &self.cover {
+                        ui.image(cover);
+                    }
+                    ui.vertical(|ui| {
+                        let metadata = &self.metadata;
+                        ui.heading(metadata.title.as_deref().unwrap_or("Unknown"));
+                        ui.heading(metadata.artist.as_deref().unwrap_or("Unknown"));
+                        let release: Vec<&str> = [&metadata.album, &metadata.label, &metadata.year]
+                            .into_iter()
+                            .flatten()
+                            .map(String::as_str)
+                            .collect();
+                        if !release.is_empty() {
+                            ui.label(release.join(" · "));
+                        }
+                        if let Some(comment) = &metadata.comment {
+                            ui.weak(comment);
+                        }
+                    });
+                    ui.vertical(|ui| match self.current_key {
+                        Some(key) => {
+                            ui.heading(key.camelot());
+                            ui.heading(key.open_key());
+                        }
+                        None => {
+                            ui.heading("--");
+                        }
+                    });
                     ui.vertical(|ui| {
-                        ui.heading(&self.current_title);
-                        ui.heading(&self.current_artist);
+                        // The beatgrid's tempo where we are, the tag's until
+                        // there is a grid
+                        let position =
+                            PLAYHEAD.load(Ordering::Relaxed) as f64 / PLAYBACK_SAMPLE_RATE as f64;
+                        match deck::tempo_at(&self.beat_times, position).or(self.metadata.bpm) {
+                            Some(bpm) => ui.heading(format!("{:.1}", bpm)),
+                            None => ui.heading("---"),
+                        };
+                        if let Some(genre) = &self.metadata.genre {
+                            ui.label(genre);
+                        }
+                    });
+                    ui.vertical(|ui| {
+                        if LOAD_LOCK.load(Ordering::Relaxed) {
+                            ui.heading("LOCK");
+                        }
+                        if QUANTIZE.load(Ordering::Relaxed) {
+                            ui.heading("QUANTIZE");
+                        }
+                        if IS_LOADING.load(Ordering::Relaxed) {
+                            ui.heading(egui::RichText::new("LOADING").color(egui::Color32::YELLOW));
+                        }
                     });
                 });
             });
+            if let Some(error) = &self.load_error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
             self.draw_overview_waveform(ui);
             self.draw_detailed_waveform(ui);
+            self.draw_diagnostics(ui);
         });
         ctx.request_repaint_after(Duration::from_millis(12));
     }
 }
 
 fn main() {
-    let _ = run_heartbeat();
-    get_player_number();
+    // Per deck defaults for empty skip and nudge commands, e.g. `--skip 4b`
+    let skip_default = jump_arg("--skip").unwrap_or(Jump::Seconds(5.0));
+    let nudge_default = jump_arg("--nudge").unwrap_or(Jump::Seconds(0.01));
+    // SCHED_FIFO priority for the thread feeding JACK, below the JACK
+    // process thread at the usual -P 70
+    let rt_priority = std::env::args()
+        .skip_while(|arg| arg != "--rt-priority")
+        .nth(1)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(50);
+
+    // `--render set.events.jsonl --out set.wav` plays a SMRITI event log
+    // back offline, no JACK or NATS needed
+    if let Some(log) = std::env::args().skip_while(|arg| arg != "--render").nth(1) {
+        let out = std::env::args()
+            .skip_while(|arg| arg != "--out")
+            .nth(1)
+            .unwrap_or_else(|| String::from("render.wav"));
+        let options = RenderOptions {
+            skip: skip_default,
+            nudge: nudge_default,
+            faders: fader_args(),
+        };
+        if let Err(e) = render::render(Path::new(&log), Path::new(&out), &options) {
+            eprintln!("Render failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `--timecode serato_2a` puts the deck under a timecode record's control
+    let timecode_def = timecode_arg();
+    // `--decode-timecode take.wav` prints what a recording of the record
+    // decodes to, to check a setup without JACK
+    if let Some(take) = std::env::args()
+        .skip_while(|arg| arg != "--decode-timecode")
+        .nth(1)
+    {
+        let def = timecode_def.unwrap_or(&timecode::DEFINITIONS[0]);
+        let readings = match std::fs::File::open(&take) {
+            Ok(file) => timecode::decode_wav(std::io::BufReader::new(file), def, 0.1)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match readings {
+            Ok(readings) => {
+                for (time, reading) in readings {
+                    match reading.position {
+                        Some(position) => println!(
+                            "{:8.2}s  speed {:+.3}  position {:.3}s",
+                            time, reading.speed, position
+                        ),
+                        None => println!("{:8.2}s  speed {:+.3}", time, reading.speed),
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not decode {}: {}", take, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // No window, for boxes without a display. Everything a view needs is on NATS.
+    let headless = std::env::args().any(|arg| arg == "--headless");
+    // `--transport master` drives JACK transport from this deck,
+    // `--transport follow` starts and stops with it
+    let transport_mode = match std::env::args()
+        .skip_while(|arg| arg != "--transport")
+        .nth(1)
+    {
+        Some(value) => TransportMode::parse(&value).unwrap_or_else(|| {
+            eprintln!(
+                "Ignoring --transport {}, expected off, master or follow",
+                value
+            );
+            TransportMode::Off
+        }),
+        None => TransportMode::Off,
+    };
+    run_heartbeat();
+    if let Err(e) = get_player_number() {
+        // Without NATS there is no other deck to clash with
+        eprintln!("Could not negotiate a player number, using 1: {}", e);
+        ANAHATA_NO.store(1, Ordering::Relaxed);
+    }
     println!("GOT");
 
+    // The deck comes back as it was last saved under its number, playing
+    // if it was unless `--restore-paused`. `--no-restore` starts empty.
+    let sessions = SessionStore::open_default()
+        .map_err(|e| eprintln!("Sessions unavailable, nothing is saved or restored: {}", e))
+        .ok();
+    let restored = sessions
+        .as_ref()
+        .filter(|_| !std::env::args().any(|arg| arg == "--no-restore"))
+        .and_then(|sessions| sessions.load::<DeckSession>(&session_name()));
+    if let Some(session) = &restored {
+        END_OF_TRACK.store(session.end_of_track as u8, Ordering::Relaxed);
+        LOAD_LOCK.store(session.load_lock, Ordering::Relaxed);
+        QUANTIZE.store(session.quantize, Ordering::Relaxed);
+    }
+    // Flags win over the restored settings
+    if std::env::args().any(|arg| arg == "--load-lock") {
+        LOAD_LOCK.store(true, Ordering::Relaxed);
+    }
+    if std::env::args().any(|arg| arg == "--quantize") {
+        QUANTIZE.store(true, Ordering::Relaxed);
+    }
+    if let Some(value) = std::env::args()
+        .skip_while(|arg| arg != "--end-of-track")
+        .nth(1)
+    {
+        match EndOfTrack::parse(&value) {
+            Some(mode) => END_OF_TRACK.store(mode as u8, Ordering::Relaxed),
+            None => eprintln!(
+                "Ignoring --end-of-track {}, expected stop, loop or next",
+                value
+            ),
+        }
+    }
+
     let (client, _status) = Client::new("ANAHATA", ClientOptions::NO_START_SERVER)
         .expect("Failed to create JACK client");
     let jack_buffer_size = client.buffer_size();
@@ -386,11 +820,19 @@ fn main() {
         jack_buffer_size, jack_sample_rate
     );
 
+    // Where the frames in the ring buffer are on the beatgrid
+    let (anchors, mut beat_clock) = transport::beat_clock(PLAYBACK_SAMPLE_RATE as f64);
+
     let rtrb_buffer_size = jack_buffer_size * 2;
     let (mut producer, mut consumer) = RingBuffer::<(f32, f32)>::new(rtrb_buffer_size as usize);
 
     let (cmd_tx, cmd_rx) = bounded::<PlayerCommand>(32);
+    // The window stops reading these while it's hidden, so senders try and
+    // drop what doesn't fit rather than wait
     let (meta_tx, meta_rx) = bounded::<MetaCommand>(32);
+    let (publish_tx, publish_rx) = bounded::<Outgoing>(32);
+    let (track_tx, track_rx) = bounded::<TrackState>(32);
+    let (session_track_tx, session_track_rx) = bounded::<TrackState>(32);
 
     let mut out_port_left = client
         .register_port("out_left", AudioOut::default())
@@ -398,527 +840,1368 @@ fn main() {
     let mut out_port_right = client
         .register_port("out_right", AudioOut::default())
         .expect("Failed to create right output port");
+    let mut timecode_input = timecode_def.map(|def| {
+        let left = client
+            .register_port("timecode_left", AudioIn::default())
+            .expect("Failed to create left timecode port");
+        let right = client
+            .register_port("timecode_right", AudioIn::default())
+            .expect("Failed to create right timecode port");
+        println!("Following {} timecode", def.name);
+        TIMECODE.store(true, Ordering::Relaxed);
+        (left, right, Decoder::new(def, jack_sample_rate as f64))
+    });
 
-    let mut song = Vec::new();
+    if let Some(DeckSession {
+        path: Some(path),
+        playhead,
+        playing,
+        ..
+    }) = restored
+    {
+        let play = playing && !std::env::args().any(|arg| arg == "--restore-paused");
+        let _ = cmd_tx.send(PlayerCommand::Restore {
+            path: PathBuf::from(path),
+            playhead,
+            play,
+        });
+    }
+    if let Some(sessions) = sessions {
+        thread::spawn(move || {
+            session_thread(sessions, session_track_rx);
+        });
+    }
 
+    let health_meta_tx = meta_tx.clone();
+    let health_publish_tx = publish_tx.clone();
+    let gui_publish_tx = publish_tx.clone();
     thread::spawn(move || {
-        playback_thread(producer, song, &meta_tx, cmd_rx);
+        health_thread(health_meta_tx, health_publish_tx);
     });
+    // Started from here so it and the threads it starts keep normal
+    // priority, they'd inherit SCHED_FIFO from the feeder
+    let (job_tx, job_rx) = bounded::<Job>(JOB_QUEUE);
+    let worker_job_tx = job_tx.clone();
+    let worker_cmd_tx = cmd_tx.clone();
     thread::spawn(move || {
-        control_thread(cmd_tx);
+        worker_thread(
+            worker_job_tx,
+            job_rx,
+            worker_cmd_tx,
+            meta_tx,
+            publish_tx,
+            [track_tx, session_track_tx],
+        );
+    });
+    let feeder = thread::spawn(move || {
+        match rt::promote_current_thread(rt_priority) {
+            Ok(()) => println!("Feeding JACK at real-time priority {}", rt_priority),
+            Err(e) => eprintln!(
+                "No real-time priority for the playback thread ({}), check rtprio in limits.conf",
+                e
+            ),
+        }
+        playback_thread(
+            RingBackend::new(producer, jack_sample_rate as u32),
+            job_tx,
+            anchors,
+            cmd_rx,
+        );
+    });
+    thread::spawn(move || {
+        publish_thread(publish_rx, track_rx);
+    });
+    thread::spawn(move || {
+        control_thread(cmd_tx, skip_default, nudge_default);
     });
 
-    let process_callback = move |_: &Client, ps: &ProcessScope| -> Control {
+    // Woken every cycle, once there's room in the ring buffer to refill
+    let feeder = feeder.thread().clone();
+    let mut transport_sync = TransportSync::new(&client, transport_mode);
+    let mut underruns = UnderrunCounter::default();
+    let process_callback = move |client: &Client, ps: &ProcessScope| -> Control {
+        let _guard = CallbackGuard::enter();
+        transport_sync.cycle();
+        if let Some((left, right, decoder)) = &mut timecode_input {
+            decoder.process(left.as_slice(ps), right.as_slice(ps));
+            let reading = decoder.reading();
+            let position = reading.position.unwrap_or(f64::NAN);
+            TIMECODE_SPEED.store(reading.speed.to_bits(), Ordering::Relaxed);
+            TIMECODE_POSITION.store(position.to_bits(), Ordering::Relaxed);
+        }
+        DSP_LOAD.store(client.cpu_load().to_bits(), Ordering::Relaxed);
         let out_buffer_left = out_port_left.as_mut_slice(ps);
         let out_buffer_right = out_port_right.as_mut_slice(ps);
 
-        if !IS_PLAYING.load(Ordering::Relaxed) {
+        // Acquire pairs with a quantized start, which sets up START_ON_BEAT
+        // before it starts playing
+        if !IS_PLAYING.load(Ordering::Acquire) {
+            underruns.stopped();
             // If not playing, output silence. WE ARE ALWAYS PLAYING, SOMETIMES VERY SOFTLY
             for (left, right) in out_buffer_left.iter_mut().zip(out_buffer_right.iter_mut()) {
                 *left = 0.0;
                 *right = 0.0;
             }
+            beat_clock.advance(0);
+            feeder.unpark();
+            return Control::Continue;
+        }
+
+        // A quantized start drops what was rendered before the playhead
+        // snapped, then holds off until the master's next beat
+        let mut start = 0;
+        let mut popped = 0;
+        if START_ON_BEAT.load(Ordering::Relaxed) {
+            for _ in 0..STALE_FRAMES.swap(0, Ordering::Relaxed) {
+                popped += consumer.pop().is_ok() as usize;
+            }
+            start = transport_sync
+                .frames_to_master_beat()
+                .unwrap_or(0)
+                .min(out_buffer_left.len());
+            if start < out_buffer_left.len() {
+                START_ON_BEAT.store(false, Ordering::Relaxed);
+            }
+        }
+        out_buffer_left[..start].fill(0.0);
+        out_buffer_right[..start].fill(0.0);
+        if start == out_buffer_left.len() {
+            beat_clock.advance(popped);
+            feeder.unpark();
             return Control::Continue;
         }
 
-        for (left, right) in out_buffer_left.iter_mut().zip(out_buffer_right.iter_mut()) {
+        let mut underran = false;
+        for (left, right) in out_buffer_left[start..]
+            .iter_mut()
+            .zip(out_buffer_right[start..].iter_mut())
+        {
             if let Ok((l, r)) = consumer.pop() {
                 *left = l;
                 *right = r;
+                popped += 1;
             } else {
                 *left = 0.0;
                 *right = 0.0;
+                underran = true;
             }
         }
+        underruns.played(underran);
+        beat_clock.advance(popped);
+        feeder.unpark();
         Control::Continue
     };
 
     let active_client = client
-        .activate_async((), jack::ClosureProcessHandler::new(process_callback))
+        .activate_async(
+            XrunCounter,
+            jack::contrib::ClosureProcessHandler::new(process_callback),
+        )
         .expect("Failed to activate client");
+    if transport_mode == TransportMode::Master {
+        match transport::become_timebase_master(active_client.as_client()) {
+            Ok(()) => println!("JACK timebase master"),
+            Err(e) => eprintln!("Not taking over JACK timebase: {}", e),
+        }
+    }
+    let owner = active_client.as_client().name().to_string();
+    if let Err(e) = connections::spawn(owner, Rules::load_default()) {
+        eprintln!("Not connecting any ports: {}", e);
+    }
 
-    let native_options = eframe::NativeOptions::default();
-    eframe::run_native(
-        "ANAHATA",
-        native_options,
-        Box::new(|cc| Ok(Box::new(PlayerApp::new(cc, meta_rx)))),
-    );
+    if headless {
+        run_headless(meta_rx);
+    } else {
+        let native_options = eframe::NativeOptions::default();
+        let _ = eframe::run_native(
+            "ANAHATA",
+            native_options,
+            Box::new(|cc| Ok(Box::new(PlayerApp::new(cc, meta_rx, gui_publish_tx)))),
+        );
+    }
 
+    IS_PLAYING.store(false, Ordering::Relaxed);
+    if transport_mode == TransportMode::Master {
+        transport::release_timebase(active_client.as_client());
+    }
     active_client
         .deactivate()
         .expect("Failed to deactivate client");
 }
 
-fn send_metadata(
-    metadata: &symphonia::core::meta::MetadataRevision,
-    meta_tx: &Sender<MetaCommand>,
-) {
-    let title = metadata
-        .tags()
-        .iter()
-        .find(|tag| tag.std_key == Some(symphonia::core::meta::StandardTagKey::TrackTitle))
-        .map(|tag| tag.value.to_string())
-        .unwrap_or("EH".to_owned());
-
-    let artist = metadata
-        .tags()
-        .iter()
-        .find(|tag| tag.std_key == Some(symphonia::core::meta::StandardTagKey::Artist))
-        .map(|tag| tag.value.to_string())
-        .unwrap_or("AH".to_owned());
-    meta_tx
-        .send(MetaCommand::Metadata(title, artist))
-        .expect("Failed to send metadata");
-}
-
-fn control_thread(cmd_tx: Sender<PlayerCommand>) {
-    let nc = nats::connect("nats://localhost:4222").expect("Failed to connect to NATS");
-
-    let sub = nc
-        .subscribe("anahata.>")
-        .expect("Failed to subscribe topic");
-    let player_num = ANAHATA_NO.load(Ordering::Relaxed);
-    println!("Control thread started, listening for NATS messages");
-    for msg in sub.messages() {
-        let subject = msg.subject;
-        if subject == "drishti.result" {
-            let content = String::from_utf8_lossy(&msg.data);
-            if let Ok(v) = serde_json::from_str::<serde_json::Value>(&content) {
-                let bpm = v["bpm"].as_u64().unwrap_or(0) as u32;
-                let beat_times: Vec<f64> = v["beat_times"]
-                    .as_array()
-                    .unwrap_or(&vec![])
-                    .iter()
-                    .filter_map(|v| v.as_f64())
-                    .collect();
-                println!("Received BPM: {}, {} beats", bpm, beat_times.len());
+// Anything that isn't a jump ("na" from SARASVATI, empty) means the default
+fn jump_payload(data: &[u8]) -> Option<Jump> {
+    Jump::parse(&String::from_utf8_lossy(data))
+}
+
+// Every `--fader <xone fader id>=<deck>`, e.g. `--fader 16=1`
+fn fader_args() -> HashMap<u8, u32> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == "--fader")
+        .filter_map(|pair| {
+            let fader = pair[1]
+                .split_once('=')
+                .and_then(|(id, deck)| Some((id.parse().ok()?, deck.parse().ok()?)));
+            if fader.is_none() {
+                eprintln!("Ignoring --fader {}, expected e.g. 16=1", pair[1]);
             }
-        } else if subject == format!("anahata.{}.stop", player_num) {
-            if IS_PLAYING.load(Ordering::Relaxed) == true {
-                println!("Received resume command via NATS");
-                IS_PLAYING.store(false, Ordering::Relaxed)
-            } else {
-                println!("Received stop command via NATS");
-                IS_PLAYING.store(true, Ordering::Relaxed)
-            };
-        } else if subject == format!("anahata.{}.select", player_num) {
-            let content = String::from_utf8_lossy(&msg.data);
-            let path = PathBuf::from(content.into_owned());
-            cmd_tx
-                .send(PlayerCommand::ChangeSong(path))
-                .expect("Failed to send command");
-        } else if subject == format!("anahata.{}.skipforward", player_num) {
-            cmd_tx
-                .send(PlayerCommand::SkipForward)
-                .expect("Failed to send command");
-        } else if subject == format!("anahata.{}.skipbackward", player_num) {
-            cmd_tx
-                .send(PlayerCommand::SkipBackward)
-                .expect("Failed to send command");
-        }
+            fader
+        })
+        .collect()
+}
+
+fn timecode_arg() -> Option<&'static TimecodeDef> {
+    let name = std::env::args()
+        .skip_while(|arg| arg != "--timecode")
+        .nth(1)?;
+    let def = timecode::definition(&name);
+    if def.is_none() {
+        let names: Vec<&str> = timecode::DEFINITIONS.iter().map(|def| def.name).collect();
+        eprintln!(
+            "Ignoring --timecode {}, expected one of {}",
+            name,
+            names.join(", ")
+        );
     }
+    def
 }
 
-fn decode_flac_to_vec(path: &PathBuf, meta_tx: &Sender<MetaCommand>) -> Vec<(f32, f32)> {
-    let file = File::open(path).expect("Failed to open file");
-    let mmap = unsafe { Mmap::map(&file) }.expect("Failed to mmap file");
-    let mss = MediaSourceStream::new(Box::new(std::io::Cursor::new(mmap)), Default::default());
+fn jump_arg(name: &str) -> Option<Jump> {
+    let value = std::env::args().skip_while(|arg| arg != name).nth(1)?;
+    let jump = Jump::parse(&value);
+    if jump.is_none() {
+        eprintln!(
+            "Ignoring {} {}, expected e.g. 5s, 4b or 480smp",
+            name, value
+        );
+    }
+    jump
+}
 
-    let mut hint = Hint::new();
-    hint.with_extension("flac");
+struct XrunCounter;
 
-    let probed = symphonia::default::get_probe()
-        .format(
-            &hint,
-            mss,
-            &FormatOptions::default(),
-            &MetadataOptions::default(),
-        )
-        .expect("Failed to probe media");
-
-    let mut format = probed.format;
-
-    // Collect all packets first
-    let mut packets = Vec::new();
-    while let Ok(packet) = format.next_packet() {
-        packets.push(packet);
-    }
-    let track = format.default_track().expect("No default track");
-    let codec_params = track.codec_params.clone();
-
-    // Unused, This is for flacs that are bundles of songs
-    // I have never seen this in my life
-    let track_id = track.id;
-
-    let total_frames = track
-        .codec_params
-        .n_frames
-        .expect("Could not determine total number of frames");
-
-    let sample_format = track.codec_params.sample_format;
-    // Process the collected packets in parallel
-    let decoded_samples = packets
-        .into_par_iter()
-        .map_init(
-            || {
-                symphonia::default::get_codecs()
-                    .make(&codec_params, &DecoderOptions::default())
-                    .expect("Failed to create decoder")
-            },
-            |decoder, packet| {
-                let mut samples = Vec::new();
-                let decoded = decoder.decode(&packet).expect("Failed to decode packet");
-                decode_audio_buffer(decoded, &mut samples);
-                samples
-            },
-        )
-        .reduce_with(|mut acc, chunk| {
-            acc.extend(chunk);
-            acc
-        })
-        .unwrap_or_default();
+impl jack::NotificationHandler for XrunCounter {
+    fn xrun(&mut self, _: &Client) -> Control {
+        XRUNS.fetch_add(1, Ordering::Relaxed);
+        Control::Continue
+    }
+}
 
-    println!("Decoded samples length: {}", decoded_samples.len());
-    if let Some(metadata) = format.metadata().current() {
-        send_metadata(metadata, meta_tx);
+fn health_thread(meta_tx: Sender<MetaCommand>, publish_tx: Sender<Outgoing>) {
+    let mut sampler = HealthSampler::new();
+    let mut callback_allocations = 0;
+    let mut callback_locks = 0;
+    loop {
+        thread::sleep(HEALTH_INTERVAL);
+        let health = sampler.sample();
+        if let Ok(payload) = serde_json::to_string(&health) {
+            let subject = format!("anahata.{}.health", ANAHATA_NO.load(Ordering::Relaxed));
+            if publish_tx.send(Outgoing::event(subject, payload)).is_err() {
+                break;
+            }
+        }
+        // A busy window misses a sample rather than holding this thread up
+        let _ = meta_tx.try_send(MetaCommand::Health(health));
+
+        let allocations = rt::callback_allocations();
+        if allocations > callback_allocations {
+            eprintln!(
+                "JACK callback allocated or freed {} times",
+                allocations - callback_allocations
+            );
+            callback_allocations = allocations;
+        }
+        let locks = rt::callback_locks();
+        if locks > callback_locks {
+            eprintln!(
+                "JACK callback waited on a lock {} times",
+                locks - callback_locks
+            );
+            callback_locks = locks;
+        }
     }
+}
 
-    decoded_samples
+// Stands in for the GUI: keeps draining the meta channel so the playback
+// thread never blocks on it. Returns on SIGTERM or SIGINT.
+fn run_headless(meta_rx: Receiver<MetaCommand>) {
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&terminate))
+            .expect("Failed to register signal handler");
+    }
+    println!("Running headless");
+
+    while !terminate.load(Ordering::Relaxed) {
+        // Deck state, waveform, key and beats are published by the other threads
+        if let Err(RecvTimeoutError::Disconnected) =
+            meta_rx.recv_timeout(Duration::from_millis(100))
+        {
+            break;
+        }
+    }
+    println!("Shutting down");
 }
 
-fn decode_audio_buffer(decoded: AudioBufferRef<'_>, decoded_samples: &mut Vec<(f32, f32)>) {
-    // All these guys should get the SIMD treatment too.
-    // otoh, who uses U24 samples?
-    match decoded {
-        AudioBufferRef::F32(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                decoded_samples.push((*left, *right));
-            }
+/// A message for the publish thread. Deck state (key, waveform, beats) is
+/// retained, one off events like errors are not.
+struct Outgoing {
+    subject: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+impl Outgoing {
+    fn state(subject: String, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            subject,
+            payload: payload.into(),
+            retain: true,
         }
-        AudioBufferRef::F64(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = *left as f32;
-                let right_sample = *right as f32;
-                decoded_samples.push((left_sample, right_sample));
-            }
+    }
+
+    fn event(subject: String, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            subject,
+            payload: payload.into(),
+            retain: false,
         }
-        AudioBufferRef::U16(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = *left as f32 / u16::MAX as f32;
-                let right_sample = *right as f32 / u16::MAX as f32;
-                decoded_samples.push((left_sample, right_sample));
-            }
+    }
+}
+
+// The last retained message per subject is replayed when a view asks on
+// `anahata.refresh`. The playhead and `anahata.N.state` go out on their own,
+// on every change and at least once a second.
+fn publish_thread(publish_rx: Receiver<Outgoing>, track_rx: Receiver<TrackState>) {
+    const POSITION_INTERVAL: Duration = Duration::from_millis(33);
+    const STATE_INTERVAL: Duration = Duration::from_secs(1);
+
+    let player_num = ANAHATA_NO.load(Ordering::Relaxed);
+    let nc = connect_nats();
+    let subscriptions = nc.subscribe("anahata.refresh").and_then(|refresh| {
+        let state_get = nc.subscribe(&format!("anahata.{}.state.get", player_num))?;
+        Ok((refresh, state_get))
+    });
+    let (refresh, state_get) = match subscriptions {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            eprintln!("Publishing disabled, failed to subscribe: {}", e);
+            return;
         }
-        AudioBufferRef::U32(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = *left as f32 / u32::MAX as f32;
-                let right_sample = *right as f32 / u32::MAX as f32;
-                decoded_samples.push((left_sample, right_sample));
+    };
+    let state_subject = format!("anahata.{}.state", player_num);
+
+    let mut retained: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut track = TrackState::default();
+    let mut last_position = None;
+    let mut last_state: Option<(DeckState, Instant)> = None;
+    let mut next_position = Instant::now();
+    loop {
+        match publish_rx.recv_timeout(POSITION_INTERVAL) {
+            Ok(message) => {
+                if let Err(e) = nc.publish(&message.subject, &message.payload) {
+                    eprintln!("Failed to publish {}: {}", message.subject, e);
+                }
+                if message.retain {
+                    retained.insert(message.subject, message.payload);
+                }
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-        AudioBufferRef::U8(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = (*left as f32 - 128.0) / 128.0;
-                let right_sample = (*right as f32 - 128.0) / 128.0;
-                decoded_samples.push((left_sample, right_sample));
-            }
+        if let Some(latest) = track_rx.try_iter().last() {
+            track = latest;
         }
-        AudioBufferRef::S8(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = *left as f32 / 128.0;
-                let right_sample = *right as f32 / 128.0;
-                decoded_samples.push((left_sample, right_sample));
+
+        if refresh.try_next().is_some() {
+            for (subject, payload) in &retained {
+                let _ = nc.publish(subject, payload);
             }
+            last_position = None;
+            last_state = None;
         }
-        AudioBufferRef::S16(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = *left as f32 / i16::MAX as f32;
-                let right_sample = *right as f32 / i16::MAX as f32;
-                decoded_samples.push((left_sample, right_sample));
+
+        while let Some(request) = state_get.try_next() {
+            if let Ok(payload) = serde_json::to_string(&deck_state(&track)) {
+                let _ = request.respond(payload);
             }
         }
-        AudioBufferRef::U24(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = (left.inner() as f32) / 8388607.0;
-                let right_sample = (right.inner() as f32) / 8388607.0;
-                decoded_samples.push((left_sample, right_sample));
+
+        if Instant::now() >= next_position {
+            next_position = Instant::now() + POSITION_INTERVAL;
+            let position = (
+                PLAYHEAD.load(Ordering::Relaxed),
+                IS_PLAYING.load(Ordering::Relaxed),
+                speed(),
+            );
+            if last_position != Some(position) {
+                let subject = format!("anahata.{}.position", player_num);
+                let _ = nc.publish(
+                    &subject,
+                    deck::format_position(position.0, position.1, position.2),
+                );
+                last_position = Some(position);
             }
-        }
-        AudioBufferRef::S24(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = (left.inner() as i32 as f32) / 8388607.0;
-                let right_sample = (right.inner() as i32 as f32) / 8388607.0;
-                decoded_samples.push((left_sample, right_sample));
+
+            let state = deck_state(&track);
+            let due = last_state
+                .as_ref()
+                .is_none_or(|(last, sent)| *last != state || sent.elapsed() >= STATE_INTERVAL);
+            if due {
+                if let Ok(payload) = serde_json::to_string(&state) {
+                    let _ = nc.publish(&state_subject, payload);
+                }
+                last_state = Some((state, Instant::now()));
             }
         }
+    }
+}
 
-        AudioBufferRef::S32(buf) => {
-            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
-                let left_sample = *left as f32 / i32::MAX as f32;
-                let right_sample = *right as f32 / i32::MAX as f32;
-                decoded_samples.push((left_sample, right_sample));
+fn end_of_track() -> EndOfTrack {
+    match END_OF_TRACK.load(Ordering::Relaxed) {
+        1 => EndOfTrack::Loop,
+        2 => EndOfTrack::Next,
+        _ => EndOfTrack::Stop,
+    }
+}
+
+// A looping track never ends, so there is nothing to warn about
+fn end_warning() -> bool {
+    let remaining_ms = DURATION
+        .load(Ordering::Relaxed)
+        .saturating_sub(CURRENT_POSITION.load(Ordering::Relaxed));
+    IS_PLAYING.load(Ordering::Relaxed)
+        && end_of_track() != EndOfTrack::Loop
+        && remaining_ms < END_WARNING_SECONDS * 1000
+}
+
+// The rate the deck plays at. Only a timecode record changes it, there is
+// no pitch control.
+fn speed() -> f64 {
+    if TIMECODE.load(Ordering::Relaxed) {
+        f64::from_bits(TIMECODE_SPEED.load(Ordering::Relaxed))
+    } else {
+        1.0
+    }
+}
+
+fn loop_range() -> Option<(u64, u64)> {
+    let (start, end) = (
+        LOOP_START.load(Ordering::Relaxed),
+        LOOP_END.load(Ordering::Relaxed),
+    );
+    (end > start).then_some((start, end))
+}
+
+fn deck_state(track: &TrackState) -> DeckState {
+    let tempo = speed();
+    DeckState {
+        path: track.path.as_ref().map(|path| path.display().to_string()),
+        title: track.title.clone(),
+        artist: track.artist.clone(),
+        position_ms: CURRENT_POSITION.load(Ordering::Relaxed),
+        duration_ms: DURATION.load(Ordering::Relaxed),
+        playing: IS_PLAYING.load(Ordering::Relaxed),
+        tempo,
+        bpm: track.bpm.map(|bpm| bpm * tempo),
+        loop_range: loop_range(),
+        loading: IS_LOADING.load(Ordering::Relaxed),
+        load_lock: LOAD_LOCK.load(Ordering::Relaxed),
+        quantize: QUANTIZE.load(Ordering::Relaxed),
+        end_of_track: end_of_track(),
+        end_warning: end_warning(),
+    }
+}
+
+fn control_thread(cmd_tx: Sender<PlayerCommand>, mut skip_default: Jump, mut nudge_default: Jump) {
+    let nc = connect_nats();
+    let subscriptions = nc.subscribe("anahata.>").and_then(|sub| {
+        let drishti = nc.subscribe("drishti.result")?;
+        Ok((sub, drishti))
+    });
+    let (sub, drishti) = match subscriptions {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            eprintln!("Remote control disabled, failed to subscribe: {}", e);
+            return;
+        }
+    };
+    let player_num = ANAHATA_NO.load(Ordering::Relaxed);
+    println!("Control thread started, listening for NATS messages");
+    loop {
+        let msg = crossbeam::channel::select! {
+            recv(sub.receiver()) -> msg => msg,
+            recv(drishti.receiver()) -> msg => msg,
+        };
+        let Ok(msg) = msg else {
+            break;
+        };
+        let subject = msg.subject;
+        let command = if subject == "drishti.result" {
+            let content = String::from_utf8_lossy(&msg.data);
+            serde_json::from_str::<serde_json::Value>(&content)
+                .ok()
+                .and_then(|v| {
+                    let bpm = v["bpm"].as_f64().unwrap_or(0.0);
+                    let beat_times: Vec<f64> = v["beat_times"]
+                        .as_array()
+                        .unwrap_or(&vec![])
+                        .iter()
+                        .filter_map(|v| v.as_f64())
+                        .collect();
+                    println!("Received BPM: {}, {} beats", bpm, beat_times.len());
+                    // Results without a path are for whatever is loaded right now
+                    let path = v["path"].as_str().map(PathBuf::from);
+                    (bpm > 0.0).then_some(PlayerCommand::BeatGrid {
+                        path,
+                        bpm,
+                        beat_times,
+                    })
+                })
+        } else if subject == format!("anahata.{}.stop", player_num) {
+            if IS_PLAYING.load(Ordering::Relaxed) {
+                println!("Received resume command via NATS");
+                IS_PLAYING.store(false, Ordering::Relaxed);
+                None
+            } else if QUANTIZE.load(Ordering::Relaxed) {
+                // The playback thread knows the beatgrid, it starts the deck
+                println!("Received stop command via NATS, starting on the beat");
+                Some(PlayerCommand::Play)
+            } else {
+                println!("Received stop command via NATS");
+                IS_PLAYING.store(true, Ordering::Relaxed);
+                None
+            }
+        } else if subject == format!("anahata.{}.select", player_num)
+            || subject == format!("anahata.{}.select.force", player_num)
+            || subject == format!("anahata.{}.select.next", player_num)
+        {
+            let content = String::from_utf8_lossy(&msg.data);
+            Some(PlayerCommand::ChangeSong {
+                path: PathBuf::from(content.into_owned()),
+                force: subject.ends_with(".force"),
+                next: subject.ends_with(".next"),
+            })
+        } else if subject == format!("anahata.{}.endoftrack", player_num) {
+            match EndOfTrack::parse(&String::from_utf8_lossy(&msg.data)) {
+                Some(mode) => {
+                    println!("End of track: {:?}", mode);
+                    END_OF_TRACK.store(mode as u8, Ordering::Relaxed);
+                }
+                None => eprintln!("Unknown end of track mode, expected stop, loop or next"),
+            }
+            None
+        } else if subject == format!("anahata.{}.loadlock", player_num) {
+            let locked = msg.data.as_slice() != b"0";
+            println!("Load lock {}", if locked { "on" } else { "off" });
+            LOAD_LOCK.store(locked, Ordering::Relaxed);
+            None
+        } else if subject == format!("anahata.{}.seek", player_num) {
+            // Milliseconds into the track, what the waveforms send
+            String::from_utf8_lossy(&msg.data)
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .map(|position_ms| {
+                    PlayerCommand::Seek(position_ms * PLAYBACK_SAMPLE_RATE as u64 / 1000)
+                })
+        } else if subject == format!("anahata.{}.cue", player_num) {
+            // Numbered from 1 like the pads
+            match String::from_utf8_lossy(&msg.data).trim().parse::<usize>() {
+                Ok(cue) if cue > 0 => Some(PlayerCommand::Cue(cue - 1)),
+                _ => {
+                    eprintln!("Unknown hot cue, expected a number from 1");
+                    None
+                }
+            }
+        } else if subject == format!("anahata.{}.cue.set", player_num) {
+            Some(PlayerCommand::SetCue)
+        } else if subject == format!("anahata.{}.loopin", player_num) {
+            Some(PlayerCommand::LoopIn)
+        } else if subject == format!("anahata.{}.loopout", player_num) {
+            Some(PlayerCommand::LoopOut)
+        } else if subject == format!("anahata.{}.loopexit", player_num) {
+            Some(PlayerCommand::LoopExit)
+        } else if subject == format!("anahata.{}.quantize", player_num) {
+            let quantize = msg.data.as_slice() != b"0";
+            println!("Quantize {}", if quantize { "on" } else { "off" });
+            QUANTIZE.store(quantize, Ordering::Relaxed);
+            None
+        } else if subject == format!("anahata.{}.zoomin", player_num) {
+            PENDING_ZOOM.fetch_add(1, Ordering::Relaxed);
+            None
+        } else if subject == format!("anahata.{}.zoomout", player_num) {
+            PENDING_ZOOM.fetch_sub(1, Ordering::Relaxed);
+            None
+        } else if subject == format!("anahata.{}.skip.default", player_num) {
+            // Changes what a skip without a payload does
+            if let Some(jump) = jump_payload(&msg.data) {
+                println!("Default skip is now {}", jump);
+                skip_default = jump;
+            }
+            None
+        } else if subject == format!("anahata.{}.nudge.default", player_num) {
+            if let Some(jump) = jump_payload(&msg.data) {
+                println!("Default nudge is now {}", jump);
+                nudge_default = jump;
+            }
+            None
+        } else if subject == format!("anahata.{}.skipforward", player_num) {
+            let jump = jump_payload(&msg.data).unwrap_or(skip_default);
+            Some(PlayerCommand::Jump { jump, snap: true })
+        } else if subject == format!("anahata.{}.skipbackward", player_num) {
+            let jump = jump_payload(&msg.data).unwrap_or(skip_default);
+            Some(PlayerCommand::Jump {
+                jump: jump.reversed(),
+                snap: true,
+            })
+        } else if subject == format!("anahata.{}.nudgeforward", player_num) {
+            let jump = jump_payload(&msg.data).unwrap_or(nudge_default);
+            Some(PlayerCommand::Jump { jump, snap: false })
+        } else if subject == format!("anahata.{}.nudgebackward", player_num) {
+            let jump = jump_payload(&msg.data).unwrap_or(nudge_default);
+            Some(PlayerCommand::Jump {
+                jump: jump.reversed(),
+                snap: false,
+            })
+        } else {
+            None
+        };
+
+        if let Some(command) = command {
+            if cmd_tx.send(command).is_err() {
+                // The playback thread is gone, nothing left to control
+                break;
             }
         }
     }
 }
 
-fn playback_thread(
-    mut producer: rtrb::Producer<(f32, f32)>,
-    mut song: Vec<(f32, f32)>,
+/// The parts of the deck state only the playback thread knows about, the
+/// rest comes from the atomics in `globals`.
+#[derive(Debug, Clone, Default)]
+struct TrackState {
+    path: Option<PathBuf>,
+    title: Option<String>,
+    artist: Option<String>,
+    bpm: Option<f64>,
+}
 
-    meta_tx: &Sender<MetaCommand>,
+fn playback_thread(
+    mut backend: impl AudioBackend,
+    job_tx: Sender<Job>,
+    mut anchors: BeatAnchors,
     cmd_rx: crossbeam::channel::Receiver<PlayerCommand>,
 ) {
     const SAMPLE_RATE: f64 = 48000.0;
-    const MS_PER_SAMPLE: f64 = 1000.0 / SAMPLE_RATE; // ~0.0208333 ms per sample
-    const SAMPLES_PER_SECOND: u64 = 48000;
-    const SKIP_SECONDS: u64 = 5;
+    const CHUNK_SIZE: usize = 1024;
+    // In case JACK stops calling back, commands still get through
+    const IDLE_WAKE: Duration = Duration::from_millis(20);
+
+    // The loaded track, its beatgrid and hot cues, the worker holds the
+    // rest of the analysis
+    let mut current: Option<(PathBuf, Vec<f64>, Vec<u64>)> = None;
+    let mut track = TrackState::default();
+    let mut loads = Loads::default();
+    let mut deck = Deck::new(SAMPLE_RATE);
+    let mut chunk = vec![(0.0, 0.0); CHUNK_SIZE];
+    // Path, playhead and whether to play, applied when that path loads
+    let mut restore: Option<(PathBuf, u64, bool)> = None;
 
     loop {
-        match cmd_rx.try_recv() {
-            Ok(PlayerCommand::ChangeSong(path)) => {
-                IS_PLAYING.store(false, Ordering::Relaxed);
+        let received = cmd_rx.try_recv();
+        let idle = received.is_err();
+        match received {
+            Ok(PlayerCommand::ChangeSong {
+                path, next: true, ..
+            }) => {
+                // Not asked for, or the DJ loaded something since
+                let Some(generation) = loads.start_next(load_locked()) else {
+                    continue;
+                };
+                request_load(&job_tx, path, generation, false);
+            }
+            Ok(PlayerCommand::ChangeSong { path, force, .. }) => {
+                let Some(generation) = loads.start(force, load_locked()) else {
+                    let error = LoadError::Locked;
+                    hand_off(&job_tx, Job::LoadError { path, error });
+                    continue;
+                };
+                request_load(&job_tx, path, generation, force);
+            }
+            Ok(PlayerCommand::Restore {
+                path,
+                playhead,
+                play,
+            }) => {
+                println!("Restoring {}", path.display());
+                // Nothing is playing yet, it's the deck as it was left
+                let Some(generation) = loads.start(true, false) else {
+                    continue;
+                };
+                request_load(&job_tx, path.clone(), generation, true);
+                restore = Some((path, playhead, play));
+            }
+            Ok(PlayerCommand::Loaded {
+                generation,
+                path,
+                force,
+                result,
+            }) => {
+                let play = match loads.finish(generation, force, load_locked()) {
+                    Finished::Stale => continue,
+                    Finished::Locked => {
+                        // Resumed while it decoded, the playing track stays
+                        restore = None;
+                        IS_LOADING.store(false, Ordering::Relaxed);
+                        let error = LoadError::Locked;
+                        hand_off(&job_tx, Job::LoadError { path, error });
+                        continue;
+                    }
+                    Finished::Ready { play } => play,
+                };
+                // Loaded or not, the latest load ends the wait on the restored
+                // track, anything loaded in the meantime replaced it
+                let restored = restore
+                    .take()
+                    .filter(|(restore_path, _, _)| *restore_path == path);
+                IS_LOADING.store(false, Ordering::Relaxed);
+                let loaded = match result {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        // A bad file leaves the current track loaded and playing
+                        hand_off(&job_tx, Job::LoadError { path, error: e });
+                        continue;
+                    }
+                };
+                let LoadedTrack {
+                    hash,
+                    cached,
+                    metadata,
+                    decoded,
+                } = *loaded;
 
+                IS_PLAYING.store(false, Ordering::Relaxed);
                 PLAYHEAD.store(0, Ordering::Relaxed);
                 CURRENT_POSITION.store(0, Ordering::Relaxed);
+                deck.load(Arc::new(decoded.samples));
+                store_loop(&deck);
+                hand_off(&job_tx, Job::Lock(Arc::clone(deck.song())));
+                let metadata = metadata.unwrap_or_default();
+                let tagged_key = metadata.key;
+                track = TrackState {
+                    path: Some(path.clone()),
+                    title: metadata.title.clone(),
+                    artist: metadata.artist.clone(),
+                    bpm: cached.as_ref().and_then(|analysis| analysis.bpm),
+                };
+                hand_off(&job_tx, Job::Metadata(Box::new(metadata)));
+                hand_off(&job_tx, Job::Track(track.clone()));
 
-                song = decode_flac_to_vec(&path, meta_tx);
-
-                let wf = generate_waveform(&song, 20000);
-                let _ = meta_tx.send(MetaCommand::Waveform(wf));
+                DURATION.store(deck.duration_ms(), Ordering::Relaxed);
+                if play {
+                    IS_PLAYING.store(true, Ordering::Relaxed);
+                }
+                if let Some((_, playhead, play)) = restored {
+                    deck.seek(playhead);
+                    PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+                    CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
+                    IS_PLAYING.store(play, Ordering::Relaxed);
+                }
 
-                let total_samples = song.len() as f64;
-                let duration_ms = (total_samples * MS_PER_SAMPLE) as u64;
-                DURATION.store(duration_ms, Ordering::Relaxed);
-                IS_PLAYING.store(false, Ordering::Relaxed);
+                let (beat_times, cues) = cached
+                    .as_ref()
+                    .map(|analysis| (analysis.beat_times.clone(), analysis.cues.clone()))
+                    .unwrap_or_default();
+                current = Some((path.clone(), beat_times, cues));
+                hand_off(
+                    &job_tx,
+                    Job::Loaded {
+                        path,
+                        hash,
+                        cached,
+                        song: Arc::clone(deck.song()),
+                        tagged_key,
+                        sample_rate: decoded.sample_rate as f32,
+                    },
+                );
             }
-            Ok(PlayerCommand::SkipForward) => {
-                let current_pos = PLAYHEAD.load(Ordering::Relaxed);
-                let skip_amount = SKIP_SECONDS * SAMPLES_PER_SECOND;
-                let new_pos = if (current_pos + skip_amount) as usize >= song.len() {
-                    song.len() as u64
+            Ok(PlayerCommand::BeatGrid {
+                path,
+                bpm,
+                beat_times,
+            }) => {
+                // The worker keeps it with the analysis and sends it back
+                if let Some((current_path, _, _)) = &current {
+                    if path.is_none() || path.as_ref() == Some(current_path) {
+                        let path = current_path.clone();
+                        hand_off(
+                            &job_tx,
+                            Job::BeatGrid {
+                                path,
+                                bpm,
+                                beat_times,
+                            },
+                        );
+                    }
+                }
+            }
+            Ok(PlayerCommand::Beats {
+                path,
+                bpm,
+                beat_times,
+                cues,
+            }) => {
+                // Results for a track that has since been replaced are dropped
+                if let Some((current_path, current_beats, current_cues)) = &mut current {
+                    if *current_path == path {
+                        *current_beats = beat_times;
+                        *current_cues = cues;
+                        track.bpm = bpm;
+                        hand_off(&job_tx, Job::Track(track.clone()));
+                    }
+                }
+            }
+            Ok(PlayerCommand::Jump { jump, snap }) => {
+                let beat_times = current.as_ref().map_or(&[][..], |(_, beats, _)| &beats[..]);
+                deck.seek(PLAYHEAD.load(Ordering::Relaxed));
+                if snap && QUANTIZE.load(Ordering::Relaxed) && beat_times.len() >= 2 {
+                    // The deck only hears it stopped at the next chunk
+                    deck.playing = IS_PLAYING.load(Ordering::Relaxed);
+                    if deck.quantized_jump(jump, beat_times) && !deck.playing {
+                        PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+                        CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
+                    }
+                } else if deck.jump(jump, beat_times) {
+                    PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+                    CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
                 } else {
-                    current_pos + skip_amount
+                    println!("Ignoring {} jump, no beatgrid", jump);
+                }
+            }
+            Ok(PlayerCommand::Play) => {
+                if IS_PLAYING.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let beat_times = current.as_ref().map_or(&[][..], |(_, beats, _)| &beats[..]);
+                deck.seek(PLAYHEAD.load(Ordering::Relaxed));
+                // Without a grid there is no beat to start on
+                if deck.snap_to_beat(beat_times) {
+                    PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+                    CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
+                    STALE_FRAMES.store(backend.queued() as u64, Ordering::Relaxed);
+                    START_ON_BEAT.store(true, Ordering::Relaxed);
+                }
+                IS_PLAYING.store(true, Ordering::Release);
+            }
+            Ok(PlayerCommand::Seek(target)) => {
+                let beat_times = current.as_ref().map_or(&[][..], |(_, beats, _)| &beats[..]);
+                seek_deck(&mut deck, target, beat_times);
+            }
+            Ok(PlayerCommand::Cue(cue)) => {
+                let Some((_, beat_times, cues)) = &current else {
+                    continue;
+                };
+                match cues.get(cue) {
+                    Some(&target) => seek_deck(&mut deck, target, beat_times),
+                    None => println!("Ignoring hot cue {}, not set", cue + 1),
+                }
+            }
+            Ok(PlayerCommand::SetCue) => {
+                // The worker keeps it with the analysis and sends it back
+                let Some((path, beat_times, _)) = &current else {
+                    continue;
                 };
-                PLAYHEAD.store(new_pos, Ordering::Relaxed);
-                let ms_position = (new_pos as f64 * MS_PER_SAMPLE) as u64;
-                CURRENT_POSITION.store(ms_position, Ordering::Relaxed);
-            }
-            Ok(PlayerCommand::SkipBackward) => {
-                let current_pos = PLAYHEAD.load(Ordering::Relaxed);
-                let skip_amount = SKIP_SECONDS * SAMPLES_PER_SECOND;
-                let new_pos = if current_pos <= skip_amount {
-                    0
+                catch_up(&mut deck);
+                let at = deck
+                    .nearest_beat(beat_times)
+                    .filter(|_| QUANTIZE.load(Ordering::Relaxed))
+                    .unwrap_or(deck.playhead());
+                let path = path.clone();
+                hand_off(&job_tx, Job::Cue { path, at });
+            }
+            Ok(PlayerCommand::LoopIn) => {
+                let beat_times = current.as_ref().map_or(&[][..], |(_, beats, _)| &beats[..]);
+                catch_up(&mut deck);
+                // Without a grid the loop starts where the deck is
+                if !(QUANTIZE.load(Ordering::Relaxed) && deck.quantized_loop_in(beat_times)) {
+                    deck.loop_in(deck.playhead());
+                }
+            }
+            Ok(PlayerCommand::LoopOut) => {
+                let beat_times = current.as_ref().map_or(&[][..], |(_, beats, _)| &beats[..]);
+                catch_up(&mut deck);
+                let closed = if QUANTIZE.load(Ordering::Relaxed) && beat_times.len() >= 2 {
+                    deck.quantized_loop_out(beat_times)
                 } else {
-                    current_pos - skip_amount
+                    deck.loop_out(deck.playhead())
                 };
-                PLAYHEAD.store(new_pos, Ordering::Relaxed);
-                let ms_position = (new_pos as f64 * MS_PER_SAMPLE) as u64;
-                CURRENT_POSITION.store(ms_position, Ordering::Relaxed);
+                if !closed {
+                    println!("Ignoring loop out, no loop in before it");
+                }
+                // Closing it behind the playhead goes round straight away
+                PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+                CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
+                store_loop(&deck);
+            }
+            Ok(PlayerCommand::LoopExit) => {
+                deck.exit_loop();
+                store_loop(&deck);
             }
             _ => {}
         }
 
-        if IS_PLAYING.load(Ordering::Relaxed) {
-            let current_pos = PLAYHEAD.load(Ordering::Relaxed) as usize;
-            if current_pos >= song.len() {
-                PLAYHEAD.store(0, Ordering::Relaxed);
-                CURRENT_POSITION.store(0, Ordering::Relaxed);
-                continue;
+        if TIMECODE.load(Ordering::Relaxed) {
+            follow_timecode(&mut deck, backend.queued(), SAMPLE_RATE);
+        }
+        // The GUI and remote control move the playhead and start and stop
+        // through the globals, the deck picks that up before each chunk.
+        // Fill all the room there is, the callback may have drained a whole
+        // period.
+        while IS_PLAYING.load(Ordering::Relaxed) && backend.available() > 0 {
+            let room = backend.available().min(CHUNK_SIZE);
+            let playhead = PLAYHEAD.load(Ordering::Relaxed);
+            if playhead != deck.playhead() {
+                deck.seek(playhead);
             }
+            deck.playing = true;
+            deck.end_of_track = end_of_track();
+            let ended = match deck.pump(&mut backend, &mut chunk[..room]) {
+                Some(ended) => {
+                    anchors.wrote(room);
+                    ended
+                }
+                None => false,
+            };
+            PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+            CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
+            anchor_beat(&mut anchors, &current, deck.speed, SAMPLE_RATE);
 
-            let free_space = producer.slots();
-            if free_space >= 1024 {
-                let chunk_size = 1024.min(song.len() - current_pos);
-                for i in 0..chunk_size {
-                    if let Err(_) = producer.push(song[current_pos + i]) {
-                        break;
-                    }
+            if ended && !deck.playing {
+                IS_PLAYING.store(false, Ordering::Relaxed);
+                let current_path = current.as_ref().map(|(path, _, _)| path);
+                if let (EndOfTrack::Next, Some(path)) = (deck.end_of_track, current_path) {
+                    // AKASHA answers on select.next, only that load plays
+                    loads.ask_next();
+                    let next = Outgoing::event(
+                        format!("akasha.{}.next", ANAHATA_NO.load(Ordering::Relaxed)),
+                        path.display().to_string(),
+                    );
+                    hand_off(&job_tx, Job::Publish(next));
                 }
-                PLAYHEAD.fetch_add(chunk_size as u64, Ordering::Relaxed);
+            }
+        }
+        // A seek while stopped, or a new beatgrid
+        anchor_beat(&mut anchors, &current, deck.speed, SAMPLE_RATE);
+
+        // The JACK callback wakes us each cycle once it has made room
+        if idle {
+            thread::park_timeout(IDLE_WAKE);
+        }
+    }
+}
+
+// The GUI and remote control move the playhead and start and stop through
+// the globals, the deck catches up before a command acts on it. A seek it
+// has scheduled stays unless the playhead moved.
+fn catch_up(deck: &mut Deck) {
+    let playhead = PLAYHEAD.load(Ordering::Relaxed);
+    if playhead != deck.playhead() {
+        deck.seek(playhead);
+    }
+    deck.playing = IS_PLAYING.load(Ordering::Relaxed);
+}
+
+// Waveform clicks and hot cues, timed like a skip when quantizing
+fn seek_deck(deck: &mut Deck, target: u64, beat_times: &[f64]) {
+    catch_up(deck);
+    if QUANTIZE.load(Ordering::Relaxed) && beat_times.len() >= 2 {
+        if !deck.quantized_seek(target, beat_times) || deck.playing {
+            return;
+        }
+    } else {
+        deck.seek(target);
+    }
+    PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+    CURRENT_POSITION.store(deck.position_ms(), Ordering::Relaxed);
+}
 
-                let ms_position = (PLAYHEAD.load(Ordering::Relaxed) as f64 * MS_PER_SAMPLE) as u64;
-                CURRENT_POSITION.store(ms_position, Ordering::Relaxed);
+fn store_loop(deck: &Deck) {
+    let (start, end) = deck.loop_range().unwrap_or_default();
+    LOOP_START.store(start, Ordering::Relaxed);
+    LOOP_END.store(end, Ordering::Relaxed);
+}
+
+// Decode off the playback thread so the current track keeps playing and
+// commands keep working. Only the latest `generation` counts.
+fn request_load(job_tx: &Sender<Job>, path: PathBuf, generation: u64, force: bool) {
+    IS_LOADING.store(true, Ordering::Relaxed);
+    let load = Job::Load {
+        path,
+        generation,
+        force,
+    };
+    if !hand_off(job_tx, load) {
+        IS_LOADING.store(false, Ordering::Relaxed);
+    }
+}
+
+// The feeder never waits for the worker, it would stop filling the ring
+// buffer. Should the worker be that far behind, the job is dropped and
+// false returned.
+fn hand_off(job_tx: &Sender<Job>, job: Job) -> bool {
+    match job_tx.try_send(job) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            eprintln!("Worker thread is behind, dropped a job");
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+fn worker_thread(
+    job_tx: Sender<Job>,
+    job_rx: Receiver<Job>,
+    cmd_tx: Sender<PlayerCommand>,
+    meta_tx: Sender<MetaCommand>,
+    publish_tx: Sender<Outgoing>,
+    track_txs: [Sender<TrackState>; 2],
+) {
+    let cache = match AnalysisCache::open_default() {
+        Ok(cache) => Some(Arc::new(cache)),
+        Err(e) => {
+            eprintln!("Analysis cache unavailable, analysing every load: {}", e);
+            None
+        }
+    };
+    let store = |hash: &Option<String>, analysis: &Analysis| {
+        if let (Some(cache), Some(hash)) = (&cache, hash) {
+            if let Err(e) = cache.store(hash, analysis) {
+                eprintln!("Failed to cache analysis: {}", e);
+            }
+        }
+    };
+    // Tells the feeder the beatgrid it should play to and its hot cues
+    let send_beats = |path: &Path, analysis: &Analysis| {
+        let _ = cmd_tx.send(PlayerCommand::Beats {
+            path: path.to_path_buf(),
+            bpm: analysis.bpm,
+            beat_times: analysis.beat_times.clone(),
+            cues: analysis.cues.clone(),
+        });
+    };
+    // Held for the track that's loaded, unlocked when the next one replaces it
+    let mut _song_lock: Option<MemoryLock<Frame>> = None;
+    // The loaded track's path, content hash and analysis
+    let mut current: Option<(PathBuf, Option<String>, Analysis)> = None;
+
+    for job in job_rx {
+        match job {
+            Job::Load {
+                path,
+                generation,
+                force,
+            } => {
+                let cache = cache.clone();
+                let cmd_tx = cmd_tx.clone();
+                thread::spawn(move || {
+                    let result = load_track(&path, cache.as_deref()).map(Box::new);
+                    let _ = cmd_tx.send(PlayerCommand::Loaded {
+                        generation,
+                        path,
+                        force,
+                        result,
+                    });
+                });
+            }
+            Job::Lock(song) => {
+                _song_lock = MemoryLock::new(song)
+                    .map_err(|e| {
+                        eprintln!(
+                            "Could not lock the track in memory, check memlock in limits.conf: {}",
+                            e
+                        )
+                    })
+                    .ok();
+            }
+            Job::Loaded {
+                path,
+                hash,
+                cached,
+                song,
+                tagged_key,
+                sample_rate,
+            } => match cached {
+                Some(analysis) if analysis.is_current() => {
+                    announce_analysis(&path, &analysis, &meta_tx, &publish_tx);
+                    current = Some((path, hash, analysis));
+                }
+                stale => {
+                    // An older analysis still has the beatgrid, only the
+                    // waveforms, key and loudness are worked out again.
+                    // Don't leave the previous track's waveform up meanwhile.
+                    let stale = stale.unwrap_or_default();
+                    let _ = meta_tx.try_send(MetaCommand::Waveform(Vec::new()));
+                    let _ = meta_tx.try_send(MetaCommand::Detail(Vec::new()));
+                    let _ = meta_tx.try_send(MetaCommand::Key(None));
+                    let _ = meta_tx.try_send(MetaCommand::BeatGrid(stale.beat_times.clone()));
+                    if !stale.beat_times.is_empty() {
+                        publish_beats(&stale.beat_times, &publish_tx);
+                    }
+                    current = Some((path.clone(), hash, stale));
+                    let job_tx = job_tx.clone();
+                    thread::spawn(move || {
+                        let analysis = analyse(&song, tagged_key, sample_rate);
+                        let _ = job_tx.send(Job::Analysed { path, analysis });
+                    });
+                }
+            },
+            Job::Analysed { path, mut analysis } => {
+                // Results for a track that has since been replaced are dropped
+                if let Some((current_path, hash, current_analysis)) = &mut current {
+                    if *current_path == path {
+                        // A beatgrid from DRISHTI, now or before the
+                        // analysis version changed, wins over ours
+                        analysis.keep_external(current_analysis);
+                        *current_analysis = analysis;
+                        store(hash, current_analysis);
+                        announce_analysis(&path, current_analysis, &meta_tx, &publish_tx);
+                        send_beats(&path, current_analysis);
+                    }
+                }
+            }
+            Job::BeatGrid {
+                path,
+                bpm,
+                beat_times,
+            } => {
+                if let Some((current_path, hash, analysis)) = &mut current {
+                    if *current_path == path {
+                        analysis.bpm = Some(bpm);
+                        analysis.beat_times = beat_times;
+                        let beat_times = analysis.beat_times.clone();
+                        let _ = meta_tx.try_send(MetaCommand::BeatGrid(beat_times));
+                        publish_beats(&analysis.beat_times, &publish_tx);
+                        store(hash, analysis);
+                        send_beats(&path, analysis);
+                    }
+                }
+            }
+            Job::Cue { path, at } => {
+                if let Some((current_path, hash, analysis)) = &mut current {
+                    if *current_path == path {
+                        analysis.cues.push(at);
+                        store(hash, analysis);
+                        send_beats(&path, analysis);
+                    }
+                }
+            }
+            Job::Metadata(metadata) => {
+                let _ = meta_tx.try_send(MetaCommand::Metadata(metadata));
+            }
+            Job::Track(track) => {
+                for track_tx in &track_txs {
+                    let _ = track_tx.send(track.clone());
+                }
+            }
+            Job::LoadError { path, error } => {
+                report_load_error(&path, &error, &meta_tx, &publish_tx);
+            }
+            Job::Publish(outgoing) => {
+                let _ = publish_tx.send(outgoing);
             }
         }
-        thread::sleep(Duration::from_micros(1000));
     }
 }
 
-#[derive(Debug)]
-struct FrequencyBand {
-    rms_left: f32,
-    rms_right: f32,
-    peak_left: f32,
-    peak_right: f32,
+// Loads are refused while the deck plays with the load lock on
+fn load_locked() -> bool {
+    LOAD_LOCK.load(Ordering::Relaxed) && IS_PLAYING.load(Ordering::Relaxed)
 }
 
-#[derive(Debug)]
-struct WaveformBin {
-    low: FrequencyBand,  // 20-200Hz (sub bass and bass)
-    mid: FrequencyBand,  // 200-3000Hz (kicks, snares, vocals)
-    high: FrequencyBand, // 3000-20000Hz (hi-hats, cymbals, air)
-}
-
-fn generate_waveform(song: &Vec<(f32, f32)>, num_bins: usize) -> Vec<WaveformBin> {
-    if song.is_empty() {
-        return vec![];
-    }
-
-    let samples_per_bin = (song.len() + num_bins - 1) / num_bins;
-    let mut waveform = Vec::with_capacity(num_bins);
-
-    // Formula used: coeff = exp(-2.0 * PI * (cutoff_freq / sample_rate))
-    // For 48kHz:
-    // 300Hz -> exp(-2π * (300/48000)) ≈ 0.9961
-    // 4kHz  -> exp(-2π * (4000/48000)) ≈ 0.9484
-
-    let low_coeff = 0.9961f32; // ~300Hz cutoff
-
-    // Mid band: 300Hz - 4kHz
-    let mid_low_coeff = 0.9961f32; // ~300Hz
-    let mid_high_coeff = 0.9484f32; // ~4kHz
-
-    // High band: 4kHz - 20kHz
-    let high_coeff = 0.9484f32; // ~4kHz
-
-    for bin in 0..num_bins {
-        let chunk_start = bin * samples_per_bin;
-        let chunk_end = (chunk_start + samples_per_bin).min(song.len());
-
-        if chunk_start >= song.len() {
-            waveform.push(WaveformBin {
-                low: FrequencyBand {
-                    rms_left: 0.0,
-                    rms_right: 0.0,
-                    peak_left: 0.0,
-                    peak_right: 0.0,
-                },
-                mid: FrequencyBand {
-                    rms_left: 0.0,
-                    rms_right: 0.0,
-                    peak_left: 0.0,
-                    peak_right: 0.0,
-                },
-                high: FrequencyBand {
-                    rms_left: 0.0,
-                    rms_right: 0.0,
-                    peak_left: 0.0,
-                    peak_right: 0.0,
-                },
-            });
+fn session_name() -> String {
+    format!("anahata-{}", ANAHATA_NO.load(Ordering::Relaxed))
+}
+
+// Saves the deck's session whenever it has changed. Nothing is saved until
+// a track has loaded, so a deck that just started or is still loading what
+// it restored never overwrites the last session with an empty one.
+fn session_thread(sessions: SessionStore, track_rx: Receiver<TrackState>) {
+    const SESSION_INTERVAL: Duration = Duration::from_secs(2);
+
+    let name = session_name();
+    let mut track = TrackState::default();
+    let mut saved: Option<DeckSession> = None;
+    loop {
+        thread::sleep(SESSION_INTERVAL);
+        loop {
+            match track_rx.try_recv() {
+                Ok(latest) => track = latest,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        if track.path.is_none() || IS_LOADING.load(Ordering::Relaxed) {
             continue;
         }
+        let session = DeckSession {
+            path: track.path.as_ref().map(|path| path.display().to_string()),
+            playhead: PLAYHEAD.load(Ordering::Relaxed),
+            playing: IS_PLAYING.load(Ordering::Relaxed),
+            end_of_track: end_of_track(),
+            load_lock: LOAD_LOCK.load(Ordering::Relaxed),
+            quantize: QUANTIZE.load(Ordering::Relaxed),
+        };
+        if saved.as_ref() != Some(&session) {
+            match sessions.save(&name, &session) {
+                Ok(()) => saved = Some(session),
+                Err(e) => eprintln!("Failed to save session: {}", e),
+            }
+        }
+    }
+}
 
-        let mut low_sum_l = 0.0f32;
-        let mut low_sum_r = 0.0f32;
-        let mut mid_sum_l = 0.0f32;
-        let mut mid_sum_r = 0.0f32;
-        let mut high_sum_l = 0.0f32;
-        let mut high_sum_r = 0.0f32;
-
-        let mut low_peak_l = 0.0f32;
-        let mut low_peak_r = 0.0f32;
-        let mut mid_peak_l = 0.0f32;
-        let mut mid_peak_r = 0.0f32;
-        let mut high_peak_l = 0.0f32;
-        let mut high_peak_r = 0.0f32;
-
-        // State variables for filters
-        let mut low_l = 0.0f32;
-        let mut low_r = 0.0f32;
-        let mut mid_low_l = 0.0f32;
-        let mut mid_low_r = 0.0f32;
-        let mut mid_high_l = 0.0f32;
-        let mut mid_high_r = 0.0f32;
-        let mut high_l = 0.0f32;
-        let mut high_r = 0.0f32;
-
-        // I should add SIMD for all this stuff. one day.
-        for &(left, right) in &song[chunk_start..chunk_end] {
-            // Low band (below 200Hz)
-            low_l = low_l * low_coeff + left * (1.0 - low_coeff);
-            low_r = low_r * low_coeff + right * (1.0 - low_coeff);
-
-            // Mid band (200Hz-3kHz): band-pass using two filters
-            mid_low_l = mid_low_l * mid_low_coeff + left * (1.0 - mid_low_coeff);
-            mid_low_r = mid_low_r * mid_low_coeff + right * (1.0 - mid_low_coeff);
-            mid_high_l = mid_high_l * mid_high_coeff + left * (1.0 - mid_high_coeff);
-            mid_high_r = mid_high_r * mid_high_coeff + right * (1.0 - mid_high_coeff);
-
-            let mid_l = mid_high_l - mid_low_l;
-            let mid_r = mid_high_r - mid_low_r;
-
-            // High band (above 3kHz)
-            high_l = left - (high_l * high_coeff + left * (1.0 - high_coeff));
-            high_r = right - (high_r * high_coeff + right * (1.0 - high_coeff));
-
-            // Accumulate RMS
-            low_sum_l += low_l * low_l;
-            low_sum_r += low_r * low_r;
-            mid_sum_l += mid_l * mid_l;
-            mid_sum_r += mid_r * mid_r;
-            high_sum_l += high_l * high_l;
-            high_sum_r += high_r * high_r;
-
-            // Track peaks
-            low_peak_l = low_peak_l.max(low_l.abs());
-            low_peak_r = low_peak_r.max(low_r.abs());
-            mid_peak_l = mid_peak_l.max(mid_l.abs());
-            mid_peak_r = mid_peak_r.max(mid_r.abs());
-            high_peak_l = high_peak_l.max(high_l.abs());
-            high_peak_r = high_peak_r.max(high_r.abs());
-        }
-
-        let n = (chunk_end - chunk_start) as f32;
-
-        waveform.push(WaveformBin {
-            low: FrequencyBand {
-                rms_left: (low_sum_l / n).sqrt(),
-                rms_right: (low_sum_r / n).sqrt(),
-                peak_left: low_peak_l,
-                peak_right: low_peak_r,
-            },
-            mid: FrequencyBand {
-                rms_left: (mid_sum_l / n).sqrt(),
-                rms_right: (mid_sum_r / n).sqrt(),
-                peak_left: mid_peak_l,
-                peak_right: mid_peak_r,
-            },
-            high: FrequencyBand {
-                rms_left: (high_sum_l / n).sqrt(),
-                rms_right: (high_sum_r / n).sqrt(),
-                peak_left: high_peak_l,
-                peak_right: high_peak_r,
-            },
-        });
+// The record on the turntable drives the deck: its speed all the time and
+// its needle position when that reads cleanly and the deck has drifted off
+// it, after a needle drop or a skip
+fn follow_timecode(deck: &mut Deck, queued: usize, sample_rate: f64) {
+    // Below this the record counts as stopped
+    const MIN_SPEED: f64 = 0.02;
+    const MAX_DRIFT_SECONDS: f64 = 0.1;
+
+    let speed = f64::from_bits(TIMECODE_SPEED.load(Ordering::Relaxed));
+    IS_PLAYING.store(speed.abs() > MIN_SPEED, Ordering::Relaxed);
+    deck.speed = speed;
+    let position = f64::from_bits(TIMECODE_POSITION.load(Ordering::Relaxed));
+    if position.is_finite() {
+        // The deck is ahead of the needle by what sits in the ring buffer
+        let target = (position * sample_rate + queued as f64 * speed).max(0.0);
+        if (target - deck.playhead() as f64).abs() > MAX_DRIFT_SECONDS * sample_rate {
+            deck.seek(target as u64);
+            PLAYHEAD.store(deck.playhead(), Ordering::Relaxed);
+        }
     }
+}
 
-    waveform
+// Tempo and beat at the playhead, the next frame to go into the ring
+// buffer. The process callback works out the transport position from
+// these as it plays those frames.
+fn anchor_beat(
+    anchors: &mut BeatAnchors,
+    current: &Option<(PathBuf, Vec<f64>, Vec<u64>)>,
+    speed: f64,
+    sample_rate: f64,
+) {
+    let beat_times = current.as_ref().map_or(&[][..], |(_, beats, _)| &beats[..]);
+    let time = PLAYHEAD.load(Ordering::Relaxed) as f64 / sample_rate;
+    anchors.set(
+        deck::beat_at(beat_times, time),
+        deck::tempo_at(beat_times, time),
+        speed,
+    );
 }
 
-fn run_heartbeat() -> Result<(), Box<dyn std::error::Error>> {
-    let nc = nats::connect("nats://localhost:4222")?;
+fn report_load_error(
+    path: &Path,
+    error: &LoadError,
+    meta_tx: &Sender<MetaCommand>,
+    publish_tx: &Sender<Outgoing>,
+) {
+    eprintln!("Failed to load {}: {}", path.display(), error);
+    let _ = meta_tx.try_send(MetaCommand::Error(format!(
+        "{}: {}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        error
+    )));
+    let payload = serde_json::json!({
+        "path": path.display().to_string(),
+        "error": error.to_string(),
+    });
+    let _ = publish_tx.send(Outgoing::event(
+        format!("anahata.{}.error", ANAHATA_NO.load(Ordering::Relaxed)),
+        payload.to_string(),
+    ));
+}
+
+fn announce_analysis(
+    path: &Path,
+    analysis: &Analysis,
+    meta_tx: &Sender<MetaCommand>,
+    publish_tx: &Sender<Outgoing>,
+) {
+    let _ = meta_tx.try_send(MetaCommand::Waveform(analysis.waveform.clone()));
+    let _ = meta_tx.try_send(MetaCommand::Detail(analysis.detail.clone()));
+    let _ = meta_tx.try_send(MetaCommand::BeatGrid(analysis.beat_times.clone()));
+    let _ = meta_tx.try_send(MetaCommand::Key(analysis.key));
+    let player_num = ANAHATA_NO.load(Ordering::Relaxed);
+    if let Some(key) = analysis.key {
+        let _ = publish_tx.send(Outgoing::state(
+            format!("anahata.{}.key", player_num),
+            format!("{},{}", key.camelot(), path.display()),
+        ));
+    }
+    let waveform = DeckWaveform::from_detail(
+        path.display().to_string(),
+        &analysis.detail,
+        PLAYBACK_SAMPLE_RATE,
+    );
+    let _ = publish_tx.send(Outgoing::state(
+        format!("anahata.{}.waveform", player_num),
+        waveform.to_bytes(),
+    ));
+    publish_beats(&analysis.beat_times, publish_tx);
+}
 
-    std::thread::spawn(move || loop {
-        std::thread::sleep(Duration::from_millis(500));
+fn publish_beats(beat_times: &[f64], publish_tx: &Sender<Outgoing>) {
+    if let Ok(payload) = serde_json::to_string(beat_times) {
+        let _ = publish_tx.send(Outgoing::state(
+            format!("anahata.{}.beats", ANAHATA_NO.load(Ordering::Relaxed)),
+            payload,
+        ));
+    }
+}
+
+// Blocks until NATS is reachable, then keeps reconnecting forever so the
+// deck survives the server going away mid-set.
+fn connect_nats() -> nats::Connection {
+    loop {
+        match nats::Options::new()
+            .retry_on_failed_connect()
+            .max_reconnects(None)
+            .connect("nats://localhost:4222")
+        {
+            Ok(nc) => return nc,
+            Err(e) => {
+                eprintln!("Failed to connect to NATS, retrying: {}", e);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn run_heartbeat() {
+    std::thread::spawn(move || {
+        let nc = connect_nats();
+        loop {
+            std::thread::sleep(Duration::from_millis(500));
 
-        let player_num = ANAHATA_NO.load(Ordering::Relaxed);
-        if player_num > 0 {
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
+            let player_num = ANAHATA_NO.load(Ordering::Relaxed);
+            if player_num > 0 {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
 
-            let heartbeat = format!("{}-{}", player_num, timestamp);
+                let heartbeat = format!("{}-{}", player_num, timestamp);
 
-            if let Err(e) = nc.publish("anahata.heartbeat", heartbeat.into_bytes()) {
-                eprintln!("Failed to publish heartbeat: {}", e);
+                if let Err(e) = nc.publish("anahata.heartbeat", heartbeat.into_bytes()) {
+                    eprintln!("Failed to publish heartbeat: {}", e);
+                }
             }
         }
     });
-
-    Ok(())
 }
 
 fn get_player_number() -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,239 @@
+//! Keeping the audio path real-time: scheduling and memory locking for the
+//! thread that feeds the JACK callback, and in debug builds a check that the
+//! callback itself never touches the allocator or waits on a lock.
+//!
+//! Locks are checked through `CheckedMutex`, so anything the callback can
+//! reach takes that rather than `std::sync::Mutex`.
+
+use std::io;
+use std::sync::{Arc, LockResult, MutexGuard, TryLockResult};
+
+/// Moves the calling thread to SCHED_FIFO at `priority`. Needs rtprio in
+/// the user's limits (the audio group usually has it) or CAP_SYS_NICE.
+pub fn promote_current_thread(priority: i32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: pthread_self is always a valid handle for the calling thread
+    let result =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(result))
+    }
+}
+
+/// Keeps a track's pages resident until dropped, so playing into a part of
+/// it that hasn't been touched yet can't fault. Bounded by RLIMIT_MEMLOCK,
+/// which is 8 MiB unless raised.
+pub struct MemoryLock<T> {
+    // Holding on to the buffer means it can't be freed while locked
+    data: Arc<Vec<T>>,
+}
+
+impl<T> MemoryLock<T> {
+    pub fn new(data: Arc<Vec<T>>) -> io::Result<Self> {
+        // SAFETY: the range is a live allocation, mlock only pins its pages
+        let result = unsafe {
+            libc::mlock(
+                data.as_ptr() as *const libc::c_void,
+                std::mem::size_of_val(&data[..]),
+            )
+        };
+        if result == 0 {
+            Ok(Self { data })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl<T> Drop for MemoryLock<T> {
+    fn drop(&mut self) {
+        // SAFETY: same range as the successful mlock
+        unsafe {
+            libc::munlock(
+                self.data.as_ptr() as *const libc::c_void,
+                std::mem::size_of_val(&self.data[..]),
+            );
+        }
+    }
+}
+
+/// Allocations made inside the JACK callback since start. Always 0 in
+/// release builds, which don't check.
+pub fn callback_allocations() -> u64 {
+    #[cfg(debug_assertions)]
+    {
+        check::ALLOCATIONS.load(std::sync::atomic::Ordering::Relaxed)
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        0
+    }
+}
+
+/// Blocking locks taken inside the JACK callback since start. Always 0 in
+/// release builds, which don't check.
+pub fn callback_locks() -> u64 {
+    #[cfg(debug_assertions)]
+    {
+        check::LOCKS.load(std::sync::atomic::Ordering::Relaxed)
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        0
+    }
+}
+
+/// A `Mutex` that counts blocking locks taken inside the JACK callback in
+/// debug builds. `try_lock` doesn't wait and is what the callback should
+/// use.
+// Nothing the callback reaches needs a lock yet
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct CheckedMutex<T>(std::sync::Mutex<T>);
+
+#[allow(dead_code)]
+impl<T> CheckedMutex<T> {
+    pub fn new(value: T) -> Self {
+        Self(std::sync::Mutex::new(value))
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        #[cfg(debug_assertions)]
+        check::note_lock();
+        self.0.lock()
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        self.0.try_lock()
+    }
+}
+
+/// Marks the current thread as inside the JACK callback until dropped.
+pub struct CallbackGuard;
+
+impl CallbackGuard {
+    pub fn enter() -> Self {
+        #[cfg(debug_assertions)]
+        check::set_in_callback(true);
+        CallbackGuard
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        check::set_in_callback(false);
+    }
+}
+
+#[cfg(debug_assertions)]
+mod check {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+    pub static LOCKS: AtomicU64 = AtomicU64::new(0);
+
+    thread_local! {
+        static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub fn set_in_callback(value: bool) {
+        let _ = IN_CALLBACK.try_with(|flag| flag.set(value));
+    }
+
+    fn in_callback() -> bool {
+        IN_CALLBACK.try_with(Cell::get).unwrap_or(false)
+    }
+
+    // Can't print or panic from in here, both allocate. The health thread
+    // reports the count instead.
+    fn note() {
+        if in_callback() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn note_lock() {
+        if in_callback() {
+            LOCKS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The system allocator, counting calls made from the JACK callback.
+    /// Freeing counts too, dropping the last Arc of a track in there would
+    /// stall the cycle just the same.
+    pub struct CheckedAlloc;
+
+    unsafe impl GlobalAlloc for CheckedAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            note();
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            note();
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            note();
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            note();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CheckedAlloc = CheckedAlloc;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Other tests allocate on their own threads, the flag is per thread
+    #[cfg(debug_assertions)]
+    #[test]
+    fn counts_allocations_inside_the_callback_only() {
+        let before = callback_allocations();
+        drop(std::hint::black_box(vec![0u8; 64]));
+        assert_eq!(before, callback_allocations());
+
+        {
+            let _guard = CallbackGuard::enter();
+            drop(std::hint::black_box(vec![0u8; 64]));
+        }
+        // The allocation and the free
+        assert_eq!(before + 2, callback_allocations());
+
+        drop(std::hint::black_box(vec![0u8; 64]));
+        assert_eq!(before + 2, callback_allocations(), "the guard is dropped");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn counts_blocking_locks_inside_the_callback_only() {
+        let mutex = CheckedMutex::new(0);
+        let before = callback_locks();
+        *mutex.lock().unwrap() += 1;
+        assert_eq!(before, callback_locks());
+
+        {
+            let _guard = CallbackGuard::enter();
+            *mutex.try_lock().unwrap() += 1;
+            assert_eq!(before, callback_locks(), "try_lock doesn't wait");
+            *mutex.lock().unwrap() += 1;
+        }
+        assert_eq!(before + 1, callback_locks());
+        assert_eq!(3, *mutex.lock().unwrap());
+    }
+}
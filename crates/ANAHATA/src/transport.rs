@@ -0,0 +1,309 @@
+//! JACK transport. A deck can be the timebase master, giving the graph its
+//! tempo and bar/beat from the beatgrid and rolling the transport as it
+//! plays, or follow the transport starting and stopping.
+
+use crate::globals::*;
+use jack::{Client, Transport, TransportState};
+use jack_sys as j;
+use my_common::deck::{self, BarBeat, BEATS_PER_BAR, TICKS_PER_BEAT};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::Ordering;
+
+// Anchors waiting for the callback to play up to them, a few per beat at
+// most while playing through a grid
+const PENDING_ANCHORS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMode {
+    Off,
+    Master,
+    Follow,
+}
+
+impl TransportMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Some(TransportMode::Off),
+            "master" => Some(TransportMode::Master),
+            "follow" => Some(TransportMode::Follow),
+            _ => None,
+        }
+    }
+}
+
+/// Start and stop in either direction, run from the process callback. Only
+/// changes are passed on, so the deck's own play button keeps working.
+pub struct TransportSync {
+    mode: TransportMode,
+    transport: Transport,
+    rolling: bool,
+}
+
+impl TransportSync {
+    pub fn new(client: &Client, mode: TransportMode) -> Self {
+        Self {
+            mode,
+            transport: client.transport(),
+            rolling: false,
+        }
+    }
+
+    pub fn cycle(&mut self) {
+        match self.mode {
+            TransportMode::Off => {}
+            TransportMode::Master => {
+                let playing = IS_PLAYING.load(Ordering::Relaxed);
+                if playing != self.rolling {
+                    let _ = if playing {
+                        self.transport.start()
+                    } else {
+                        self.transport.stop()
+                    };
+                    self.rolling = playing;
+                }
+            }
+            TransportMode::Follow => {
+                let rolling = matches!(self.transport.query_state(), Ok(TransportState::Rolling));
+                if rolling != self.rolling {
+                    IS_PLAYING.store(rolling, Ordering::Relaxed);
+                    self.rolling = rolling;
+                }
+            }
+        }
+    }
+
+    /// Frames from the start of this cycle to the next beat of the
+    /// timebase master, for quantized starts. That is TALA when it follows
+    /// another machine, so decks land on the beat shared over the network.
+    /// `None` when this deck is the master or nobody publishes a rolling
+    /// bar/beat position.
+    pub fn frames_to_master_beat(&self) -> Option<usize> {
+        if self.mode == TransportMode::Master {
+            return None;
+        }
+        let status = self.transport.query().ok()?;
+        if status.state != TransportState::Rolling {
+            return None;
+        }
+        let bbt = status.pos.bbt()?;
+        let frame_rate = status.pos.frame_rate()? as f64;
+        if bbt.bpm <= 0.0 || bbt.ticks_per_beat <= 0.0 {
+            return None;
+        }
+        let beat = bbt.tick as f64 / bbt.ticks_per_beat;
+        Some(deck::frames_to_next_beat(beat, bbt.bpm, frame_rate))
+    }
+}
+
+/// The beat at one frame of the stream the playback thread writes to the
+/// ring buffer, and how far it moves per frame from there. NaN without a
+/// beatgrid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeatAnchor {
+    frame: u64,
+    beat: f64,
+    beats_per_frame: f64,
+    bpm: f64,
+}
+
+impl BeatAnchor {
+    fn beat_at(&self, frame: u64) -> f64 {
+        self.beat + (frame as f64 - self.frame as f64) * self.beats_per_frame
+    }
+
+    // Close enough to where `previous` puts it that it needn't be sent
+    fn continues(&self, previous: &BeatAnchor) -> bool {
+        if self.beat.is_nan() && previous.beat.is_nan() {
+            return true;
+        }
+        (previous.beat_at(self.frame) - self.beat).abs() < 1e-6
+            && previous.beats_per_frame == self.beats_per_frame
+            && previous.bpm == self.bpm
+    }
+}
+
+/// The playback thread's end: counts what it writes to the ring buffer and
+/// sends a new anchor when the beat stops following the last one, after a
+/// seek or at a change of tempo.
+pub struct BeatAnchors {
+    producer: Producer<BeatAnchor>,
+    sample_rate: f64,
+    written: u64,
+    last: Option<BeatAnchor>,
+}
+
+impl BeatAnchors {
+    pub fn wrote(&mut self, frames: usize) {
+        self.written += frames as u64;
+    }
+
+    /// `beat` and `bpm` at the next frame to be written, played at `speed`.
+    pub fn set(&mut self, beat: Option<f64>, bpm: Option<f64>, speed: f64) {
+        let (beat, bpm) = beat.zip(bpm).unwrap_or((f64::NAN, f64::NAN));
+        let anchor = BeatAnchor {
+            frame: self.written,
+            beat,
+            beats_per_frame: bpm / 60.0 / self.sample_rate * speed,
+            bpm,
+        };
+        if self.last.is_some_and(|last| anchor.continues(&last)) {
+            return;
+        }
+        // With the queue full the callback keeps the last line a bit longer
+        if self.producer.push(anchor).is_ok() {
+            self.last = Some(anchor);
+        }
+    }
+}
+
+/// The process callback's end: follows the anchors as it plays up to them
+/// and publishes the tempo and beat at the next frame it will play, for
+/// the timebase callback JACK runs right after it in the same thread.
+pub struct BeatClock {
+    anchors: Consumer<BeatAnchor>,
+    current: Option<BeatAnchor>,
+    played: u64,
+}
+
+impl BeatClock {
+    /// `frames` more were taken off the ring buffer this cycle.
+    pub fn advance(&mut self, frames: usize) {
+        self.played += frames as u64;
+        while self
+            .anchors
+            .peek()
+            .is_ok_and(|anchor| anchor.frame <= self.played)
+        {
+            self.current = self.anchors.pop().ok();
+        }
+        let (bpm, beat) = self.current.map_or((f64::NAN, f64::NAN), |anchor| {
+            (anchor.bpm, anchor.beat_at(self.played))
+        });
+        TEMPO_BPM.store(bpm.to_bits(), Ordering::Relaxed);
+        BEAT_POSITION.store(beat.to_bits(), Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    fn beat(&self) -> Option<f64> {
+        self.current.map(|anchor| anchor.beat_at(self.played))
+    }
+}
+
+pub fn beat_clock(sample_rate: f64) -> (BeatAnchors, BeatClock) {
+    let (producer, anchors) = RingBuffer::new(PENDING_ANCHORS);
+    (
+        BeatAnchors {
+            producer,
+            sample_rate,
+            written: 0,
+            last: None,
+        },
+        BeatClock {
+            anchors,
+            current: None,
+            played: 0,
+        },
+    )
+}
+
+/// Registers the timebase callback, failing if another client already is
+/// the master rather than taking over from it.
+pub fn become_timebase_master(client: &Client) -> Result<(), String> {
+    // SAFETY: the client outlives the registration, `release_timebase` is
+    // called before it is deactivated
+    let result = unsafe {
+        j::jack_set_timebase_callback(client.raw(), 1, Some(timebase), std::ptr::null_mut())
+    };
+    match result {
+        0 => Ok(()),
+        libc::EBUSY => Err(String::from("another client is timebase master")),
+        e => Err(format!("error {}", e)),
+    }
+}
+
+pub fn release_timebase(client: &Client) {
+    // SAFETY: as above, the client is still alive
+    unsafe {
+        j::jack_release_timebase(client.raw());
+    }
+}
+
+// Called by JACK in the process thread, so atomics only
+unsafe extern "C" fn timebase(
+    _state: j::jack_transport_state_t,
+    _nframes: j::jack_nframes_t,
+    pos: *mut j::jack_position_t,
+    _new_pos: libc::c_int,
+    _arg: *mut libc::c_void,
+) {
+    // SAFETY: JACK passes the position it is about to publish
+    let pos = unsafe { &mut *pos };
+    let bpm = f64::from_bits(TEMPO_BPM.load(Ordering::Relaxed));
+    let beat = f64::from_bits(BEAT_POSITION.load(Ordering::Relaxed));
+    match BarBeat::from_beat(beat).filter(|_| bpm.is_finite()) {
+        Some(position) => {
+            pos.valid |= j::JackPositionBBT;
+            pos.bar = position.bar as i32;
+            pos.beat = position.beat as i32;
+            pos.tick = position.tick as i32;
+            pos.bar_start_tick = ((position.bar - 1) * BEATS_PER_BAR * TICKS_PER_BEAT) as f64;
+            pos.beats_per_bar = BEATS_PER_BAR as f32;
+            pos.beat_type = 4.0;
+            pos.ticks_per_beat = TICKS_PER_BEAT as f64;
+            pos.beats_per_minute = bpm;
+        }
+        // No beatgrid, or before its first beat
+        None => pos.valid &= !j::JackPositionBBT,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_beat(expected: f64, clock: &BeatClock) {
+        let beat = clock.beat().unwrap();
+        assert!((beat - expected).abs() < 1e-9, "{} != {}", beat, expected);
+    }
+
+    #[test]
+    fn the_beat_follows_what_was_played() {
+        let (mut anchors, mut clock) = beat_clock(1000.0);
+        // 120 bpm, two beats a second, from beat 4
+        anchors.set(Some(4.0), Some(120.0), 1.0);
+        anchors.wrote(500);
+        anchors.set(Some(5.0), Some(120.0), 1.0);
+        anchors.wrote(250);
+        // Seeked back to beat 0, the ring still holds what came before
+        anchors.set(Some(0.0), Some(120.0), 1.0);
+        anchors.wrote(500);
+
+        clock.advance(0);
+        assert_beat(4.0, &clock);
+        // Past the second anchor, which was on the first one's line
+        clock.advance(600);
+        assert_beat(5.2, &clock);
+        // Up to the seek
+        clock.advance(150);
+        assert_beat(0.0, &clock);
+        clock.advance(250);
+        assert_beat(0.5, &clock);
+    }
+
+    #[test]
+    fn only_sends_anchors_off_the_line() {
+        let (mut anchors, mut clock) = beat_clock(1000.0);
+        anchors.set(Some(0.0), Some(60.0), 1.0);
+        for _ in 0..(2 * PENDING_ANCHORS) {
+            anchors.wrote(100);
+            anchors.set(Some(anchors.written as f64 / 1000.0), Some(60.0), 1.0);
+        }
+        assert_eq!(PENDING_ANCHORS, anchors.producer.slots() + 1);
+
+        // Half speed moves half as many beats per frame
+        anchors.set(Some(12.8), Some(60.0), 0.5);
+        anchors.wrote(1000);
+        clock.advance(12800 + 1000);
+        assert_beat(13.3, &clock);
+    }
+}
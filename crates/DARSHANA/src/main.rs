@@ -0,0 +1,305 @@
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use eframe::egui;
+use my_common::deck::{self, DeckWaveform};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LANE_HEIGHT: f32 = 160.0;
+const ZOOM_FACTOR: f32 = 1.25;
+const MIN_PIXELS_PER_SECOND: f32 = 10.0;
+const MAX_PIXELS_PER_SECOND: f32 = 1000.0;
+// Decks send a heartbeat every 500ms
+const DECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum DeckUpdate {
+    Waveform(u32, DeckWaveform),
+    Beats(u32, Vec<f64>),
+    Position(u32, u64, bool, f64),
+    Heartbeat(u32),
+    /// Why there is no NATS to hear decks on, `None` once there is
+    Nats(Option<String>),
+}
+
+struct Deck {
+    waveform: Option<DeckWaveform>,
+    beat_times: Vec<f64>,
+    playhead: u64,
+    playing: bool,
+    // Not 1.0 while a timecode record drives the deck
+    speed: f64,
+    position_received: Instant,
+    last_seen: Instant,
+}
+
+impl Deck {
+    fn new() -> Self {
+        Self {
+            waveform: None,
+            beat_times: Vec::new(),
+            playhead: 0,
+            playing: false,
+            speed: 1.0,
+            position_received: Instant::now(),
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.waveform
+            .as_ref()
+            .map_or(48000.0, |waveform| waveform.sample_rate as f64)
+    }
+
+    /// Position in seconds, moved on at the deck's speed by the time since
+    /// the last update while playing, so the lanes scroll smoothly between
+    /// messages.
+    fn position(&self) -> f64 {
+        let position = self.playhead as f64 / self.sample_rate();
+        if self.playing {
+            position + self.position_received.elapsed().as_secs_f64() * self.speed
+        } else {
+            position
+        }
+    }
+}
+
+struct DarshanaApp {
+    decks: BTreeMap<u32, Deck>,
+    pixels_per_second: f32,
+    update_rx: Receiver<DeckUpdate>,
+    nats_error: Option<String>,
+}
+
+impl DarshanaApp {
+    fn new(update_rx: Receiver<DeckUpdate>) -> Self {
+        Self {
+            decks: BTreeMap::new(),
+            pixels_per_second: 100.0,
+            update_rx,
+            nats_error: None,
+        }
+    }
+
+    fn deck(&mut self, number: u32) -> &mut Deck {
+        let deck = self.decks.entry(number).or_insert_with(Deck::new);
+        deck.last_seen = Instant::now();
+        deck
+    }
+
+    fn draw_lane(ui: &mut egui::Ui, number: u32, deck: &Deck, pixels_per_second: f32) {
+        let (rect, _) = ui.allocate_exact_size(
+            egui::vec2(ui.available_width(), LANE_HEIGHT),
+            egui::Sense::hover(),
+        );
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 0.0, egui::Color32::from_gray(16));
+
+        // Every lane shares the same playhead x, so beats line up when in sync
+        let playhead_x = rect.center().x;
+        let position = deck.position();
+        let pixels_per_second = pixels_per_second as f64;
+        let time_at = |x: f32| position + (x - playhead_x) as f64 / pixels_per_second;
+        let x_at = |t: f64| playhead_x + ((t - position) * pixels_per_second) as f32;
+
+        if let Some(waveform) = &deck.waveform {
+            let bin_seconds = waveform.samples_per_bin as f64 / waveform.sample_rate as f64;
+            let center_y = rect.center().y;
+            let half_height = rect.height() * 0.5;
+            let mut x = rect.left().floor();
+            while x < rect.right() {
+                let t = time_at(x);
+                if let Some(bands) = (t >= 0.0)
+                    .then(|| waveform.bands.get((t / bin_seconds) as usize))
+                    .flatten()
+                {
+                    // Low drawn first and widest so mids and highs stay visible on top
+                    for (value, scale, color) in [
+                        (bands[0], 1.0, egui::Color32::from_rgb(30, 90, 220)),
+                        (bands[1], 0.8, egui::Color32::from_rgb(230, 140, 40)),
+                        (bands[2], 0.5, egui::Color32::from_rgb(235, 235, 235)),
+                    ] {
+                        let extent = value as f32 / 255.0 * half_height * scale;
+                        painter.line_segment(
+                            [
+                                egui::pos2(x, center_y - extent),
+                                egui::pos2(x, center_y + extent),
+                            ],
+                            egui::Stroke::new(1.0, color),
+                        );
+                    }
+                }
+                x += 1.0;
+            }
+        }
+
+        // Beat and bar ticks, assuming the first beat is a downbeat
+        let (start, end) = (time_at(rect.left()), time_at(rect.right()));
+        let first_visible = deck.beat_times.partition_point(|&beat| beat < start);
+        for (i, &beat) in deck.beat_times.iter().enumerate().skip(first_visible) {
+            if beat > end {
+                break;
+            }
+            let x = x_at(beat);
+            let (width, alpha) = if i % 4 == 0 { (1.5, 160) } else { (0.5, 70) };
+            painter.line_segment(
+                [egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())],
+                egui::Stroke::new(width, egui::Color32::from_white_alpha(alpha)),
+            );
+        }
+
+        painter.line_segment(
+            [
+                egui::pos2(playhead_x, rect.top()),
+                egui::pos2(playhead_x, rect.bottom()),
+            ],
+            egui::Stroke::new(2.0, egui::Color32::RED),
+        );
+
+        let title = deck
+            .waveform
+            .as_ref()
+            .and_then(|waveform| {
+                std::path::Path::new(&waveform.path)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        painter.text(
+            rect.left_top() + egui::vec2(4.0, 2.0),
+            egui::Align2::LEFT_TOP,
+            format!("ANAHATA-{}  {}", number, title),
+            egui::FontId::monospace(12.0),
+            egui::Color32::WHITE,
+        );
+    }
+}
+
+impl eframe::App for DarshanaApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        while let Ok(update) = self.update_rx.try_recv() {
+            match update {
+                DeckUpdate::Waveform(number, waveform) => {
+                    self.deck(number).waveform = Some(waveform)
+                }
+                DeckUpdate::Beats(number, beat_times) => self.deck(number).beat_times = beat_times,
+                DeckUpdate::Position(number, playhead, playing, speed) => {
+                    let deck = self.deck(number);
+                    deck.playhead = playhead;
+                    deck.playing = playing;
+                    deck.speed = speed;
+                    deck.position_received = Instant::now();
+                }
+                DeckUpdate::Heartbeat(number) => {
+                    self.deck(number);
+                }
+                DeckUpdate::Nats(error) => self.nats_error = error,
+            }
+        }
+        self.decks
+            .retain(|_, deck| deck.last_seen.elapsed() < DECK_TIMEOUT);
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let scroll = ui.input(|i| i.raw_scroll_delta.y) / 50.0;
+            if scroll != 0.0 {
+                self.pixels_per_second = (self.pixels_per_second * ZOOM_FACTOR.powf(scroll))
+                    .clamp(MIN_PIXELS_PER_SECOND, MAX_PIXELS_PER_SECOND);
+            }
+
+            if let Some(error) = &self.nats_error {
+                ui.colored_label(egui::Color32::RED, error);
+            } else if self.decks.is_empty() {
+                ui.label("Waiting for decks...");
+            }
+            for (&number, deck) in &self.decks {
+                Self::draw_lane(ui, number, deck, self.pixels_per_second);
+            }
+        });
+
+        ctx.request_repaint();
+    }
+}
+
+fn connect_nats(update_tx: &Sender<DeckUpdate>) -> nats::Connection {
+    loop {
+        match nats::Options::new()
+            .retry_on_failed_connect()
+            .max_reconnects(None)
+            .connect("nats://localhost:4222")
+        {
+            Ok(nc) => return nc,
+            Err(e) => {
+                eprintln!("Failed to connect to NATS, retrying: {}", e);
+                let _ = update_tx.send(DeckUpdate::Nats(Some(format!("No NATS: {}", e))));
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn nats_thread(update_tx: Sender<DeckUpdate>) {
+    let (nc, sub) = loop {
+        let nc = connect_nats(&update_tx);
+        match nc.subscribe("anahata.>") {
+            Ok(sub) => break (nc, sub),
+            Err(e) => {
+                eprintln!("Failed to subscribe to decks, retrying: {}", e);
+                let _ = update_tx.send(DeckUpdate::Nats(Some(format!("Can't hear decks: {}", e))));
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    };
+    let _ = update_tx.send(DeckUpdate::Nats(None));
+    // Decks replay their waveform and beats for late joiners
+    let _ = nc.publish("anahata.refresh", "");
+
+    for message in sub.messages() {
+        let payload = String::from_utf8_lossy(&message.data);
+        if message.subject == "anahata.heartbeat" {
+            if let Some(number) = payload
+                .split_once('-')
+                .and_then(|(number, _)| number.parse().ok())
+            {
+                let _ = update_tx.send(DeckUpdate::Heartbeat(number));
+            }
+            continue;
+        }
+
+        let mut parts = message.subject.split('.').skip(1);
+        let (Some(number), Some(kind), None) = (
+            parts.next().and_then(|n| n.parse::<u32>().ok()),
+            parts.next(),
+            parts.next(),
+        ) else {
+            continue;
+        };
+        let update = match kind {
+            "waveform" => DeckWaveform::from_bytes(&message.data)
+                .map(|waveform| DeckUpdate::Waveform(number, waveform)),
+            "beats" => serde_json::from_str(&payload)
+                .ok()
+                .map(|beat_times| DeckUpdate::Beats(number, beat_times)),
+            "position" => deck::parse_position(&payload).map(|(playhead, playing, speed)| {
+                DeckUpdate::Position(number, playhead, playing, speed)
+            }),
+            _ => None,
+        };
+        if let Some(update) = update {
+            if update_tx.send(update).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn main() {
+    let (update_tx, update_rx) = unbounded();
+    thread::spawn(move || nats_thread(update_tx));
+
+    let native_options = eframe::NativeOptions::default();
+    let _ = eframe::run_native(
+        "DARSHANA",
+        native_options,
+        Box::new(|_cc| Ok(Box::new(DarshanaApp::new(update_rx)))),
+    );
+}
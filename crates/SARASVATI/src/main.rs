@@ -3,6 +3,7 @@ use nats;
 mod xonek2;
 use eframe::egui;
 use jack::{Client, PortFlags};
+use my_common::deck::Jump;
 use std::{error::Error, vec};
 use xonek2::*;
 
@@ -33,9 +34,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     let (nats_tx, nats_rx) = bounded(100);
     let (xone_tx, xone_rx) = bounded(100);
 
+    let jumps = JumpPayloads::from_args();
+
     // Spawn NATS thread
     std::thread::spawn(move || {
-        if let Err(e) = run_nats(xone_tx, nats_rx) {
+        if let Err(e) = run_nats(xone_tx, nats_rx, jumps) {
             eprintln!("NATS error: {}", e);
         }
     });
@@ -87,9 +90,37 @@ fn list_midi_ports_jack() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// What the skip buttons send, e.g. `SARASVATI --skip 4b --nudge 20smp`.
+/// Empty leaves it to the deck's own default.
+struct JumpPayloads {
+    skip: String,
+    nudge: String,
+}
+
+impl JumpPayloads {
+    fn from_args() -> Self {
+        let arg = |name: &str| {
+            let value = std::env::args().skip_while(|arg| arg != name).nth(1)?;
+            if Jump::parse(&value).is_none() {
+                eprintln!(
+                    "Ignoring {} {}, expected e.g. 5s, 4b or 480smp",
+                    name, value
+                );
+                return None;
+            }
+            Some(value)
+        };
+        Self {
+            skip: arg("--skip").unwrap_or_default(),
+            nudge: arg("--nudge").unwrap_or_default(),
+        }
+    }
+}
+
 fn run_nats(
     nats_tx: Sender<XoneMessage>,
     nats_rx: Receiver<XoneMessage>,
+    jumps: JumpPayloads,
 ) -> Result<(), Box<dyn Error>> {
     let nc = nats::connect("nats://localhost:4222")?;
 
@@ -100,9 +131,25 @@ fn run_nats(
             }
             XoneMessage::Encoder { id, direction } => {
                 println!("ENCODER {}", id);
+                // Outer top encoders zoom the deck waveforms
+                let zoom = match direction {
+                    EncoderDirection::Clockwise => "zoomin",
+                    EncoderDirection::CounterClockwise => "zoomout",
+                };
                 if id == 14 {
                     let _ = nc.publish("akasha.select", format!("{:?}", direction));
                 } else {
+                    // The zoom encoders still go out raw for anything else
+                    // listening to them
+                    match id {
+                        0 => {
+                            let _ = nc.publish(&format!("anahata.1.{}", zoom), "na");
+                        }
+                        3 => {
+                            let _ = nc.publish(&format!("anahata.2.{}", zoom), "na");
+                        }
+                        _ => {}
+                    }
                     let _ = nc.publish("xone.encoder", format!("{},{:?}", id, direction));
                 }
             }
@@ -117,26 +164,24 @@ fn run_nats(
                 // These numbers are silly, at this stage I should not care about midi
                 // crap.
                 match id {
-                    30 => {
-                        if pressed {
-                            nc.publish("anahata.2.skipbackward", "na");
-                        }
-                    }
-
-                    26 => {
-                        if pressed {
-                            nc.publish("anahata.2.skipforward", "na");
-                        }
-                    }
-                    29 => {
+                    25 | 26 | 29 | 30 => {
                         if pressed {
-                            nc.publish("anahata.1.skipbackward", "na");
-                        }
-                    }
-
-                    25 => {
-                        if pressed {
-                            nc.publish("anahata.1.skipforward", "na");
+                            let deck = if id == 25 || id == 29 { 1 } else { 2 };
+                            let direction = if id == 25 || id == 26 {
+                                "forward"
+                            } else {
+                                "backward"
+                            };
+                            // Shift turns the skip buttons into nudges
+                            let (kind, payload) = if main_shift != Shift::Off {
+                                ("nudge", &jumps.nudge)
+                            } else {
+                                ("skip", &jumps.skip)
+                            };
+                            let _ = nc.publish(
+                                &format!("anahata.{}.{}{}", deck, kind, direction),
+                                payload,
+                            );
                         }
                     }
                     41 => {
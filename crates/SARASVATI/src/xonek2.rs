@@ -1,6 +1,7 @@
 use std::{error::Error, thread::sleep, time::Duration};
 
 use crossbeam_channel::{Receiver, Sender};
+use my_common::connections::{self, Rules};
 use jack::{
     Client, ClientOptions, Control, MidiIn, MidiOut, MidiWriter, Port, ProcessHandler, ProcessScope,
 };
@@ -86,17 +87,6 @@ impl XoneK2 {
         let midi_in = client.register_port("midi_in", MidiIn::default())?;
         let midi_out = client.register_port("midi_out", MidiOut::default())?;
 
-        // Connect the ports first
-        // TODO: Just fuzzy match on XONE:K2 capture/playback
-        client.connect_ports_by_name(
-            "Midi-Bridge:XONE:K2 5:(capture_0) XONE:K2 MIDI 1",
-            "XoneK2-midi:midi_in",
-        )?;
-        client.connect_ports_by_name(
-            "XoneK2-midi:midi_out",
-            "Midi-Bridge:XONE:K2 5:(playback_0) XONE:K2 MIDI 1",
-        )?;
-
         let xone = Self {
             shift: Shift::Off,
             play1: false,
@@ -113,7 +103,11 @@ impl XoneK2 {
             midi_in,
             midi_out,
         };
-        let _active_client = client.activate_async((), xone)?;
+        let active_client = client.activate_async((), xone)?;
+        // The controller's ports are found by the connection rules, also
+        // when it's plugged in after we started
+        let owner = active_client.as_client().name().to_string();
+        connections::spawn(owner, Rules::load_default())?;
         std::thread::park();
         Ok(())
     }
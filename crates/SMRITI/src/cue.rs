@@ -0,0 +1,131 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A track that started playing while recording.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Frames into the recording
+    pub offset: u64,
+    pub deck: u32,
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+impl Entry {
+    fn title(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            Path::new(&self.path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.path.clone())
+        })
+    }
+}
+
+/// `mm:ss:ff` with 75 CD frames per second, minutes may go past 99.
+pub fn cue_time(offset: u64, sample_rate: u32) -> String {
+    let cd_frames = offset * 75 / sample_rate as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        cd_frames / (75 * 60),
+        cd_frames / 75 % 60,
+        cd_frames % 75
+    )
+}
+
+fn quoted(s: &str) -> String {
+    s.replace('"', "'")
+}
+
+pub fn cue_sheet(audio_file: &str, entries: &[Entry], sample_rate: u32) -> String {
+    // Players take FLAC under WAVE too, the format has no other type for it
+    let mut sheet = format!("FILE \"{}\" WAVE\n", quoted(audio_file));
+    for (i, entry) in entries.iter().enumerate() {
+        let _ = writeln!(sheet, "  TRACK {:02} AUDIO", i + 1);
+        let _ = writeln!(sheet, "    TITLE \"{}\"", quoted(&entry.title()));
+        if let Some(artist) = &entry.artist {
+            let _ = writeln!(sheet, "    PERFORMER \"{}\"", quoted(artist));
+        }
+        let _ = writeln!(sheet, "    REM DECK {}", entry.deck);
+        let _ = writeln!(
+            sheet,
+            "    INDEX 01 {}",
+            cue_time(entry.offset, sample_rate)
+        );
+    }
+    sheet
+}
+
+/// One line per track: `hh:mm:ss  Artist - Title`.
+pub fn tracklist(entries: &[Entry], sample_rate: u32) -> String {
+    let mut list = String::new();
+    for entry in entries {
+        let seconds = entry.offset / sample_rate as u64;
+        let _ = write!(
+            list,
+            "{:02}:{:02}:{:02}  ",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        );
+        if let Some(artist) = &entry.artist {
+            let _ = write!(list, "{} - ", artist);
+        }
+        let _ = writeln!(list, "{}", entry.title());
+    }
+    list
+}
+
+/// Writes next to the file and renames over it, so a crash leaves either the
+/// old or the new list and never half of one.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sheet_and_tracklist() {
+        let entries = [
+            Entry {
+                offset: 0,
+                deck: 1,
+                path: "/music/Intro.flac".into(),
+                title: None,
+                artist: None,
+            },
+            Entry {
+                offset: 48000 * 3723 + 24000,
+                deck: 2,
+                path: "/music/b.flac".into(),
+                title: Some("Say \"Yes\"".into()),
+                artist: Some("Someone".into()),
+            },
+        ];
+        assert_eq!("62:03:37", cue_time(48000 * 3723 + 24000, 48000));
+        assert_eq!(
+            "FILE \"set.flac\" WAVE\n\
+             \x20 TRACK 01 AUDIO\n\
+             \x20   TITLE \"Intro\"\n\
+             \x20   REM DECK 1\n\
+             \x20   INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n\
+             \x20   TITLE \"Say 'Yes'\"\n\
+             \x20   PERFORMER \"Someone\"\n\
+             \x20   REM DECK 2\n\
+             \x20   INDEX 01 62:03:37\n",
+            cue_sheet("set.flac", &entries, 48000)
+        );
+        assert_eq!(
+            "00:00:00  Intro\n01:02:03  Someone - Say \"Yes\"\n",
+            tracklist(&entries, 48000)
+        );
+    }
+}
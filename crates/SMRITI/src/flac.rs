@@ -0,0 +1,423 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+// A small FLAC encoder: fixed predictors and a single Rice partition per
+// subframe. Worse compression than libFLAC, but every frame stands on its
+// own, so a file cut short by a crash still plays up to the last frame.
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 24;
+const MAX_FIXED_ORDER: usize = 4;
+
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    left: Vec<i32>,
+    right: Vec<i32>,
+    frame_number: u64,
+    total_samples: u64,
+    frame_sizes: Option<(usize, usize)>,
+    finished: bool,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(b"fLaC")?;
+        // Last metadata block, STREAMINFO, 34 bytes
+        out.write_all(&[0x80, 0, 0, 34])?;
+        out.write_all(&streaminfo(sample_rate, 0, None))?;
+        Ok(Self {
+            out,
+            sample_rate,
+            left: Vec::with_capacity(BLOCK_SIZE),
+            right: Vec::with_capacity(BLOCK_SIZE),
+            frame_number: 0,
+            total_samples: 0,
+            frame_sizes: None,
+            finished: false,
+        })
+    }
+
+    /// Takes 24 bit stereo frames.
+    pub fn write(&mut self, frames: &[(i32, i32)]) -> io::Result<()> {
+        for &(left, right) in frames {
+            self.left.push(left);
+            self.right.push(right);
+            if self.left.len() == BLOCK_SIZE {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes every complete frame. Samples short of a full block stay
+    /// buffered until the next write or `finish`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Writes the last partial block and fills in the sample count, which
+    /// stays zero (unknown) if this never runs.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if !self.left.is_empty() {
+            self.write_frame()?;
+        }
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(8))?;
+        self.out.write_all(&streaminfo(
+            self.sample_rate,
+            self.total_samples,
+            self.frame_sizes,
+        ))?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let frame = encode_frame(
+            self.frame_number,
+            self.sample_rate,
+            [&self.left, &self.right],
+        );
+        self.out.write_all(&frame)?;
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(frame.len()), max.max(frame.len())),
+            None => (frame.len(), frame.len()),
+        });
+        self.frame_number += 1;
+        self.total_samples += self.left.len() as u64;
+        self.left.clear();
+        self.right.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for FlacWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn streaminfo(
+    sample_rate: u32,
+    total_samples: u64,
+    frame_sizes: Option<(usize, usize)>,
+) -> [u8; 34] {
+    let (min_frame, max_frame) = frame_sizes.unwrap_or((0, 0));
+    let mut info = [0u8; 34];
+    info[0..2].copy_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    info[2..4].copy_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    info[4..7].copy_from_slice(&(min_frame as u32).to_be_bytes()[1..]);
+    info[7..10].copy_from_slice(&(max_frame as u32).to_be_bytes()[1..]);
+    let packed = (sample_rate as u64) << 44
+        | 1 << 41 // two channels
+        | ((BITS_PER_SAMPLE - 1) as u64) << 36
+        | total_samples & 0xF_FFFF_FFFF;
+    info[10..18].copy_from_slice(&packed.to_be_bytes());
+    // MD5 left as zeros, which means not computed
+    info
+}
+
+fn encode_frame(frame_number: u64, sample_rate: u32, channels: [&[i32]; 2]) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let block_code = if block_size == BLOCK_SIZE {
+        0b1100
+    } else {
+        0b0111
+    };
+    let rate_code = match sample_rate {
+        88200 => 0b0001,
+        192000 => 0b0011,
+        44100 => 0b1001,
+        48000 => 0b1010,
+        96000 => 0b1011,
+        _ => 0b0000, // from STREAMINFO
+    };
+
+    let mut header = vec![0xFF, 0xF8, block_code << 4 | rate_code];
+    // Independent stereo, 24 bits per sample
+    header.push(0b0001 << 4 | 0b110 << 1);
+    push_utf8(&mut header, frame_number);
+    if block_code == 0b0111 {
+        header.extend_from_slice(&((block_size - 1) as u16).to_be_bytes());
+    }
+    header.push(crc8(&header));
+
+    let mut bits = BitWriter::new(header);
+    for samples in channels {
+        encode_subframe(&mut bits, samples);
+    }
+    let mut frame = bits.finish();
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+fn encode_subframe(bits: &mut BitWriter, samples: &[i32]) {
+    // Silence is common between tracks and costs a few bytes this way
+    if samples.iter().all(|&s| s == samples[0]) {
+        bits.write(0b0000_0000, 8);
+        bits.write_signed(samples[0] as i64, BITS_PER_SAMPLE);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (parameter, residual_bits) = rice_parameter(&residual);
+            let total = order as u64 * BITS_PER_SAMPLE as u64 + residual_bits;
+            (total, order, parameter, residual)
+        })
+        .min_by_key(|(total, ..)| *total);
+
+    match best {
+        Some((total, order, parameter, residual)) if total < verbatim_bits => {
+            bits.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &warm_up in &samples[..order] {
+                bits.write_signed(warm_up as i64, BITS_PER_SAMPLE);
+            }
+            // RICE2 has room for the larger parameters 24 bit audio needs
+            let (method, parameter_bits) = if parameter > 14 { (1, 5) } else { (0, 4) };
+            bits.write(method, 2);
+            bits.write(0, 4); // partition order
+            bits.write(parameter as u64, parameter_bits);
+            for &r in &residual {
+                let folded = zigzag(r);
+                bits.write_unary(folded >> parameter);
+                bits.write(folded, parameter);
+            }
+        }
+        _ => {
+            bits.write(0b0000_0010, 8);
+            for &sample in samples {
+                bits.write_signed(sample as i64, BITS_PER_SAMPLE);
+            }
+        }
+    }
+}
+
+/// Residual of the fixed polynomial predictor of the given order, which is
+/// just the order-th difference of the signal.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i64> {
+    let mut residual: Vec<i64> = samples.iter().map(|&s| s as i64).collect();
+    for _ in 0..order {
+        for i in (1..residual.len()).rev() {
+            residual[i] -= residual[i - 1];
+        }
+    }
+    residual.split_off(order)
+}
+
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Best Rice parameter near the mean and the bits it would take.
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let sum: u64 = residual.iter().map(|&r| zigzag(r)).sum();
+    let mean = sum / residual.len().max(1) as u64;
+    let guess = (64 - mean.leading_zeros()).min(30);
+    (guess.saturating_sub(1)..=(guess + 1).min(30))
+        .map(|k| {
+            let bits: u64 = residual
+                .iter()
+                .map(|&r| (zigzag(r) >> k) + 1 + k as u64)
+                .sum();
+            (k, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap_or((0, 0))
+}
+
+// Frame numbers are coded like UTF-8, extended to 36 bits
+fn push_utf8(out: &mut Vec<u8>, value: u64) {
+    if value < 0x80 {
+        out.push(value as u8);
+        return;
+    }
+    let count = match value {
+        0..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        0x1_0000..=0x1F_FFFF => 4,
+        0x20_0000..=0x3FF_FFFF => 5,
+        0x400_0000..=0x7FFF_FFFF => 6,
+        _ => 7,
+    };
+    out.push((0xFF00u16 >> count) as u8 | (value >> (6 * (count - 1))) as u8);
+    for i in (0..count - 1).rev() {
+        out.push(0x80 | ((value >> (6 * i)) & 0x3F) as u8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    pending: u32,
+}
+
+impl BitWriter {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            acc: 0,
+            pending: 0,
+        }
+    }
+
+    /// Writes the low `count` bits of `value`, at most 32 at a time.
+    fn write(&mut self, value: u64, count: u32) {
+        debug_assert!(count <= 32);
+        if count == 0 {
+            return;
+        }
+        self.acc = (self.acc << count) | (value & ((1 << count) - 1));
+        self.pending += count;
+        while self.pending >= 8 {
+            self.pending -= 8;
+            self.bytes.push((self.acc >> self.pending) as u8);
+        }
+        self.acc &= (1 << self.pending) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, count: u32) {
+        self.write(value as u64, count);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        let mut zeros = zeros;
+        while zeros > 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(0, zeros as u32);
+        self.write(1, 1);
+    }
+
+    /// Pads with zeros to a byte boundary.
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending > 0 {
+            let padding = 8 - self.pending;
+            self.write(0, padding);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::audio::{AudioBufferRef, Signal};
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    fn decode(data: Vec<u8>) -> (u64, Vec<(i32, i32)>) {
+        let mss = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                mss,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let params = format.default_track().unwrap().codec_params.clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .unwrap();
+        let mut frames = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            match decoder.decode(&packet).unwrap() {
+                // Scaled up to 32 bits by the decoder
+                AudioBufferRef::S32(buf) => frames.extend(
+                    buf.chan(0)
+                        .iter()
+                        .zip(buf.chan(1))
+                        .map(|(l, r)| (l >> 8, r >> 8)),
+                ),
+                _ => panic!("expected 32 bit samples"),
+            }
+        }
+        (params.n_frames.unwrap_or(0), frames)
+    }
+
+    fn test_signal(len: usize) -> Vec<(i32, i32)> {
+        let mut noise = 12345u32;
+        (0..len)
+            .map(|i| {
+                noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+                let hiss = (noise >> 16) as i32 - 32768;
+                let tone = (4_000_000.0 * (i as f64 * 0.05).sin()) as i32;
+                match i {
+                    // Silence, a tone, then a full scale click and noise
+                    0..=5000 => (0, 0),
+                    _ if i % 7919 == 0 => (8_388_607, -8_388_608),
+                    _ => (tone + hiss, tone / 2 - hiss),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn roundtrips_through_symphonia() {
+        // Long enough for multi byte frame numbers, ends on a partial block
+        let signal = test_signal(BLOCK_SIZE * 130 + 1000);
+        let mut writer = FlacWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+        for chunk in signal.chunks(1234) {
+            writer.write(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        let data = std::mem::take(writer.out.get_mut());
+
+        assert!(data.len() < signal.len() * 6, "no compression at all");
+        let (total, decoded) = decode(data);
+        assert_eq!(signal.len() as u64, total);
+        assert!(decoded == signal, "decoded samples differ");
+    }
+
+    #[test]
+    fn unfinished_file_still_decodes() {
+        let signal = test_signal(BLOCK_SIZE * 3);
+        let mut writer = FlacWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        writer.write(&signal).unwrap();
+        writer.flush().unwrap();
+        // What a crash leaves behind: no sample count in STREAMINFO
+        let data = writer.out.get_ref().clone();
+        writer.finished = true;
+
+        let (_, decoded) = decode(data);
+        assert!(decoded == signal, "decoded samples differ");
+    }
+}
@@ -0,0 +1,569 @@
+mod cue;
+mod flac;
+
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use flac::FlacWriter;
+use jack::{AudioIn, Client, ClientOptions, Control, ProcessScope};
+use my_common::connections::{self, Rules};
+use my_common::deck::DeckState;
+use my_common::events::{self, LoggedEvent};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Set by the writer thread once the files exist, the process callback only
+// queues audio while it is on
+static RECORDING: AtomicBool = AtomicBool::new(false);
+// Frames lost because the writer thread fell behind, the same frames on
+// every stream
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Seconds of audio the ring buffers hold if the disk stalls
+const BUFFER_SECONDS: u32 = 4;
+// WAV headers and FLAC frames hit the disk at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Flac,
+    Wav,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Flac => "flac",
+            Format::Wav => "wav",
+        }
+    }
+}
+
+enum RecorderCommand {
+    Start,
+    Stop,
+    Deck(u32, DeckState),
+    /// A control subject and payload for the event log
+    Event(String, String),
+    /// Stop and finish the files, then end the thread
+    Quit,
+}
+
+enum Sink {
+    Flac(FlacWriter<BufWriter<File>>),
+    Wav(hound::WavWriter<BufWriter<File>>),
+}
+
+impl Sink {
+    fn create(path: &Path, format: Format, sample_rate: u32) -> io::Result<Sink> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match format {
+            Format::Flac => Sink::Flac(FlacWriter::new(file, sample_rate)?),
+            Format::Wav => {
+                let spec = hound::WavSpec {
+                    channels: 2,
+                    sample_rate,
+                    bits_per_sample: 24,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                Sink::Wav(hound::WavWriter::new(file, spec).map_err(io::Error::other)?)
+            }
+        })
+    }
+
+    fn write(&mut self, frames: &[(f32, f32)]) -> io::Result<()> {
+        let to_i24 = |s: f32| (s.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32;
+        match self {
+            Sink::Flac(writer) => {
+                let frames: Vec<(i32, i32)> = frames
+                    .iter()
+                    .map(|&(l, r)| (to_i24(l), to_i24(r)))
+                    .collect();
+                writer.write(&frames)
+            }
+            Sink::Wav(writer) => frames
+                .iter()
+                .try_for_each(|&(l, r)| {
+                    writer.write_sample(to_i24(l))?;
+                    writer.write_sample(to_i24(r))
+                })
+                .map_err(io::Error::other),
+        }
+    }
+
+    /// After this the file on disk is playable up to here, even if the
+    /// process dies before `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Flac(writer) => writer.flush(),
+            Sink::Wav(writer) => writer.flush().map_err(io::Error::other),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::Flac(mut writer) => writer.finish(),
+            Sink::Wav(writer) => writer.finalize().map_err(io::Error::other),
+        }
+    }
+}
+
+struct Stream {
+    /// Appended to the file name, empty for the master
+    suffix: String,
+    consumer: Consumer<(f32, f32)>,
+}
+
+#[derive(Default)]
+struct DeckLog {
+    state: DeckState,
+    /// Last path that went into the tracklist of this recording
+    logged: Option<String>,
+}
+
+struct Recording {
+    audio_path: PathBuf,
+    sample_rate: u32,
+    sinks: Vec<Sink>,
+    frames: u64,
+    entries: Vec<cue::Entry>,
+    /// Control events for `ANAHATA --render`, a line is on disk as soon as
+    /// it is written
+    events: LineWriter<File>,
+    started: Instant,
+}
+
+impl Recording {
+    fn start(dir: &Path, streams: &[Stream], format: Format, sample_rate: u32) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let base = chrono::Local::now().format("set-%Y%m%d-%H%M%S").to_string();
+        let sinks = streams
+            .iter()
+            .map(|stream| {
+                let name = format!("{}{}.{}", base, stream.suffix, format.extension());
+                Sink::create(&dir.join(name), format, sample_rate)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let events = LineWriter::new(File::create(dir.join(format!("{}.events.jsonl", base)))?);
+        Ok(Self {
+            audio_path: dir.join(format!("{}.{}", base, format.extension())),
+            sample_rate,
+            sinks,
+            frames: 0,
+            entries: Vec::new(),
+            events,
+            started: Instant::now(),
+        })
+    }
+
+    fn log_event(&mut self, subject: &str, payload: &str) {
+        let event = LoggedEvent {
+            time: self.started.elapsed().as_secs_f64(),
+            subject: subject.to_string(),
+            payload: payload.to_string(),
+        };
+        let result = serde_json::to_string(&event)
+            .map_err(io::Error::other)
+            .and_then(|line| writeln!(self.events, "{}", line));
+        if let Err(e) = result {
+            eprintln!("Failed to write event log: {}", e);
+        }
+    }
+
+    /// Adds a tracklist entry when a deck is playing something it has not
+    /// played yet in this recording.
+    fn log_track(&mut self, number: u32, deck: &mut DeckLog) {
+        if !deck.state.playing || deck.state.path.is_none() || deck.logged == deck.state.path {
+            return;
+        }
+        deck.logged = deck.state.path.clone();
+        self.entries.push(cue::Entry {
+            offset: self.frames,
+            deck: number,
+            path: deck.state.path.clone().unwrap_or_default(),
+            title: deck.state.title.clone(),
+            artist: deck.state.artist.clone(),
+        });
+        if let Err(e) = self.write_tracklist() {
+            eprintln!("Failed to write tracklist: {}", e);
+        }
+    }
+
+    fn write_tracklist(&self) -> io::Result<()> {
+        let file_name = self
+            .audio_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        cue::write_atomic(
+            &self.audio_path.with_extension("cue"),
+            &cue::cue_sheet(&file_name, &self.entries, self.sample_rate),
+        )?;
+        cue::write_atomic(
+            &self.audio_path.with_extension("txt"),
+            &cue::tracklist(&self.entries, self.sample_rate),
+        )
+    }
+
+    fn finish(self) -> io::Result<()> {
+        for sink in self.sinks {
+            sink.finish()?;
+        }
+        println!(
+            "Recorded {:.1}s to {}",
+            self.frames as f64 / self.sample_rate as f64,
+            self.audio_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// How many of a cycle's `frames` fit in every ring. The rest is dropped
+/// from all of them, a stem that kept frames the master lost would run
+/// ahead of it for the rest of the recording.
+fn aligned_room<'a>(
+    producers: impl IntoIterator<Item = &'a Producer<(f32, f32)>>,
+    frames: usize,
+) -> usize {
+    producers
+        .into_iter()
+        .map(|producer| producer.slots())
+        .fold(frames, usize::min)
+}
+
+/// Moves queued audio to disk. The streams stay aligned because the process
+/// callback pushes the same frames to each, and drops the same ones when
+/// any ring is full, so the master count is the recording length.
+fn drain(
+    streams: &mut [Stream],
+    recording: &mut Recording,
+    buffer: &mut Vec<(f32, f32)>,
+) -> io::Result<()> {
+    for (i, (stream, sink)) in streams.iter_mut().zip(&mut recording.sinks).enumerate() {
+        buffer.clear();
+        while let Ok(frame) = stream.consumer.pop() {
+            buffer.push(frame);
+        }
+        sink.write(buffer)?;
+        if i == 0 {
+            recording.frames += buffer.len() as u64;
+        }
+    }
+    Ok(())
+}
+
+fn publish_status(nc: &nats::Connection, recording: Option<&Recording>) {
+    let status = serde_json::json!({
+        "recording": recording.is_some(),
+        "path": recording.map(|r| r.audio_path.to_string_lossy().into_owned()),
+        "seconds": recording.map_or(0.0, |r| r.frames as f64 / r.sample_rate as f64),
+        "tracks": recording.map_or(0, |r| r.entries.len()),
+        "dropped": DROPPED.load(Ordering::Relaxed),
+    });
+    let _ = nc.publish("smriti.status", status.to_string());
+}
+
+fn writer_thread(
+    mut streams: Vec<Stream>,
+    cmd_rx: Receiver<RecorderCommand>,
+    nc: nats::Connection,
+    dir: PathBuf,
+    format: Format,
+    sample_rate: u32,
+) {
+    let mut recording: Option<Recording> = None;
+    let mut decks: BTreeMap<u32, DeckLog> = BTreeMap::new();
+    let mut buffer = Vec::new();
+    let mut last_flush = Instant::now();
+
+    loop {
+        match cmd_rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(RecorderCommand::Start) if recording.is_none() => {
+                match Recording::start(&dir, &streams, format, sample_rate) {
+                    Ok(mut started) => {
+                        // Anything left over from the last recording
+                        for stream in &mut streams {
+                            while stream.consumer.pop().is_ok() {}
+                        }
+                        DROPPED.store(0, Ordering::Relaxed);
+                        RECORDING.store(true, Ordering::Relaxed);
+                        println!("Recording to {}", started.audio_path.display());
+                        // Tracks already playing start at 0:00, and a render
+                        // starts each deck from where it is now
+                        for (&number, deck) in &mut decks {
+                            deck.logged = None;
+                            started.log_track(number, deck);
+                            if let Ok(state) = serde_json::to_string(&deck.state) {
+                                started.log_event(&format!("anahata.{}.state", number), &state);
+                            }
+                        }
+                        recording = Some(started);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to start recording: {}", e);
+                        let _ = nc.publish("smriti.error", e.to_string());
+                    }
+                }
+                publish_status(&nc, recording.as_ref());
+            }
+            Ok(RecorderCommand::Start) => {}
+            Ok(RecorderCommand::Stop) => {
+                stop(&mut streams, recording.take(), &mut buffer, &nc);
+                publish_status(&nc, None);
+            }
+            Ok(RecorderCommand::Deck(number, state)) => {
+                let deck = decks.entry(number).or_default();
+                deck.state = state;
+                if let Some(recording) = &mut recording {
+                    recording.log_track(number, deck);
+                }
+            }
+            Ok(RecorderCommand::Event(subject, payload)) => {
+                if let Some(recording) = &mut recording {
+                    recording.log_event(&subject, &payload);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(RecorderCommand::Quit) | Err(RecvTimeoutError::Disconnected) => {
+                stop(&mut streams, recording.take(), &mut buffer, &nc);
+                break;
+            }
+        }
+
+        let Some(current) = &mut recording else {
+            continue;
+        };
+        let mut result = drain(&mut streams, current, &mut buffer);
+        if result.is_ok() && last_flush.elapsed() >= FLUSH_INTERVAL {
+            last_flush = Instant::now();
+            result = current.sinks.iter_mut().try_for_each(Sink::flush);
+            publish_status(&nc, Some(current));
+        }
+        if let Err(e) = result {
+            // Most likely a full disk, keep what made it so far
+            eprintln!("Recording stopped, write failed: {}", e);
+            let _ = nc.publish("smriti.error", e.to_string());
+            RECORDING.store(false, Ordering::Relaxed);
+            if let Some(failed) = recording.take() {
+                let _ = failed.finish();
+            }
+            publish_status(&nc, None);
+        }
+    }
+}
+
+fn stop(
+    streams: &mut [Stream],
+    recording: Option<Recording>,
+    buffer: &mut Vec<(f32, f32)>,
+    nc: &nats::Connection,
+) {
+    let Some(mut recording) = recording else {
+        return;
+    };
+    RECORDING.store(false, Ordering::Relaxed);
+    // Renders run up to the last event, so they end where the audio does
+    recording.log_event("smriti.stop", "");
+    let result = drain(streams, &mut recording, buffer).and_then(|_| recording.finish());
+    if let Err(e) = result {
+        eprintln!("Failed to finish recording: {}", e);
+        let _ = nc.publish("smriti.error", e.to_string());
+    }
+}
+
+fn nats_thread(nc: nats::Connection, cmd_tx: Sender<RecorderCommand>) {
+    let subscriptions = nc.subscribe("smriti.>").and_then(|sub| {
+        let decks = nc.subscribe("anahata.>")?;
+        let drishti = nc.subscribe("drishti.result")?;
+        let faders = nc.subscribe("xone.fader")?;
+        Ok((sub, decks, drishti, faders))
+    });
+    let (sub, decks, drishti, faders) = match subscriptions {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            eprintln!("Remote control disabled, failed to subscribe: {}", e);
+            return;
+        }
+    };
+    loop {
+        let msg = crossbeam::channel::select! {
+            recv(sub.receiver()) -> msg => msg,
+            recv(decks.receiver()) -> msg => msg,
+            recv(drishti.receiver()) -> msg => msg,
+            recv(faders.receiver()) -> msg => msg,
+        };
+        let Ok(msg) = msg else {
+            break;
+        };
+        let command = match msg.subject.as_str() {
+            "smriti.start" => Some(RecorderCommand::Start),
+            "smriti.stop" => Some(RecorderCommand::Stop),
+            subject if events::is_control_subject(subject) => Some(RecorderCommand::Event(
+                msg.subject.clone(),
+                String::from_utf8_lossy(&msg.data).into_owned(),
+            )),
+            subject => subject
+                .strip_prefix("anahata.")
+                .and_then(|rest| rest.strip_suffix(".state"))
+                .and_then(|number| number.parse().ok())
+                .zip(serde_json::from_slice(&msg.data).ok())
+                .map(|(number, state)| RecorderCommand::Deck(number, state)),
+        };
+        if let Some(command) = command {
+            if cmd_tx.send(command).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn connect_nats() -> nats::Connection {
+    loop {
+        match nats::Options::new()
+            .retry_on_failed_connect()
+            .max_reconnects(None)
+            .connect("nats://localhost:4222")
+        {
+            Ok(nc) => return nc,
+            Err(e) => {
+                eprintln!("Failed to connect to NATS, retrying: {}", e);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn arg_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+fn main() {
+    let format = match arg_value("--format").as_deref() {
+        None | Some("flac") => Format::Flac,
+        Some("wav") => Format::Wav,
+        Some(other) => {
+            eprintln!("Unknown format {}, expected flac or wav", other);
+            std::process::exit(2);
+        }
+    };
+    let dir = PathBuf::from(arg_value("--dir").unwrap_or_else(|| "recordings".to_string()));
+    // Per deck stems next to the master, inputs deckN_left and deckN_right
+    let deck_stems: u32 = arg_value("--decks")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    // Deck outputs go into the master inputs by the connection rules, JACK
+    // sums them
+    let auto_connect = !std::env::args().any(|arg| arg == "--no-connect");
+    let start_now = std::env::args().any(|arg| arg == "--start");
+
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&terminate))
+            .expect("Failed to register signal handler");
+    }
+
+    let (client, _status) = Client::new("SMRITI", ClientOptions::NO_START_SERVER)
+        .expect("Failed to create JACK client");
+    let sample_rate = client.sample_rate() as u32;
+    println!(
+        "JACK buffer size: {}, sample rate: {}",
+        client.buffer_size(),
+        sample_rate
+    );
+
+    let mut inputs = Vec::new();
+    let mut streams = Vec::new();
+    let names = std::iter::once(("master".to_string(), String::new()))
+        .chain((1..=deck_stems).map(|n| (format!("deck{}", n), format!("-deck{}", n))));
+    for (name, suffix) in names {
+        let left = client
+            .register_port(&format!("{}_left", name), AudioIn::default())
+            .expect("Failed to create left input port");
+        let right = client
+            .register_port(&format!("{}_right", name), AudioIn::default())
+            .expect("Failed to create right input port");
+        let (producer, consumer) =
+            RingBuffer::<(f32, f32)>::new((sample_rate * BUFFER_SECONDS) as usize);
+        inputs.push((left, right, producer));
+        streams.push(Stream { suffix, consumer });
+    }
+
+    let (cmd_tx, cmd_rx) = bounded::<RecorderCommand>(32);
+    let nc = connect_nats();
+    let writer_nc = nc.clone();
+    let writer = thread::spawn(move || {
+        writer_thread(streams, cmd_rx, writer_nc, dir, format, sample_rate);
+    });
+    let nats_tx = cmd_tx.clone();
+    thread::spawn(move || {
+        nats_thread(nc, nats_tx);
+    });
+
+    let process_callback = move |_: &Client, ps: &ProcessScope| -> Control {
+        if !RECORDING.load(Ordering::Relaxed) {
+            return Control::Continue;
+        }
+        let frames = ps.n_frames() as usize;
+        // The writer only ever frees slots, so this much fits all the way
+        let room = aligned_room(inputs.iter().map(|(_, _, producer)| producer), frames);
+        if room < frames {
+            DROPPED.fetch_add((frames - room) as u64, Ordering::Relaxed);
+        }
+        for (left, right, producer) in &mut inputs {
+            for frame in left.as_slice(ps).iter().zip(right.as_slice(ps)).take(room) {
+                let _ = producer.push((*frame.0, *frame.1));
+            }
+        }
+        Control::Continue
+    };
+
+    let active_client = client
+        .activate_async(
+            (),
+            jack::contrib::ClosureProcessHandler::new(process_callback),
+        )
+        .expect("Failed to activate client");
+
+    if auto_connect {
+        let owner = active_client.as_client().name().to_string();
+        if let Err(e) = connections::spawn(owner, Rules::load_default()) {
+            eprintln!("Not connecting any ports: {}", e);
+        }
+    }
+
+    if start_now {
+        let _ = cmd_tx.send(RecorderCommand::Start);
+    }
+    while !terminate.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    println!("Shutting down");
+    let _ = cmd_tx.send(RecorderCommand::Quit);
+    let _ = writer.join();
+    active_client
+        .deactivate()
+        .expect("Failed to deactivate client");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn a_full_ring_drops_frames_from_every_stream() {
+        let (mut master, _master_out) = RingBuffer::<(f32, f32)>::new(8);
+        let (stem, _stem_out) = RingBuffer::<(f32, f32)>::new(8);
+        for _ in 0..5 {
+            master.push((0.0, 0.0)).unwrap();
+        }
+        assert_eq!(3, aligned_room([&master, &stem], 6));
+        assert_eq!(2, aligned_room([&master, &stem], 2));
+        assert_eq!(6, aligned_room([&stem], 6));
+    }
+}
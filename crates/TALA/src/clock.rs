@@ -0,0 +1,157 @@
+//! MIDI clock from a beat position: 24 pulses per quarter note, song
+//! position pointer and start, continue and stop, each message placed at its
+//! frame in the period so the only jitter left is the receiver's.
+
+pub const PULSES_PER_BEAT: f64 = 24.0;
+// Song position pointer counts sixteenths, six pulses each
+const PULSES_PER_SIXTEENTH: i64 = 6;
+// How far the reported position may wander from the pulse count before it
+// counts as a jump. Wobbles smaller than that neither repeat nor drop pulses.
+const MAX_DRIFT: f64 = 6.0;
+
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const SONG_POSITION: u8 = 0xF2;
+
+/// Where the tempo master is at the first frame of a period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tempo {
+    /// Beats since the first beat of the grid
+    pub beat: f64,
+    pub bpm: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct ClockGenerator {
+    rolling: bool,
+    next_pulse: i64,
+}
+
+impl ClockGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Produces the messages for one period of `frames`, calling
+    /// `emit(offset, message)` in frame order. No tempo, e.g. a master
+    /// without a beatgrid, counts as stopped.
+    pub fn period(
+        &mut self,
+        tempo: Option<Tempo>,
+        sample_rate: f64,
+        frames: u32,
+        mut emit: impl FnMut(u32, &[u8]),
+    ) {
+        let Some(tempo) = tempo.filter(|tempo| tempo.bpm > 0.0 && tempo.beat >= 0.0) else {
+            if self.rolling {
+                emit(0, &[STOP]);
+                self.rolling = false;
+            }
+            return;
+        };
+        let now = tempo.beat * PULSES_PER_BEAT;
+        let pulses_per_frame = tempo.bpm / 60.0 * PULSES_PER_BEAT / sample_rate;
+
+        if !self.rolling {
+            // Receivers pick up on a sixteenth, so the first pulse goes there
+            let sixteenth = (now / PULSES_PER_SIXTEENTH as f64).ceil() as i64;
+            self.next_pulse = sixteenth * PULSES_PER_SIXTEENTH;
+            if sixteenth == 0 {
+                emit(0, &[START]);
+            } else {
+                let position = sixteenth.min(0x3fff);
+                emit(
+                    0,
+                    &[
+                        SONG_POSITION,
+                        (position & 0x7f) as u8,
+                        (position >> 7) as u8,
+                    ],
+                );
+                emit(0, &[CONTINUE]);
+            }
+            self.rolling = true;
+        } else if (self.next_pulse as f64 - now).abs() > MAX_DRIFT {
+            // A jump while running, carry on counting from the new position
+            self.next_pulse = now.ceil() as i64;
+        }
+
+        loop {
+            // Pulses that are already late go out first thing
+            let offset = ((self.next_pulse as f64 - now) / pulses_per_frame)
+                .max(0.0)
+                .round();
+            if offset >= frames as f64 {
+                break;
+            }
+            emit(offset as u32, &[CLOCK]);
+            self.next_pulse += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(clock: &mut ClockGenerator, tempo: Option<Tempo>, frames: u32) -> Vec<(u32, Vec<u8>)> {
+        let mut messages = Vec::new();
+        clock.period(tempo, 48000.0, frames, |offset, message| {
+            messages.push((offset, message.to_vec()))
+        });
+        messages
+    }
+
+    #[test]
+    fn pulses_at_their_frames() {
+        // 120 BPM at 48 kHz is a pulse every 1000 frames
+        let mut clock = ClockGenerator::new();
+        let tempo = |frame: f64| {
+            Some(Tempo {
+                beat: frame / 24000.0,
+                bpm: 120.0,
+            })
+        };
+        assert_eq!(
+            vec![
+                (0, vec![START]),
+                (0, vec![CLOCK]),
+                (1000, vec![CLOCK]),
+                (2000, vec![CLOCK])
+            ],
+            run(&mut clock, tempo(0.0), 2048)
+        );
+        assert_eq!(
+            vec![(952, vec![CLOCK]), (1952, vec![CLOCK])],
+            run(&mut clock, tempo(2048.0), 2048)
+        );
+        // Reported behind where the last period ended, pulse 4 isn't sent
+        // again and pulse 5 keeps its frame
+        assert_eq!(
+            vec![(1100, vec![CLOCK])],
+            run(&mut clock, tempo(3900.0), 1200)
+        );
+        assert_eq!(vec![(0, vec![STOP])], run(&mut clock, None, 2048));
+        assert!(run(&mut clock, None, 2048).is_empty());
+    }
+
+    #[test]
+    fn continues_from_the_next_sixteenth() {
+        let mut clock = ClockGenerator::new();
+        // Beat 4.1 is pulse 98.4, the next sixteenth starts at pulse 102
+        let tempo = Some(Tempo {
+            beat: 4.1,
+            bpm: 120.0,
+        });
+        assert_eq!(
+            vec![
+                (0, vec![SONG_POSITION, 17, 0]),
+                (0, vec![CONTINUE]),
+                (3600, vec![CLOCK])
+            ],
+            run(&mut clock, tempo, 4096)
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Tempo and beat phase shared between machines over UDP multicast, in the
+//! spirit of Ableton Link but much smaller.
+//!
+//! Every TALA whose JACK transport is rolling with a tempo announces its
+//! timeline a few times a second. The one that has been playing longest
+//! leads and everyone else follows its timeline until it stops, so in a back
+//! to back set the tempo hands over when the outgoing laptop stops. Packets
+//! carry how long the sender has been playing rather than when it started,
+//! so the machines' clocks don't have to agree. Nothing corrects for network
+//! latency, which on a LAN is well under a MIDI clock pulse and over
+//! loopback is nothing.
+//!
+//! The session drives TALA's MIDI clock and whatever is synced to it, such
+//! as a drum machine. On a machine following another, TALA also publishes
+//! it as the JACK transport position, and decks started quantized there
+//! land on the session's beat, see timebase.rs. They have no tempo control
+//! though, a deck plays its track at its own tempo and only stays in phase
+//! when that matches the session's.
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+pub const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 71, 84), 20808);
+// A peer that stops announcing without saying so, crashed or unplugged
+const PEER_TIMEOUT_US: u64 = 1_000_000;
+
+/// A tempo and which beat it was on at `at`, microseconds on this machine's
+/// monotonic JACK clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeline {
+    pub bpm: f64,
+    pub beat: f64,
+    pub at: u64,
+}
+
+impl Timeline {
+    pub fn beat_at(&self, time: u64) -> f64 {
+        self.beat + (time as f64 - self.at as f64) / 60e6 * self.bpm
+    }
+}
+
+// On the wire. The beat is the sender's at the moment it sent.
+#[derive(Debug, Serialize, Deserialize)]
+struct Announce {
+    peer: u64,
+    bpm: f64,
+    beat: f64,
+    /// Microseconds it has been playing for, to agree on who came first
+    playing_for: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leader {
+    Local,
+    Peer(u64),
+}
+
+struct Candidate {
+    timeline: Timeline,
+    // When it started playing, on this machine's clock
+    started: u64,
+    heard: u64,
+}
+
+pub struct Session {
+    id: u64,
+    local: Option<Candidate>,
+    peers: HashMap<u64, Candidate>,
+}
+
+impl Session {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            local: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// This machine's own timeline, `None` while its transport is stopped.
+    /// Playing started at the first one after a stop.
+    pub fn set_local(&mut self, timeline: Option<Timeline>) {
+        self.local = timeline.map(|timeline| Candidate {
+            timeline,
+            started: self
+                .local
+                .as_ref()
+                .map_or(timeline.at, |local| local.started),
+            heard: timeline.at,
+        });
+    }
+
+    /// The packet to send at `now`, nothing while stopped.
+    pub fn announce(&self, now: u64) -> Option<Vec<u8>> {
+        let local = self.local.as_ref()?;
+        serde_json::to_vec(&Announce {
+            peer: self.id,
+            bpm: local.timeline.bpm,
+            beat: local.timeline.beat_at(now),
+            playing_for: now.saturating_sub(local.started),
+        })
+        .ok()
+    }
+
+    /// Takes in a packet that arrived at `now`. Our own come back through
+    /// multicast loop and are ignored, as is anything unreadable.
+    pub fn receive(&mut self, packet: &[u8], now: u64) {
+        let Ok(announce) = serde_json::from_slice::<Announce>(packet) else {
+            return;
+        };
+        if announce.peer == self.id || !announce.bpm.is_finite() || announce.bpm <= 0.0 {
+            return;
+        }
+        self.peers.insert(
+            announce.peer,
+            Candidate {
+                timeline: Timeline {
+                    bpm: announce.bpm,
+                    beat: announce.beat,
+                    at: now,
+                },
+                started: now.saturating_sub(announce.playing_for),
+                heard: now,
+            },
+        );
+    }
+
+    /// Forgets peers not heard from for a while.
+    pub fn expire(&mut self, now: u64) {
+        self.peers
+            .retain(|_, peer| now.saturating_sub(peer.heard) < PEER_TIMEOUT_US);
+    }
+
+    /// Who leads and the timeline everyone follows: whoever has been
+    /// playing longest, the lower id if that's a tie.
+    pub fn leader(&self) -> Option<(Leader, Timeline)> {
+        let local = self
+            .local
+            .as_ref()
+            .map(|local| (Leader::Local, self.id, local));
+        let peers = self
+            .peers
+            .iter()
+            .map(|(&id, peer)| (Leader::Peer(id), id, peer));
+        local
+            .into_iter()
+            .chain(peers)
+            .min_by_key(|(_, id, candidate)| (candidate.started, *id))
+            .map(|(leader, _, candidate)| (leader, candidate.timeline))
+    }
+}
+
+/// Joins `group` on the interface with address `interface`, e.g. 127.0.0.1
+/// to try two processes on one machine. Several sockets on a machine can
+/// share the group's port.
+pub fn open(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    const SECOND: u64 = 1_000_000;
+
+    fn timeline(bpm: f64, beat: f64, at: u64) -> Option<Timeline> {
+        Some(Timeline { bpm, beat, at })
+    }
+
+    #[test]
+    fn longest_playing_leads() {
+        let mut a = Session::new(1);
+        let mut b = Session::new(2);
+        assert_eq!(None, a.leader());
+        assert_eq!(None, a.announce(0));
+
+        // b's clock reads 100 seconds less than a's, a starts a second
+        // before b
+        let on_b = |time: u64| time - 100 * SECOND;
+        a.set_local(timeline(120.0, 8.0, 100 * SECOND));
+        b.set_local(timeline(126.0, 0.0, on_b(101 * SECOND)));
+        // Three seconds in, a's 120 BPM has moved on six beats
+        b.receive(&a.announce(103 * SECOND).unwrap(), on_b(103 * SECOND));
+        a.receive(&b.announce(on_b(103 * SECOND)).unwrap(), 103 * SECOND);
+        assert_eq!(
+            Some((Leader::Local, timeline(120.0, 8.0, 100 * SECOND).unwrap())),
+            a.leader()
+        );
+        let (leader, followed) = b.leader().unwrap();
+        assert_eq!(Leader::Peer(1), leader);
+        assert_eq!(14.0, followed.beat_at(on_b(103 * SECOND)));
+        assert_eq!(16.0, followed.beat_at(on_b(104 * SECOND)));
+
+        // Staying on keeps the original start, a stopping hands over to b
+        b.set_local(timeline(126.0, 10.5, on_b(106 * SECOND)));
+        a.set_local(None);
+        a.receive(&b.announce(on_b(106 * SECOND)).unwrap(), 106 * SECOND);
+        assert_eq!(Some(Leader::Peer(2)), a.leader().map(|(leader, _)| leader));
+        b.expire(on_b(106 * SECOND));
+        assert_eq!(Some(Leader::Local), b.leader().map(|(leader, _)| leader));
+        // A restart counts from then, b has been playing longer
+        a.set_local(timeline(120.0, 0.0, 106 * SECOND));
+        assert_eq!(Some(Leader::Peer(2)), a.leader().map(|(leader, _)| leader));
+
+        // Our own packets come back and are nobody's lead
+        let mut c = Session::new(3);
+        c.set_local(timeline(120.0, 0.0, 0));
+        c.receive(&c.announce(0).unwrap(), 0);
+        c.receive(b"not json", 0);
+        assert!(c.peers.is_empty());
+    }
+
+    #[test]
+    fn two_sockets_over_loopback() {
+        let group = SocketAddrV4::new(*DEFAULT_GROUP.ip(), 20809);
+        let (Ok(a), Ok(b)) = (
+            open(group, Ipv4Addr::LOCALHOST),
+            open(group, Ipv4Addr::LOCALHOST),
+        ) else {
+            eprintln!("No multicast on loopback here, skipping");
+            return;
+        };
+        b.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut session = Session::new(2);
+        session.set_local(timeline(120.0, 0.0, 0));
+        a.send_to(&session.announce(0).unwrap(), group).unwrap();
+        let mut buf = [0; 512];
+        let (len, _) = b.recv_from(&mut buf).unwrap();
+        let mut follower = Session::new(1);
+        follower.receive(&buf[..len], 0);
+        assert_eq!(
+            Some(Leader::Peer(2)),
+            follower.leader().map(|(leader, _)| leader)
+        );
+    }
+}
@@ -0,0 +1,294 @@
+mod clock;
+mod link;
+mod timebase;
+
+use clock::{ClockGenerator, Tempo};
+use jack::{Client, ClientOptions, Control, MidiOut, ProcessScope, RawMidi};
+use jack::{TransportBBT, TransportState};
+use link::{Leader, Session, Timeline};
+use my_common::connections::{self, Rules};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Handed between the process callback and the main thread's `Link`. The callback
+/// only ever tries the lock and keeps going with what it had if it's busy.
+#[derive(Debug, Default)]
+struct Shared {
+    /// From this machine's JACK transport
+    local: Option<Timeline>,
+    /// Whoever leads, possibly the local one
+    session: Option<Timeline>,
+    /// TALA is the timebase master, the transport position is the session
+    /// and not a local timeline
+    timebase: bool,
+}
+
+fn arg_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+// Beats since bar 1 beat 1 from the timebase master's bar/beat/tick
+fn beats(bbt: &TransportBBT) -> f64 {
+    (bbt.bar as f64 - 1.0) * bbt.sig_num as f64
+        + (bbt.beat as f64 - 1.0)
+        + bbt.tick as f64 / bbt.ticks_per_beat
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timebase {
+    Released,
+    Held,
+    // Another client is master, tried again once we stop following
+    Refused,
+}
+
+/// Announces the local timeline and listens for peers, deciding who leads.
+/// Without a socket there's only the local timeline to follow. Following a
+/// peer, TALA becomes the JACK timebase master and rolls the transport so
+/// the decks here start on the session's beat.
+struct Link {
+    socket: Option<UdpSocket>,
+    group: SocketAddrV4,
+    session: Session,
+    // What was last printed, leader and rounded tempo
+    leader: Option<(Leader, f64)>,
+    last_announce: Option<Instant>,
+    timebase: Timebase,
+}
+
+impl Link {
+    fn new(socket: Option<UdpSocket>, group: SocketAddrV4) -> Self {
+        Self {
+            socket,
+            group,
+            session: Session::new(rand::random()),
+            leader: None,
+            last_announce: None,
+            timebase: Timebase::Released,
+        }
+    }
+
+    /// Waits for a packet up to the socket's read timeout, then brings
+    /// `shared` up to date. Times are JACK's, like the process callback's.
+    fn poll(&mut self, client: &Client, shared: &Mutex<Shared>) {
+        let mut buf = [0u8; 512];
+        match &self.socket {
+            Some(socket) => match socket.recv_from(&mut buf) {
+                Ok((len, _)) => self.session.receive(&buf[..len], client.time()),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    eprintln!("Link receive failed: {}", e);
+                    thread::sleep(ANNOUNCE_INTERVAL);
+                }
+            },
+            None => thread::sleep(ANNOUNCE_INTERVAL),
+        }
+
+        let now = client.time();
+        self.session.expire(now);
+        let current = {
+            let mut shared = shared.lock().unwrap();
+            self.session.set_local(shared.local);
+            let current = self.session.leader();
+            shared.session = current.map(|(_, timeline)| timeline);
+            current
+        };
+        let current = current.map(|(leader, timeline)| (leader, timeline.bpm.round()));
+        if current != self.leader {
+            match current {
+                Some((Leader::Local, bpm)) => println!("Leading at {} BPM", bpm),
+                Some((Leader::Peer(id), bpm)) => println!("Following {:016x} at {} BPM", id, bpm),
+                None => println!("Nobody is playing"),
+            }
+            self.leader = current;
+        }
+        let following = matches!(current, Some((Leader::Peer(_), _)));
+        self.follow(client, shared, following);
+
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if self
+            .last_announce
+            .is_none_or(|at| at.elapsed() >= ANNOUNCE_INTERVAL)
+        {
+            if let Some(packet) = self.session.announce(now) {
+                if let Err(e) = socket.send_to(&packet, self.group) {
+                    eprintln!("Link announce failed: {}", e);
+                }
+            }
+            self.last_announce = Some(Instant::now());
+        }
+    }
+
+    /// Takes the timebase while following a peer and gives it back after.
+    /// A transport that rolls here starts decks in `--transport follow`,
+    /// those in `off` only wait for its beat on a quantized start.
+    fn follow(&mut self, client: &Client, shared: &Mutex<Shared>, following: bool) {
+        match (following, self.timebase) {
+            (true, Timebase::Released) => {
+                // Set first, the callback must not take the session it is
+                // about to see in the transport for a local timeline
+                shared.lock().unwrap().timebase = true;
+                self.timebase = match timebase::become_master(client) {
+                    Ok(()) => {
+                        println!("JACK timebase master, decks here start on the session's beat");
+                        let _ = client.transport().start();
+                        Timebase::Held
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Not taking over JACK timebase, decks here keep their beat: {}",
+                            e
+                        );
+                        shared.lock().unwrap().timebase = false;
+                        Timebase::Refused
+                    }
+                };
+            }
+            (false, Timebase::Held) => {
+                let _ = client.transport().stop();
+                timebase::release(client);
+                shared.lock().unwrap().timebase = false;
+                self.timebase = Timebase::Released;
+            }
+            (false, Timebase::Refused) => self.timebase = Timebase::Released,
+            _ => {}
+        }
+    }
+}
+
+fn main() {
+    // e.g. `--connect Digitakt` for the drum machine's MIDI input, a regex
+    // on port names on top of the connection rules
+    let connect = arg_value("--connect");
+    // Other machines are found on the multicast group, `--interface
+    // 127.0.0.1` keeps it to this one
+    let group = arg_value("--group")
+        .and_then(|group| group.parse().ok())
+        .unwrap_or(link::DEFAULT_GROUP);
+    let interface = arg_value("--interface")
+        .and_then(|interface| interface.parse().ok())
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let socket = if std::env::args().any(|arg| arg == "--no-link") {
+        None
+    } else {
+        match link::open(group, interface) {
+            Ok(socket) => {
+                socket
+                    .set_read_timeout(Some(ANNOUNCE_INTERVAL / 5))
+                    .expect("Failed to set read timeout");
+                println!("Sharing tempo on {}", group);
+                Some(socket)
+            }
+            Err(e) => {
+                eprintln!("No tempo sharing, could not join {}: {}", group, e);
+                None
+            }
+        }
+    };
+
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&terminate))
+            .expect("Failed to register signal handler");
+    }
+
+    let (client, _status) =
+        Client::new("TALA", ClientOptions::NO_START_SERVER).expect("Failed to create JACK client");
+    let sample_rate = client.sample_rate() as f64;
+    let mut clock_out = client
+        .register_port("clock_out", MidiOut::default())
+        .expect("Failed to create MIDI clock port");
+    let output = clock_out.name().expect("Failed to get port name");
+    println!("MIDI clock on {}", output);
+
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let callback_shared = Arc::clone(&shared);
+
+    // The local tempo and position come from the JACK timebase master, a
+    // deck started with `ANAHATA --transport master`, unless that is TALA
+    // following another machine. The session drives the MIDI clock and in
+    // that case the transport, see timebase.rs.
+    let transport = client.transport();
+    let mut clock = ClockGenerator::new();
+    let mut session = None;
+    let process_callback = move |client: &Client, ps: &ProcessScope| -> Control {
+        let (now, next) = ps.cycle_times().map_or_else(
+            |_| {
+                let now = client.time();
+                (now, now + (ps.n_frames() as f64 / sample_rate * 1e6) as u64)
+            },
+            |times| (times.current_usecs, times.next_usecs),
+        );
+        let local = transport
+            .query()
+            .ok()
+            .filter(|position| position.state == TransportState::Rolling)
+            .and_then(|position| position.pos.bbt())
+            .map(|bbt| Timeline {
+                bpm: bbt.bpm,
+                beat: beats(&bbt),
+                at: now,
+            });
+        if let Ok(mut shared) = callback_shared.try_lock() {
+            let holds_timebase = shared.timebase;
+            shared.local = local.filter(|_| !holds_timebase);
+            session = shared.session;
+        }
+        let tempo = |at: u64| {
+            session.map(|timeline: Timeline| Tempo {
+                beat: timeline.beat_at(at),
+                bpm: timeline.bpm,
+            })
+        };
+        // For the timebase callback, which positions the next cycle
+        timebase::publish(tempo(next));
+        let tempo = tempo(now);
+        let mut writer = clock_out.writer(ps);
+        clock.period(tempo, sample_rate, ps.n_frames(), |time, bytes| {
+            // A full port buffer drops the message, nothing better to do in here
+            let _ = writer.write(&RawMidi { time, bytes });
+        });
+        Control::Continue
+    };
+
+    let active_client = client
+        .activate_async(
+            (),
+            jack::contrib::ClosureProcessHandler::new(process_callback),
+        )
+        .expect("Failed to activate client");
+
+    let mut rules = Rules::load_default();
+    if let Some(pattern) = &connect {
+        if let Err(e) = rules.push(&output, &format!("/{}/", pattern)) {
+            eprintln!("Ignoring --connect {}", e.message);
+        }
+    }
+    let owner = active_client.as_client().name().to_string();
+    if let Err(e) = connections::spawn(owner, rules) {
+        eprintln!("Not connecting any ports: {}", e);
+    }
+
+    let mut link = Link::new(socket, group);
+    while !terminate.load(Ordering::Relaxed) {
+        link.poll(active_client.as_client(), &shared);
+    }
+
+    println!("Shutting down");
+    link.follow(active_client.as_client(), &shared, false);
+    active_client
+        .deactivate()
+        .expect("Failed to deactivate client");
+}
@@ -0,0 +1,173 @@
+//! TALA as the JACK timebase master while it follows another machine. The
+//! session's tempo and bar/beat go out as this machine's transport
+//! position, so a quantized start on any ANAHATA deck here waits for the
+//! session's beat, see `TransportSync::frames_to_master_beat`. Decks keep
+//! their own tempo, there is no pitch control to match the session's.
+
+use crate::clock::Tempo;
+use jack::Client;
+use jack_sys as j;
+use my_common::deck::{BarBeat, BEATS_PER_BAR, TICKS_PER_BEAT};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The session at the first frame of the next cycle as f64 bits, NaN while
+// nobody plays. Written by the process callback for the timebase callback
+// JACK runs right after it in the same thread.
+static SESSION_BPM: AtomicU64 = AtomicU64::new(f64::NAN.to_bits());
+static SESSION_BEAT: AtomicU64 = AtomicU64::new(f64::NAN.to_bits());
+
+pub fn publish(tempo: Option<Tempo>) {
+    let (bpm, beat) = tempo.map_or((f64::NAN, f64::NAN), |tempo| (tempo.bpm, tempo.beat));
+    SESSION_BPM.store(bpm.to_bits(), Ordering::Relaxed);
+    SESSION_BEAT.store(beat.to_bits(), Ordering::Relaxed);
+}
+
+/// Registers the timebase callback, failing if another client already is
+/// the master, such as a deck started with `--transport master`, rather
+/// than taking over from it.
+pub fn become_master(client: &Client) -> Result<(), String> {
+    // SAFETY: the client outlives the registration, `release` is called
+    // before it is deactivated
+    let result = unsafe {
+        j::jack_set_timebase_callback(client.raw(), 1, Some(timebase), std::ptr::null_mut())
+    };
+    match result {
+        0 => Ok(()),
+        libc::EBUSY => Err(String::from("another client is timebase master")),
+        e => Err(format!("error {}", e)),
+    }
+}
+
+pub fn release(client: &Client) {
+    // SAFETY: as above, the client is still alive
+    unsafe {
+        j::jack_release_timebase(client.raw());
+    }
+}
+
+// Called by JACK in the process thread, so atomics only
+unsafe extern "C" fn timebase(
+    _state: j::jack_transport_state_t,
+    _nframes: j::jack_nframes_t,
+    pos: *mut j::jack_position_t,
+    _new_pos: libc::c_int,
+    _arg: *mut libc::c_void,
+) {
+    // SAFETY: JACK passes the position it is about to publish
+    let pos = unsafe { &mut *pos };
+    let bpm = f64::from_bits(SESSION_BPM.load(Ordering::Relaxed));
+    let beat = f64::from_bits(SESSION_BEAT.load(Ordering::Relaxed));
+    match BarBeat::from_beat(beat).filter(|_| bpm.is_finite() && bpm > 0.0) {
+        Some(position) => {
+            pos.valid |= j::JackPositionBBT;
+            pos.bar = position.bar as i32;
+            pos.beat = position.beat as i32;
+            pos.tick = position.tick as i32;
+            pos.bar_start_tick = ((position.bar - 1) * BEATS_PER_BAR * TICKS_PER_BEAT) as f64;
+            pos.beats_per_bar = BEATS_PER_BAR as f32;
+            pos.beat_type = 4.0;
+            pos.ticks_per_beat = TICKS_PER_BEAT as f64;
+            pos.beats_per_minute = bpm;
+        }
+        None => pos.valid &= !j::JackPositionBBT,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::link::{self, Leader, Session, Timeline};
+    use anahata_engine::deck::Deck;
+    use my_common::deck;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const SECOND: u64 = 1_000_000;
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    #[test]
+    fn decks_start_on_the_leaders_beat() {
+        let group = SocketAddrV4::new(*link::DEFAULT_GROUP.ip(), 20810);
+        let (Ok(on_a), Ok(on_b)) = (
+            link::open(group, Ipv4Addr::LOCALHOST),
+            link::open(group, Ipv4Addr::LOCALHOST),
+        ) else {
+            eprintln!("No multicast on loopback here, skipping");
+            return;
+        };
+        on_b.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        // a leads at 120 BPM, b's clock reads 100 seconds less and nothing
+        // plays there yet
+        let b_time = |a_time: u64| a_time - 100 * SECOND;
+        let leading = Timeline {
+            bpm: 120.0,
+            beat: 8.0,
+            at: 100 * SECOND,
+        };
+        let mut a = Session::new(1);
+        a.set_local(Some(leading));
+        let mut b = Session::new(2);
+        on_a.send_to(&a.announce(103 * SECOND).unwrap(), group)
+            .unwrap();
+        let mut buf = [0; 512];
+        let (len, _) = on_b.recv_from(&mut buf).unwrap();
+        b.receive(&buf[..len], b_time(103 * SECOND));
+        let (leader, session) = b.leader().unwrap();
+        assert_eq!(Leader::Peer(1), leader);
+
+        // b's TALA publishes the session for a cycle starting off the beat
+        let cycle = b_time(103 * SECOND) + 123_456;
+        publish(Some(Tempo {
+            beat: session.beat_at(cycle),
+            bpm: session.bpm,
+        }));
+        // SAFETY: a position like the one JACK passes, zeroed is valid
+        let mut pos: j::jack_position_t = unsafe { std::mem::zeroed() };
+        // SAFETY: `pos` outlives the call
+        unsafe {
+            timebase(
+                j::JackTransportRolling,
+                1024,
+                &mut pos,
+                0,
+                std::ptr::null_mut(),
+            );
+        }
+        assert_ne!(0, pos.valid & j::JackPositionBBT);
+        // What a quantized start on b waits from the start of that cycle
+        let wait = deck::frames_to_next_beat(
+            pos.tick as f64 / pos.ticks_per_beat,
+            pos.beats_per_minute,
+            SAMPLE_RATE,
+        );
+
+        // A 120 BPM track on one of b's decks, stopped between beats and
+        // started quantized
+        let beat_times: Vec<f64> = (0..64).map(|beat| 0.25 + beat as f64 * 0.5).collect();
+        let mut b_deck = Deck::new(SAMPLE_RATE);
+        b_deck.load(Arc::new(vec![(0.0, 0.0); 30 * SAMPLE_RATE as usize]));
+        b_deck.seek(5 * SAMPLE_RATE as u64 + 1234);
+        assert!(b_deck.snap_to_beat(&beat_times));
+        b_deck.playing = true;
+        let started = cycle as f64 + wait as f64 * 1e6 / SAMPLE_RATE;
+
+        // Its beats fall on a's as it plays on, as near as JACK's ticks go
+        let mut out = vec![(0.0, 0.0); SAMPLE_RATE as usize / 2];
+        for step in 0..6 {
+            let b_now = started + step as f64 * 0.5e6;
+            let deck_beat =
+                deck::beat_at(&beat_times, b_deck.playhead() as f64 / SAMPLE_RATE).unwrap();
+            let a_beat = leading.beat_at((b_now + 100e6).round() as u64);
+            let phase = (deck_beat - a_beat).rem_euclid(1.0);
+            assert!(
+                phase.min(1.0 - phase) < 2.0 / TICKS_PER_BEAT as f64,
+                "deck beat {} against {}",
+                deck_beat,
+                a_beat
+            );
+            b_deck.render(&mut out);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::deck::Frame;
+use rtrb::Producer;
+
+/// Where a deck's frames go. The deck never waits on a backend: it asks how
+/// much room there is and writes at most that.
+pub trait AudioBackend {
+    fn sample_rate(&self) -> u32;
+
+    /// Frames that can be written right now.
+    fn available(&self) -> usize;
+
+    /// Queues frames for output and returns how many were taken.
+    fn write(&mut self, frames: &[Frame]) -> usize;
+
+    /// Frames written but not played yet, how far the deck's playhead is
+    /// ahead of what can be heard.
+    fn queued(&self) -> usize {
+        0
+    }
+}
+
+/// Feeds a ring buffer that a real-time callback, such as ANAHATA's JACK
+/// process handler, drains.
+pub struct RingBackend {
+    producer: Producer<Frame>,
+    sample_rate: u32,
+}
+
+impl RingBackend {
+    pub fn new(producer: Producer<Frame>, sample_rate: u32) -> Self {
+        Self {
+            producer,
+            sample_rate,
+        }
+    }
+}
+
+impl AudioBackend for RingBackend {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn available(&self) -> usize {
+        self.producer.slots()
+    }
+
+    fn write(&mut self, frames: &[Frame]) -> usize {
+        frames
+            .iter()
+            .take_while(|&&frame| self.producer.push(frame).is_ok())
+            .count()
+    }
+
+    fn queued(&self) -> usize {
+        self.producer.buffer().capacity() - self.producer.slots()
+    }
+}
+
+/// Collects everything in memory, for tests and offline tools.
+#[derive(Debug, Default)]
+pub struct BufferBackend {
+    pub frames: Vec<Frame>,
+    pub sample_rate: u32,
+}
+
+impl BufferBackend {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            frames: Vec::new(),
+            sample_rate,
+        }
+    }
+}
+
+impl AudioBackend for BufferBackend {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn available(&self) -> usize {
+        usize::MAX
+    }
+
+    fn write(&mut self, frames: &[Frame]) -> usize {
+        self.frames.extend_from_slice(frames);
+        frames.len()
+    }
+}
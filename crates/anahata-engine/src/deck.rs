@@ -0,0 +1,522 @@
+use crate::backend::AudioBackend;
+use my_common::deck::{self, EndOfTrack, Jump};
+use std::sync::Arc;
+
+pub type Frame = (f32, f32);
+
+/// Playback of one track with no audio backend or threads attached. The
+/// live deck pulls its frames from here for JACK and `--render` does the
+/// same for a whole set offline.
+pub struct Deck {
+    song: Arc<Vec<Frame>>,
+    sample_rate: f64,
+    playhead: u64,
+    // Between `playhead` and the next frame when not playing at 1.0
+    fraction: f64,
+    pub playing: bool,
+    /// Playback rate, 1.0 normally. Timecode vinyl sets anything including
+    /// backwards.
+    pub speed: f64,
+    pub end_of_track: EndOfTrack,
+    // A seek waiting for the playhead to reach a frame, (at, target)
+    scheduled: Option<(u64, u64)>,
+    // Where `loop_in` marked a loop to start, until `loop_out` closes it
+    loop_start: Option<u64>,
+    // The loop playback goes round, (start, end)
+    looping: Option<(u64, u64)>,
+}
+
+impl Deck {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            song: Arc::new(Vec::new()),
+            sample_rate,
+            playhead: 0,
+            fraction: 0.0,
+            playing: false,
+            speed: 1.0,
+            end_of_track: EndOfTrack::Stop,
+            scheduled: None,
+            loop_start: None,
+            looping: None,
+        }
+    }
+
+    /// Swaps in a new track, stopped at the start.
+    pub fn load(&mut self, song: Arc<Vec<Frame>>) {
+        self.song = song;
+        self.playhead = 0;
+        self.fraction = 0.0;
+        self.playing = false;
+        self.scheduled = None;
+        self.loop_start = None;
+        self.looping = None;
+    }
+
+    pub fn song(&self) -> &Arc<Vec<Frame>> {
+        &self.song
+    }
+
+    pub fn playhead(&self) -> u64 {
+        self.playhead
+    }
+
+    pub fn position_ms(&self) -> u64 {
+        (self.playhead as f64 * 1000.0 / self.sample_rate) as u64
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        (self.song.len() as f64 * 1000.0 / self.sample_rate) as u64
+    }
+
+    pub fn seek(&mut self, playhead: u64) {
+        self.playhead = playhead.min(self.song.len() as u64);
+        self.fraction = 0.0;
+        self.scheduled = None;
+    }
+
+    /// Seeks to `target` when playback reaches frame `at`, on that exact
+    /// frame of the output. Replaces any seek scheduled before, a seek in
+    /// the meantime cancels it.
+    pub fn seek_at(&mut self, at: u64, target: u64) {
+        self.scheduled = Some((at, target));
+    }
+
+    pub fn scheduled(&self) -> Option<(u64, u64)> {
+        self.scheduled
+    }
+
+    // Takes the scheduled seek once the playhead has reached it
+    fn run_scheduled(&mut self) {
+        if let Some((at, target)) = self.scheduled {
+            if self.playhead >= at {
+                self.seek(target);
+            }
+        }
+    }
+
+    /// False if a beat jump was asked for without a beatgrid.
+    pub fn jump(&mut self, jump: Jump, beat_times: &[f64]) -> bool {
+        match jump.target(self.playhead, self.sample_rate, beat_times) {
+            Some(target) => {
+                self.seek(target);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A beat jump that lands on the beatgrid, for a quantized deck. Playing,
+    /// it finishes the beat it is in and jumps on the frame the next one
+    /// starts. Stopped, it jumps to the beat nearest the target straight
+    /// away. False without a beatgrid.
+    pub fn quantized_jump(&mut self, jump: Jump, beat_times: &[f64]) -> bool {
+        let Some(from) = self.quantized_from(beat_times) else {
+            return false;
+        };
+        match jump.target(from, self.sample_rate, beat_times) {
+            Some(target) => self.land(from, target, beat_times),
+            None => false,
+        }
+    }
+
+    /// A seek to the beat nearest `target` for a quantized deck, timed like
+    /// `quantized_jump`. Waveform clicks and hot cues go through here.
+    pub fn quantized_seek(&mut self, target: u64, beat_times: &[f64]) -> bool {
+        match self.quantized_from(beat_times) {
+            Some(from) => self.land(from, target, beat_times),
+            None => false,
+        }
+    }
+
+    // Where a quantized seek leaves from, the next beat while playing
+    fn quantized_from(&self, beat_times: &[f64]) -> Option<u64> {
+        if self.playing {
+            let next = deck::next_beat(beat_times, self.seconds(self.playhead))?;
+            Some(self.frame(next))
+        } else {
+            Some(self.playhead)
+        }
+    }
+
+    fn land(&mut self, from: u64, target: u64, beat_times: &[f64]) -> bool {
+        let Some(target) = deck::nearest_beat(beat_times, self.seconds(target)) else {
+            return false;
+        };
+        if self.playing {
+            self.seek_at(from, self.frame(target));
+        } else {
+            self.seek(self.frame(target));
+        }
+        true
+    }
+
+    /// The frame of the beat nearest the playhead.
+    pub fn nearest_beat(&self, beat_times: &[f64]) -> Option<u64> {
+        deck::nearest_beat(beat_times, self.seconds(self.playhead)).map(|beat| self.frame(beat))
+    }
+
+    /// Moves the playhead to the nearest beat, where a quantized start
+    /// begins. False without a beatgrid, the deck starts where it is.
+    pub fn snap_to_beat(&mut self, beat_times: &[f64]) -> bool {
+        match self.nearest_beat(beat_times) {
+            Some(beat) => {
+                self.seek(beat);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The loop playback goes round, from its first frame up to but not
+    /// including the last.
+    pub fn loop_range(&self) -> Option<(u64, u64)> {
+        self.looping
+    }
+
+    /// Marks where a loop starts, playback carries on until `loop_out`.
+    pub fn loop_in(&mut self, at: u64) {
+        self.loop_start = Some(at.min(self.song.len() as u64));
+    }
+
+    /// Closes the loop at `at`, playback goes back to the start whenever it
+    /// reaches it. A playhead less than a loop past `at` goes round straight
+    /// away, one further out plays on. False without a loop in before `at`.
+    pub fn loop_out(&mut self, at: u64) -> bool {
+        let at = at.min(self.song.len() as u64);
+        let Some(start) = self.loop_start.filter(|&start| start < at) else {
+            return false;
+        };
+        self.looping = Some((start, at));
+        if self.playhead >= at && self.playhead < at + (at - start) {
+            self.playhead = start + (self.playhead - at);
+        }
+        true
+    }
+
+    /// `loop_in` on the beat nearest the playhead. False without a
+    /// beatgrid.
+    pub fn quantized_loop_in(&mut self, beat_times: &[f64]) -> bool {
+        match self.nearest_beat(beat_times) {
+            Some(beat) => {
+                self.loop_in(beat);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `loop_out` on a beat, the next one while playing so the loop closes
+    /// ahead of the playhead and the nearest while stopped.
+    pub fn quantized_loop_out(&mut self, beat_times: &[f64]) -> bool {
+        let end = if self.playing {
+            deck::next_beat(beat_times, self.seconds(self.playhead)).map(|beat| self.frame(beat))
+        } else {
+            self.nearest_beat(beat_times)
+        };
+        match end {
+            Some(end) => self.loop_out(end),
+            None => false,
+        }
+    }
+
+    /// Lets playback out of the loop, a new one needs a loop in again.
+    pub fn exit_loop(&mut self) {
+        self.loop_start = None;
+        self.looping = None;
+    }
+
+    // Goes back round once the playhead reaches the end of the loop
+    fn run_loop(&mut self) {
+        if let Some((start, end)) = self.looping {
+            if self.playhead == end {
+                self.playhead = start;
+            }
+        }
+    }
+
+    fn seconds(&self, frame: u64) -> f64 {
+        frame as f64 / self.sample_rate
+    }
+
+    fn frame(&self, seconds: f64) -> u64 {
+        (seconds.max(0.0) * self.sample_rate).round() as u64
+    }
+
+    /// Fills `out` from the playhead, silence once stopped. Returns true if
+    /// the end of the track was reached, in which case a looping deck has
+    /// gone back to the start and any other has stopped.
+    pub fn render(&mut self, out: &mut [Frame]) -> bool {
+        if self.speed != 1.0 || self.fraction != 0.0 {
+            return self.render_varispeed(out);
+        }
+        let mut ended = false;
+        let mut written = 0;
+        while self.playing && written < out.len() {
+            self.run_scheduled();
+            self.run_loop();
+            let start = self.playhead as usize;
+            if start >= self.song.len() {
+                ended = true;
+                if self.end_of_track == EndOfTrack::Loop && !self.song.is_empty() {
+                    self.playhead = 0;
+                } else {
+                    self.playing = false;
+                }
+                continue;
+            }
+            let mut count = (out.len() - written).min(self.song.len() - start);
+            if let Some((at, _)) = self.scheduled {
+                count = count.min((at - self.playhead) as usize);
+            }
+            if let Some((_, end)) = self.looping.filter(|&(_, end)| self.playhead < end) {
+                count = count.min((end - self.playhead) as usize);
+            }
+            out[written..written + count].copy_from_slice(&self.song[start..start + count]);
+            written += count;
+            self.playhead += count as u64;
+        }
+        out[written..].fill((0.0, 0.0));
+        ended
+    }
+
+    // Linear interpolation between frames, which is plenty for scratching
+    fn render_varispeed(&mut self, out: &mut [Frame]) -> bool {
+        let mut ended = false;
+        for frame in out.iter_mut() {
+            // Backwards the playhead never reaches `at`, which is fine
+            if self.playing {
+                self.run_scheduled();
+            }
+            let index = self.playhead as usize;
+            if !self.playing || index >= self.song.len() {
+                if self.playing {
+                    ended = true;
+                    if self.end_of_track == EndOfTrack::Loop && !self.song.is_empty() {
+                        self.playhead = 0;
+                        self.fraction = 0.0;
+                    } else {
+                        self.playing = false;
+                    }
+                }
+                *frame = (0.0, 0.0);
+                continue;
+            }
+            let (a, b) = (
+                self.song[index],
+                *self.song.get(index + 1).unwrap_or(&self.song[index]),
+            );
+            let t = self.fraction as f32;
+            *frame = (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+            // Backspinning past the start holds there
+            let mut position = (index as f64 + self.fraction + self.speed).max(0.0);
+            if let Some((start, end)) = self.looping {
+                if index < end as usize && position >= end as f64 {
+                    position -= (end - start) as f64;
+                }
+            }
+            self.playhead = position as u64;
+            self.fraction = position.fract();
+        }
+        ended
+    }
+
+    /// Renders a chunk into `backend` once it has room for all of it.
+    /// `None` means it had no room yet, otherwise what `render` returned.
+    pub fn pump(&mut self, backend: &mut impl AudioBackend, chunk: &mut [Frame]) -> Option<bool> {
+        if backend.available() < chunk.len() {
+            return None;
+        }
+        let ended = self.render(chunk);
+        backend.write(chunk);
+        Some(ended)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{BufferBackend, RingBackend};
+    use rtrb::RingBuffer;
+
+    #[test]
+    fn renders_to_the_end_of_the_track() {
+        let song: Vec<Frame> = (1..=6).map(|i| (i as f32, -(i as f32))).collect();
+        let mut deck = Deck::new(1000.0);
+        deck.load(Arc::new(song));
+        let mut out = [(9.0, 9.0); 4];
+
+        assert!(!deck.render(&mut out), "stopped decks only output silence");
+        assert_eq!([(0.0, 0.0); 4], out);
+
+        deck.playing = true;
+        assert!(!deck.render(&mut out));
+        assert_eq!((4.0, -4.0), out[3]);
+        assert!(deck.render(&mut out));
+        assert_eq!([(5.0, -5.0), (6.0, -6.0), (0.0, 0.0), (0.0, 0.0)], out);
+        assert!(!deck.playing);
+
+        deck.end_of_track = EndOfTrack::Loop;
+        deck.playing = true;
+        deck.seek(5);
+        assert!(deck.render(&mut out));
+        assert_eq!([(6.0, -6.0), (1.0, -1.0), (2.0, -2.0), (3.0, -3.0)], out);
+        assert!(deck.playing);
+        assert_eq!(3, deck.playhead());
+    }
+
+    #[test]
+    fn varispeed_both_ways() {
+        let song: Vec<Frame> = (1..=6).map(|i| (i as f32, 0.0)).collect();
+        let mut deck = Deck::new(1000.0);
+        deck.load(Arc::new(song));
+        deck.playing = true;
+        let mut out = [(9.0, 9.0); 4];
+
+        deck.speed = 0.5;
+        deck.render(&mut out);
+        assert_eq!([1.0, 1.5, 2.0, 2.5], out.map(|frame| frame.0));
+        deck.speed = -1.0;
+        deck.render(&mut out);
+        assert_eq!([3.0, 2.0, 1.0, 1.0], out.map(|frame| frame.0));
+        assert!(deck.playing, "held at the start, not stopped");
+    }
+
+    #[test]
+    fn scheduled_seeks_land_on_their_frame() {
+        let song: Vec<Frame> = (0..10).map(|i| (i as f32, 0.0)).collect();
+        let mut deck = Deck::new(1000.0);
+        deck.load(Arc::new(song));
+        deck.playing = true;
+        let mut out = [(9.0, 9.0); 4];
+
+        deck.seek_at(3, 8);
+        deck.render(&mut out);
+        assert_eq!([0.0, 1.0, 2.0, 8.0], out.map(|frame| frame.0));
+        assert_eq!(None, deck.scheduled());
+
+        deck.seek(0);
+        deck.seek_at(2, 6);
+        deck.speed = 0.5;
+        deck.render(&mut out);
+        assert_eq!([0.0, 0.5, 1.0, 1.5], out.map(|frame| frame.0));
+        deck.render(&mut out);
+        assert_eq!([6.0, 6.5, 7.0, 7.5], out.map(|frame| frame.0));
+
+        deck.seek_at(9, 0);
+        deck.seek(1);
+        assert_eq!(None, deck.scheduled(), "seeking cancels it");
+    }
+
+    #[test]
+    fn quantized_jumps_and_starts_land_on_beats() {
+        // A beat every 10 frames from frame 5
+        let beat_times = [0.005, 0.015, 0.025, 0.035, 0.045];
+        let mut deck = Deck::new(1000.0);
+        deck.load(Arc::new(vec![(0.0, 0.0); 100]));
+
+        deck.seek(12);
+        assert!(deck.snap_to_beat(&beat_times));
+        assert_eq!(15, deck.playhead());
+
+        // Stopped it goes straight to the beat nearest the target
+        deck.seek(17);
+        assert!(deck.quantized_jump(Jump::Seconds(0.011), &beat_times));
+        assert_eq!(25, deck.playhead());
+
+        // Playing it finishes the beat it is in
+        deck.seek(17);
+        deck.playing = true;
+        assert!(deck.quantized_jump(Jump::Beats(-1.0), &beat_times));
+        assert_eq!(Some((25, 15)), deck.scheduled());
+
+        assert!(!deck.snap_to_beat(&[]));
+        assert!(!deck.quantized_jump(Jump::Beats(1.0), &[]));
+
+        // Seeks land on the beat nearest where they were aimed
+        deck.playing = false;
+        assert!(deck.quantized_seek(48, &beat_times));
+        assert_eq!(45, deck.playhead());
+        deck.seek(17);
+        deck.playing = true;
+        assert!(deck.quantized_seek(3, &beat_times));
+        assert_eq!(Some((25, 5)), deck.scheduled());
+        assert!(!deck.quantized_seek(3, &[]));
+    }
+
+    #[test]
+    fn loops_go_round() {
+        let song: Vec<Frame> = (0..100).map(|i| (i as f32, 0.0)).collect();
+        let mut deck = Deck::new(1000.0);
+        deck.load(Arc::new(song));
+        deck.playing = true;
+        let mut out = [(9.0, 9.0); 4];
+
+        assert!(!deck.loop_out(4), "nothing to close without a loop in");
+        deck.seek(2);
+        deck.loop_in(2);
+        deck.seek(5);
+        assert!(deck.loop_out(5));
+        assert_eq!(Some((2, 5)), deck.loop_range());
+        deck.render(&mut out);
+        assert_eq!([2.0, 3.0, 4.0, 2.0], out.map(|frame| frame.0));
+
+        deck.speed = 0.5;
+        deck.render(&mut out);
+        assert_eq!([3.0, 3.5, 4.0, 4.5], out.map(|frame| frame.0));
+        deck.render(&mut out);
+        assert_eq!([2.0, 2.5, 3.0, 3.5], out.map(|frame| frame.0));
+
+        deck.exit_loop();
+        deck.speed = 1.0;
+        deck.seek(4);
+        deck.render(&mut out);
+        assert_eq!([4.0, 5.0, 6.0, 7.0], out.map(|frame| frame.0));
+
+        // A beat every 10 frames from frame 5, playing it closes on the next
+        let beat_times = [0.005, 0.015, 0.025, 0.035, 0.045];
+        deck.seek(13);
+        assert!(deck.quantized_loop_in(&beat_times));
+        deck.seek(31);
+        assert!(deck.quantized_loop_out(&beat_times));
+        assert_eq!(Some((15, 35)), deck.loop_range());
+
+        // Stopped just past the nearest beat, it goes round straight away
+        deck.exit_loop();
+        deck.playing = false;
+        deck.seek(14);
+        assert!(deck.quantized_loop_in(&beat_times));
+        deck.seek(37);
+        assert!(deck.quantized_loop_out(&beat_times));
+        assert_eq!(Some((15, 35)), deck.loop_range());
+        assert_eq!(17, deck.playhead());
+    }
+
+    #[test]
+    fn pumps_into_backends() {
+        let song: Vec<Frame> = (0..10).map(|i| (i as f32, 0.0)).collect();
+        let mut deck = Deck::new(1000.0);
+        deck.load(Arc::new(song.clone()));
+        deck.playing = true;
+        let mut chunk = [(0.0, 0.0); 4];
+
+        let mut buffer = BufferBackend::new(1000);
+        while deck.playing {
+            deck.pump(&mut buffer, &mut chunk);
+        }
+        assert_eq!(&song[..], &buffer.frames[..10]);
+        assert_eq!(12, buffer.frames.len());
+
+        // A full ring buffer leaves the playhead where it is
+        let (producer, mut consumer) = RingBuffer::new(6);
+        let mut ring = RingBackend::new(producer, 1000);
+        deck.seek(0);
+        deck.playing = true;
+        assert_eq!(Some(false), deck.pump(&mut ring, &mut chunk));
+        assert_eq!(None, deck.pump(&mut ring, &mut chunk));
+        assert_eq!(4, deck.playhead());
+        assert_eq!(4, ring.queued());
+        let queued: Vec<Frame> = std::iter::from_fn(|| consumer.pop().ok()).collect();
+        assert_eq!(&song[..4], &queued[..]);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::error::LoadError;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::{AudioBufferRef, Signal};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Just the audio, tags are read with `my_common::metadata` like the
+/// library does.
+#[derive(Debug)]
+pub struct DecodedTrack {
+    pub samples: Vec<(f32, f32)>,
+    pub sample_rate: u32,
+}
+
+// Where a packet starts and how long it is, in frames, and its audio
+type DecodedPacket = (u64, u64, Result<Vec<(f32, f32)>, SymphoniaError>);
+
+/// Decodes a stereo FLAC file to interleaved frames at its own sample rate.
+/// Corrupt frames come out as silence of the same length rather than failing
+/// the whole track, so everything after them stays where the beatgrid and
+/// the cues expect it.
+pub fn decode_flac_to_vec(path: &Path) -> Result<DecodedTrack, LoadError> {
+    let file = File::open(path).map_err(LoadError::Open)?;
+    let mmap = unsafe { Mmap::map(&file) }.map_err(LoadError::Open)?;
+    let mss = MediaSourceStream::new(Box::new(std::io::Cursor::new(mmap)), Default::default());
+
+    let mut hint = Hint::new();
+    hint.with_extension("flac");
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(LoadError::Probe)?;
+
+    let mut format = probed.format;
+
+    // Collect all packets first, a truncated file just ends early
+    let mut packets = Vec::new();
+    while let Ok(packet) = format.next_packet() {
+        packets.push(packet);
+    }
+    let track = format.default_track().ok_or(LoadError::NoTrack)?;
+    let codec_params = track.codec_params.clone();
+
+    let sample_rate = track.codec_params.sample_rate.unwrap_or(48000);
+    // The deck and decode_audio_buffer only deal in stereo
+    if let Some(channels) = codec_params.channels.map(|channels| channels.count()) {
+        if channels != 2 {
+            return Err(LoadError::Channels(channels));
+        }
+    }
+    // Fail once up front rather than on every worker
+    symphonia::default::get_codecs()
+        .make(&codec_params, &DecoderOptions::default())
+        .map_err(LoadError::Decoder)?;
+
+    // Process the collected packets in parallel, keeping where each one
+    // starts and how long it is for the ones that fail
+    let chunks: Vec<DecodedPacket> = packets
+        .into_par_iter()
+        .map_init(
+            || symphonia::default::get_codecs().make(&codec_params, &DecoderOptions::default()),
+            |decoder, packet| {
+                let samples = decoder
+                    .as_mut()
+                    .map_err(|_| SymphoniaError::Unsupported("codec"))
+                    .and_then(|decoder| {
+                        let mut samples = Vec::new();
+                        decode_audio_buffer(decoder.decode(&packet)?, &mut samples);
+                        Ok(samples)
+                    });
+                (packet.ts, packet.dur, samples)
+            },
+        )
+        .collect();
+
+    // A corrupt frame is silenced, anything else means the stream is unusable
+    let mut decoded_samples = Vec::with_capacity(
+        chunks
+            .last()
+            .map_or(0, |(start, duration, _)| (start + duration) as usize),
+    );
+    let mut silenced = 0;
+    let mut decoded_any = false;
+    for (start, duration, chunk) in chunks {
+        // The demuxer drops frames that fail their CRC, leaving a gap
+        if start as usize > decoded_samples.len() {
+            silenced += 1;
+            decoded_samples.resize(start as usize, (0.0, 0.0));
+        }
+        match chunk {
+            Ok(samples) => {
+                decoded_any |= !samples.is_empty();
+                decoded_samples.extend(samples);
+            }
+            Err(SymphoniaError::DecodeError(_)) => {
+                silenced += 1;
+                decoded_samples.resize(decoded_samples.len() + duration as usize, (0.0, 0.0));
+            }
+            Err(e) => return Err(LoadError::Decode(e)),
+        }
+    }
+    if silenced > 0 {
+        eprintln!("Silenced {} corrupt stretches in {}", silenced, path.display());
+    }
+    if !decoded_any {
+        return Err(LoadError::NoAudio);
+    }
+
+    println!("Decoded samples length: {}", decoded_samples.len());
+
+    Ok(DecodedTrack {
+        samples: decoded_samples,
+        sample_rate,
+    })
+}
+
+fn decode_audio_buffer(decoded: AudioBufferRef<'_>, decoded_samples: &mut Vec<(f32, f32)>) {
+    // All these guys should get the SIMD treatment too.
+    // otoh, who uses U24 samples?
+    match decoded {
+        AudioBufferRef::F32(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                decoded_samples.push((*left, *right));
+            }
+        }
+        AudioBufferRef::F64(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = *left as f32;
+                let right_sample = *right as f32;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::U16(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = *left as f32 / u16::MAX as f32;
+                let right_sample = *right as f32 / u16::MAX as f32;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::U32(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = *left as f32 / u32::MAX as f32;
+                let right_sample = *right as f32 / u32::MAX as f32;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::U8(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = (*left as f32 - 128.0) / 128.0;
+                let right_sample = (*right as f32 - 128.0) / 128.0;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::S8(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = *left as f32 / 128.0;
+                let right_sample = *right as f32 / 128.0;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::S16(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = *left as f32 / i16::MAX as f32;
+                let right_sample = *right as f32 / i16::MAX as f32;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::U24(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = (left.inner() as f32) / 8388607.0;
+                let right_sample = (right.inner() as f32) / 8388607.0;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+        AudioBufferRef::S24(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = (left.inner() as f32) / 8388607.0;
+                let right_sample = (right.inner() as f32) / 8388607.0;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+
+        AudioBufferRef::S32(buf) => {
+            for (left, right) in buf.chan(0).iter().zip(buf.chan(1).iter()) {
+                let left_sample = *left as f32 / i32::MAX as f32;
+                let right_sample = *right as f32 / i32::MAX as f32;
+                decoded_samples.push((left_sample, right_sample));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Written by testdata/generate.py
+    fn decode_fixture(name: &str) -> Result<DecodedTrack, LoadError> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name);
+        decode_flac_to_vec(&path)
+    }
+
+    #[test]
+    fn decodes_fixture() {
+        let track = decode_fixture("tone.flac").unwrap();
+        assert_eq!(4 * 576, track.samples.len());
+        assert_eq!(48000, track.sample_rate);
+    }
+
+    #[test]
+    fn rejects_unreadable_files() {
+        assert!(matches!(
+            decode_fixture("missing.flac"),
+            Err(LoadError::Open(_))
+        ));
+        assert!(matches!(
+            decode_fixture("garbage.flac"),
+            Err(LoadError::Probe(_))
+        ));
+        assert!(matches!(
+            decode_fixture("bad_streaminfo.flac"),
+            Err(LoadError::Probe(_))
+        ));
+        assert!(matches!(
+            decode_fixture("mono.flac"),
+            Err(LoadError::Channels(1))
+        ));
+    }
+
+    #[test]
+    fn keeps_what_survives_of_damaged_files() {
+        let truncated = decode_fixture("truncated.flac").unwrap();
+        assert_eq!(2 * 576, truncated.samples.len());
+        // Corrupt frames keep their length, later audio stays in place
+        let corrupt = decode_fixture("corrupt_frames.flac").unwrap();
+        let clean = decode_fixture("tone.flac").unwrap();
+        assert_eq!(clean.samples.len(), corrupt.samples.len());
+        assert_eq!(clean.samples[..576], corrupt.samples[..576]);
+        assert!(corrupt.samples[576..3 * 576]
+            .iter()
+            .all(|frame| *frame == (0.0, 0.0)));
+        assert_eq!(clean.samples[3 * 576..], corrupt.samples[3 * 576..]);
+    }
+}
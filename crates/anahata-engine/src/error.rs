@@ -0,0 +1,48 @@
+use std::fmt;
+use std::io;
+use symphonia::core::errors::Error as SymphoniaError;
+
+/// Why a track could not be loaded. The deck keeps playing whatever it had
+/// before and reports this on `anahata.N.error`.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The deck is playing and the load lock is on
+    Locked,
+    Open(io::Error),
+    Probe(SymphoniaError),
+    NoTrack,
+    /// Only stereo files can be played
+    Channels(usize),
+    Decoder(SymphoniaError),
+    Decode(SymphoniaError),
+    /// Every packet failed to decode or the file had none
+    NoAudio,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Locked => write!(f, "deck is playing and load lock is on"),
+            LoadError::Open(e) => write!(f, "cannot open file: {}", e),
+            LoadError::Probe(e) => write!(f, "not a readable FLAC file: {}", e),
+            LoadError::NoTrack => write!(f, "file has no audio track"),
+            LoadError::Channels(n) => write!(f, "{} channels, only stereo is supported", n),
+            LoadError::Decoder(e) => write!(f, "unsupported stream: {}", e),
+            LoadError::Decode(e) => write!(f, "decoding failed: {}", e),
+            LoadError::NoAudio => write!(f, "file contains no decodable audio"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Open(e) => Some(e),
+            LoadError::Probe(e) | LoadError::Decoder(e) | LoadError::Decode(e) => Some(e),
+            LoadError::Locked
+            | LoadError::NoTrack
+            | LoadError::Channels(_)
+            | LoadError::NoAudio => None,
+        }
+    }
+}
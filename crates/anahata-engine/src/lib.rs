@@ -0,0 +1,30 @@
+//! ANAHATA's audio side without its window, NATS or JACK: decoding,
+//! analysis, a deck that plays a track into any `AudioBackend`, and the
+//! offline renderer for SMRITI event logs.
+//!
+//! ```no_run
+//! use anahata_engine::backend::BufferBackend;
+//! use anahata_engine::deck::Deck;
+//! use anahata_engine::decode::decode_flac_to_vec;
+//! use std::sync::Arc;
+//!
+//! let track = decode_flac_to_vec("track.flac".as_ref()).unwrap();
+//! let mut deck = Deck::new(track.sample_rate as f64);
+//! deck.load(Arc::new(track.samples));
+//! deck.playing = true;
+//! let mut out = BufferBackend::new(track.sample_rate);
+//! let mut chunk = vec![(0.0, 0.0); 1024];
+//! while deck.playing {
+//!     deck.pump(&mut out, &mut chunk);
+//! }
+//! ```
+
+pub mod backend;
+pub mod deck;
+pub mod decode;
+pub mod error;
+pub mod render;
+pub mod timecode;
+pub mod track;
+
+pub use my_common::waveform::{generate_detail, generate_waveform};
@@ -0,0 +1,432 @@
+use crate::deck::{Deck, Frame};
+use crate::track::load_track;
+use my_common::analysis::AnalysisCache;
+use my_common::deck::{DeckState, EndOfTrack, Jump};
+use my_common::events::{self, LoggedEvent};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const SAMPLE_RATE: f64 = 48000.0;
+const BLOCK_SIZE: u64 = 4096;
+
+pub struct RenderOptions {
+    pub skip: Jump,
+    pub nudge: Jump,
+    /// Xone fader id to the deck whose level it sets
+    pub faders: HashMap<u8, u32>,
+}
+
+/// A decoded track with its beats and hot cues
+#[derive(Clone)]
+struct Track {
+    song: Arc<Vec<Frame>>,
+    beat_times: Vec<f64>,
+    cues: Vec<u64>,
+}
+
+struct RenderDeck {
+    deck: Deck,
+    path: Option<PathBuf>,
+    beat_times: Vec<f64>,
+    /// Hot cues in frames, in the order they were set
+    cues: Vec<u64>,
+    gain: f32,
+    load_lock: bool,
+    /// Skips and starts snap to the beatgrid
+    quantize: bool,
+    /// Hit the end in `next` mode, AKASHA's `select.next` answer plays
+    play_next: bool,
+    skip: Jump,
+    nudge: Jump,
+}
+
+impl RenderDeck {
+    // Waveform clicks and hot cues, timed like a skip when quantizing
+    fn seek(&mut self, target: u64) {
+        if self.quantize && self.beat_times.len() >= 2 {
+            self.deck.quantized_seek(target, &self.beat_times);
+        } else {
+            self.deck.seek(target);
+        }
+    }
+}
+
+struct Renderer<'a> {
+    options: &'a RenderOptions,
+    cache: Option<AnalysisCache>,
+    /// Sets often come back to a track
+    tracks: HashMap<PathBuf, Track>,
+    decks: BTreeMap<u32, RenderDeck>,
+}
+
+impl Renderer<'_> {
+    fn deck(&mut self, number: u32) -> &mut RenderDeck {
+        let options = self.options;
+        self.decks.entry(number).or_insert_with(|| RenderDeck {
+            deck: Deck::new(SAMPLE_RATE),
+            path: None,
+            beat_times: Vec::new(),
+            cues: Vec::new(),
+            gain: 1.0,
+            load_lock: false,
+            quantize: false,
+            play_next: false,
+            skip: options.skip,
+            nudge: options.nudge,
+        })
+    }
+
+    /// Whether the track is on the deck now
+    fn load(&mut self, number: u32, path: PathBuf) -> bool {
+        let track = match self.tracks.get(&path) {
+            Some(track) => Some(track.clone()),
+            None => match load_track(&path, self.cache.as_ref()) {
+                Ok(loaded) => {
+                    let (beat_times, cues) = loaded
+                        .cached
+                        .map(|a| (a.beat_times, a.cues))
+                        .unwrap_or_default();
+                    let track = Track {
+                        song: Arc::new(loaded.decoded.samples),
+                        beat_times,
+                        cues,
+                    };
+                    self.tracks.insert(path.clone(), track.clone());
+                    Some(track)
+                }
+                Err(e) => {
+                    // Same as live, the deck keeps what it had
+                    eprintln!("Failed to load {}: {}", path.display(), e);
+                    None
+                }
+            },
+        };
+        if let Some(track) = track {
+            let deck = self.deck(number);
+            deck.deck.load(track.song);
+            deck.beat_times = track.beat_times;
+            deck.cues = track.cues;
+            deck.path = Some(path);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn apply(&mut self, event: &LoggedEvent) {
+        let payload = event.payload.as_str();
+        match event.subject.as_str() {
+            "drishti.result" => self.apply_beatgrid(payload),
+            "xone.fader" => {
+                let fader = payload
+                    .split_once(',')
+                    .and_then(|(id, value)| Some((id.parse().ok()?, value.parse().ok()?)));
+                if let Some((id, value)) = fader {
+                    if let Some(&number) = self.options.faders.get(&id) {
+                        self.deck(number).gain = value;
+                    }
+                }
+            }
+            subject => {
+                let Some((number, control)) = subject
+                    .strip_prefix("anahata.")
+                    .and_then(|rest| rest.split_once('.'))
+                    .and_then(|(number, control)| Some((number.parse().ok()?, control)))
+                else {
+                    return;
+                };
+                self.apply_deck(number, control, payload);
+            }
+        }
+    }
+
+    fn apply_deck(&mut self, number: u32, control: &str, payload: &str) {
+        // Payloads that aren't a jump mean the default, as on a live deck
+        let jump = Jump::parse(payload);
+        match control {
+            "state" => {
+                let Ok(state) = serde_json::from_str::<DeckState>(payload) else {
+                    return;
+                };
+                if let Some(path) = state.path {
+                    self.load(number, PathBuf::from(path));
+                }
+                let deck = self.deck(number);
+                deck.deck
+                    .seek((state.position_ms as f64 * SAMPLE_RATE / 1000.0) as u64);
+                deck.deck.playing = state.playing;
+                deck.deck.end_of_track = state.end_of_track;
+                deck.load_lock = state.load_lock;
+                deck.quantize = state.quantize;
+                deck.deck.exit_loop();
+                if let Some((start, end)) = state.loop_range {
+                    deck.deck.loop_in(start);
+                    deck.deck.loop_out(end);
+                }
+            }
+            "select" | "select.force" => {
+                let deck = self.deck(number);
+                // Whatever AKASHA answers next no longer plays by itself
+                deck.play_next = false;
+                if deck.load_lock && deck.deck.playing && control == "select" {
+                    return;
+                }
+                self.load(number, PathBuf::from(payload));
+            }
+            "select.next" => {
+                let deck = self.deck(number);
+                if !std::mem::take(&mut deck.play_next) || (deck.load_lock && deck.deck.playing) {
+                    return;
+                }
+                if self.load(number, PathBuf::from(payload)) {
+                    self.deck(number).deck.playing = true;
+                }
+            }
+            "seek" => {
+                if let Ok(position_ms) = payload.trim().parse::<u64>() {
+                    let frame = (position_ms as f64 * SAMPLE_RATE / 1000.0) as u64;
+                    self.deck(number).seek(frame);
+                }
+            }
+            "cue" => {
+                let deck = self.deck(number);
+                let cue = payload.trim().parse::<usize>().ok();
+                if let Some(&target) = cue.and_then(|cue| deck.cues.get(cue.checked_sub(1)?)) {
+                    deck.seek(target);
+                }
+            }
+            "cue.set" => {
+                let deck = self.deck(number);
+                let at = deck
+                    .deck
+                    .nearest_beat(&deck.beat_times)
+                    .filter(|_| deck.quantize)
+                    .unwrap_or(deck.deck.playhead());
+                deck.cues.push(at);
+                // Kept with the track like the analysis cache does live
+                let (path, cues) = (deck.path.clone(), deck.cues.clone());
+                if let Some(track) = path.and_then(|path| self.tracks.get_mut(&path)) {
+                    track.cues = cues;
+                }
+            }
+            "loopin" => {
+                let deck = self.deck(number);
+                if !(deck.quantize && deck.deck.quantized_loop_in(&deck.beat_times)) {
+                    deck.deck.loop_in(deck.deck.playhead());
+                }
+            }
+            "loopout" => {
+                let deck = self.deck(number);
+                if deck.quantize && deck.beat_times.len() >= 2 {
+                    deck.deck.quantized_loop_out(&deck.beat_times);
+                } else {
+                    deck.deck.loop_out(deck.deck.playhead());
+                }
+            }
+            "loopexit" => self.deck(number).deck.exit_loop(),
+            "stop" => {
+                let deck = self.deck(number);
+                // No JACK master to wait for, a quantized start is on the
+                // nearest beat
+                if !deck.deck.playing && deck.quantize {
+                    deck.deck.snap_to_beat(&deck.beat_times);
+                }
+                deck.deck.playing = !deck.deck.playing;
+            }
+            "endoftrack" => {
+                if let Some(mode) = EndOfTrack::parse(payload) {
+                    self.deck(number).deck.end_of_track = mode;
+                }
+            }
+            "loadlock" => self.deck(number).load_lock = payload != "0",
+            "quantize" => self.deck(number).quantize = payload != "0",
+            "skip.default" => {
+                if let Some(jump) = jump {
+                    self.deck(number).skip = jump;
+                }
+            }
+            "nudge.default" => {
+                if let Some(jump) = jump {
+                    self.deck(number).nudge = jump;
+                }
+            }
+            "skipforward" | "skipbackward" | "nudgeforward" | "nudgebackward" => {
+                let deck = self.deck(number);
+                let default = if control.starts_with("skip") {
+                    deck.skip
+                } else {
+                    deck.nudge
+                };
+                let jump = jump.unwrap_or(default);
+                let jump = if control.ends_with("backward") {
+                    jump.reversed()
+                } else {
+                    jump
+                };
+                // Nudges are never snapped
+                if deck.quantize && control.starts_with("skip") && deck.beat_times.len() >= 2 {
+                    deck.deck.quantized_jump(jump, &deck.beat_times);
+                } else {
+                    deck.deck.jump(jump, &deck.beat_times);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_beatgrid(&mut self, payload: &str) {
+        let Ok(v) = serde_json::from_str::<serde_json::Value>(payload) else {
+            return;
+        };
+        let beat_times: Vec<f64> = v["beat_times"]
+            .as_array()
+            .map(|beats| beats.iter().filter_map(|b| b.as_f64()).collect())
+            .unwrap_or_default();
+        // Results without a path are for whatever every deck has loaded
+        let path = v["path"].as_str().map(PathBuf::from);
+        for deck in self.decks.values_mut() {
+            if path.is_none() || path == deck.path {
+                deck.beat_times = beat_times.clone();
+            }
+        }
+    }
+
+    fn mix(&mut self, out: &mut [Frame], buffer: &mut [Frame]) {
+        out.fill((0.0, 0.0));
+        for deck in self.decks.values_mut() {
+            let ended = deck.deck.render(buffer);
+            if ended && !deck.deck.playing && deck.deck.end_of_track == EndOfTrack::Next {
+                deck.play_next = true;
+            }
+            for (mixed, (l, r)) in out.iter_mut().zip(buffer.iter()) {
+                mixed.0 += l * deck.gain;
+                mixed.1 += r * deck.gain;
+            }
+        }
+    }
+}
+
+/// Plays an event log back through fresh decks and writes the sum as a
+/// 32 bit float WAV, so nothing clips. Runs up to the last event, which
+/// for a SMRITI log is the end of the recording. Clicks and drags on a
+/// deck's waveform go out as `anahata.N.seek`, so they are in the log too.
+pub fn render(log: &Path, out: &Path, options: &RenderOptions) -> Result<(), Box<dyn Error>> {
+    let events = events::read_log(BufReader::new(File::open(log)?))?;
+    let out_file = BufWriter::new(File::create(out)?);
+    let cache = AnalysisCache::open_default().ok();
+    let rendered = render_events(&events, out_file, options, cache)?;
+    println!(
+        "Rendered {:.1}s from {} events to {}",
+        rendered as f64 / SAMPLE_RATE,
+        events.len(),
+        out.display()
+    );
+    Ok(())
+}
+
+// The frames rendered
+fn render_events<W: Write + Seek>(
+    events: &[LoggedEvent],
+    out: W,
+    options: &RenderOptions,
+    cache: Option<AnalysisCache>,
+) -> Result<u64, hound::Error> {
+    let mut renderer = Renderer {
+        options,
+        cache,
+        tracks: HashMap::new(),
+        decks: BTreeMap::new(),
+    };
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: SAMPLE_RATE as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::new(out, spec)?;
+
+    let mut mix = vec![(0.0, 0.0); BLOCK_SIZE as usize];
+    let mut buffer = mix.clone();
+    let mut rendered = 0u64;
+    for event in events {
+        let until = (event.time.max(0.0) * SAMPLE_RATE).round() as u64;
+        while rendered < until {
+            let count = (until - rendered).min(BLOCK_SIZE) as usize;
+            renderer.mix(&mut mix[..count], &mut buffer[..count]);
+            for &(l, r) in &mix[..count] {
+                writer.write_sample(l)?;
+                writer.write_sample(r)?;
+            }
+            rendered += count as u64;
+        }
+        renderer.apply(event);
+    }
+    writer.finalize()?;
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::decode_flac_to_vec;
+    use std::io::Cursor;
+
+    #[test]
+    fn renders_a_logged_set() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("tone.flac");
+        let event = |time: f64, subject: &str, payload: &str| LoggedEvent {
+            time,
+            subject: subject.to_string(),
+            payload: payload.to_string(),
+        };
+        // The tone is 48ms long
+        let events = [
+            event(0.0, "anahata.1.select", &path.display().to_string()),
+            event(0.005, "xone.fader", "7,0.5"),
+            event(0.01, "anahata.1.stop", ""),
+            event(0.02, "anahata.1.seek", "5"),
+            event(0.02, "anahata.1.loopin", ""),
+            event(0.025, "anahata.1.loopout", ""),
+            event(0.03, "anahata.1.stop", ""),
+        ];
+        let options = RenderOptions {
+            skip: Jump::Seconds(1.0),
+            nudge: Jump::Seconds(0.1),
+            faders: HashMap::from([(7, 1)]),
+        };
+
+        let mut wav = Cursor::new(Vec::new());
+        assert_eq!(
+            1440,
+            render_events(&events, &mut wav, &options, None).unwrap()
+        );
+        wav.set_position(0);
+        let samples: Vec<f32> = hound::WavReader::new(wav)
+            .unwrap()
+            .samples::<f32>()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(2 * 1440, samples.len());
+
+        let song = decode_flac_to_vec(&path).unwrap().samples;
+        let left = |frame: usize| samples[2 * frame];
+        assert!(
+            (0..480).all(|frame| left(frame) == 0.0),
+            "silent until play"
+        );
+        assert!((480..960).any(|frame| left(frame) != 0.0));
+        assert_eq!(
+            song[0].0 * 0.5,
+            left(480),
+            "played from the start at the fader's level"
+        );
+        assert_eq!(song[240].0 * 0.5, left(960), "seeked to 5ms");
+        assert_eq!(song[240].0 * 0.5, left(1200), "looped back to 5ms");
+    }
+}
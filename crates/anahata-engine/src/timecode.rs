@@ -0,0 +1,562 @@
+//! Timecode vinyl: decoding the control signal a DVS record plays into
+//! needle position, speed and direction, and generating one for tests.
+//!
+//! The records carry a sine carrier on both channels a quarter cycle apart,
+//! which channel leads giving the direction and the carrier's frequency the
+//! speed. Each cycle's amplitude is one bit of a maximum length LFSR
+//! sequence, so any run of `bits` cycles says where on the record the needle
+//! is. Sequence parameters, which channel is read, its phase and polarity
+//! are the ones xwax uses for the commercial records, and the tests check
+//! the decoder against a transcription of xwax's. None of it has been tried
+//! with a recording of a real record yet, the Traktor ones in particular.
+
+use crate::deck::Frame;
+use std::io::Read;
+
+pub struct TimecodeDef {
+    pub name: &'static str,
+    /// Carrier cycles per second at normal speed
+    pub resolution: f64,
+    pub bits: u32,
+    pub seed: u32,
+    pub taps: u32,
+    /// Cycles from the start of the sequence to the end of the side
+    pub length: u32,
+    /// The carrier that's read for bits is on the left channel rather than
+    /// the right, xwax's SWITCH_PRIMARY
+    pub primary_left: bool,
+    /// Bits are read at the primary's negative peak rather than its
+    /// positive one, SWITCH_POLARITY
+    pub negative_bits: bool,
+    /// Going forwards the secondary leads the primary by a quarter cycle
+    /// rather than lagging it, SWITCH_PHASE
+    pub secondary_leads: bool,
+}
+
+pub const DEFINITIONS: [TimecodeDef; 4] = [
+    TimecodeDef {
+        name: "serato_2a",
+        resolution: 1000.0,
+        bits: 20,
+        seed: 0x59017,
+        taps: 0x361e4,
+        length: 712_000,
+        primary_left: false,
+        negative_bits: false,
+        secondary_leads: false,
+    },
+    TimecodeDef {
+        name: "serato_2b",
+        resolution: 1000.0,
+        bits: 20,
+        seed: 0x8f3c6,
+        taps: 0x4f0d8,
+        length: 922_000,
+        primary_left: false,
+        negative_bits: false,
+        secondary_leads: false,
+    },
+    TimecodeDef {
+        name: "traktor_a",
+        resolution: 2000.0,
+        bits: 23,
+        seed: 0x134503,
+        taps: 0x041040,
+        length: 1_500_000,
+        primary_left: true,
+        negative_bits: true,
+        secondary_leads: true,
+    },
+    TimecodeDef {
+        name: "traktor_b",
+        resolution: 2000.0,
+        bits: 23,
+        seed: 0x32066c,
+        taps: 0x041040,
+        length: 2_110_000,
+        primary_left: true,
+        negative_bits: true,
+        secondary_leads: true,
+    },
+];
+
+pub fn definition(name: &str) -> Option<&'static TimecodeDef> {
+    DEFINITIONS.iter().find(|def| def.name == name)
+}
+
+// The carrier has to clear this before a zero crossing counts, so noise
+// around silence doesn't read as motion
+const HYSTERESIS: f32 = 0.01;
+// Per sample weight of the DC estimate taken out of each channel
+const DC_WEIGHT: f32 = 0.001;
+// Per bit weight of the level that tells ones from zeros
+const LEVEL_WEIGHT: f32 = 1.0 / 64.0;
+// Per half cycle weight of the speed estimate
+const SPEED_WEIGHT: f64 = 0.25;
+// No crossing for this long means the record has stopped
+const STOPPED_SECONDS: f64 = 0.05;
+
+fn parity(value: u32) -> u32 {
+    value.count_ones() & 1
+}
+
+impl TimecodeDef {
+    fn mask(&self) -> u32 {
+        (1 << self.bits) - 1
+    }
+
+    /// The state one cycle on, the new bit going in at the top.
+    fn forward(&self, state: u32) -> u32 {
+        (state >> 1) | (parity(state & (self.taps | 1)) << (self.bits - 1))
+    }
+
+    /// The state one cycle back, the old bit coming back in at the bottom.
+    fn backward(&self, state: u32) -> u32 {
+        let bit = parity(state & ((self.taps >> 1) | (1 << (self.bits - 1))));
+        ((state << 1) & self.mask()) | bit
+    }
+}
+
+/// Where the needle is, as far as the signal says.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// Seconds from the start of the side at normal speed, `None` until
+    /// enough clean bits have been read
+    pub position: Option<f64>,
+    /// 1.0 is normal speed, negative is backwards
+    pub speed: f64,
+}
+
+pub struct Decoder {
+    def: &'static TimecodeDef,
+    sample_rate: f64,
+    /// Cycle number of every sequence state, `u32::MAX` for states the
+    /// record never has
+    positions: Vec<u32>,
+    dc: (f32, f32),
+    primary_positive: bool,
+    secondary_positive: bool,
+    forward: bool,
+    since_crossing: u64,
+    speed: f64,
+    level: f32,
+    state: u32,
+    /// Bits in a row that matched the sequence's prediction
+    valid: u32,
+}
+
+impl Decoder {
+    /// Builds the position table up front, for Traktor records 32 MiB.
+    pub fn new(def: &'static TimecodeDef, sample_rate: f64) -> Self {
+        let mut positions = vec![u32::MAX; 1 << def.bits];
+        let mut state = def.seed;
+        for cycle in 0..def.length {
+            positions[state as usize] = cycle;
+            state = def.forward(state);
+        }
+        Self {
+            def,
+            sample_rate,
+            positions,
+            dc: (0.0, 0.0),
+            primary_positive: false,
+            secondary_positive: false,
+            forward: true,
+            since_crossing: 0,
+            speed: 0.0,
+            level: 0.0,
+            state: 0,
+            valid: 0,
+        }
+    }
+
+    pub fn process(&mut self, left: &[f32], right: &[f32]) {
+        for (&left, &right) in left.iter().zip(right) {
+            let (primary, secondary) = if self.def.primary_left {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            self.sample(primary, secondary);
+        }
+    }
+
+    fn sample(&mut self, primary: f32, secondary: f32) {
+        self.dc.0 += (primary - self.dc.0) * DC_WEIGHT;
+        self.dc.1 += (secondary - self.dc.1) * DC_WEIGHT;
+        let (primary, secondary) = (primary - self.dc.0, secondary - self.dc.1);
+        let crossed = |value: f32, positive: bool| {
+            if positive {
+                value < -HYSTERESIS
+            } else {
+                value > HYSTERESIS
+            }
+        };
+
+        self.since_crossing += 1;
+        if crossed(primary, self.primary_positive) {
+            self.primary_positive = !self.primary_positive;
+            // Going forwards the secondary lags, so it's still on the side
+            // the primary has just left
+            self.forward =
+                (self.primary_positive != self.secondary_positive) != self.def.secondary_leads;
+            let cycles = self.sample_rate / (2 * self.since_crossing) as f64;
+            let speed = cycles / self.def.resolution * if self.forward { 1.0 } else { -1.0 };
+            self.speed += (speed - self.speed) * SPEED_WEIGHT;
+            self.since_crossing = 0;
+        }
+        if crossed(secondary, self.secondary_positive) {
+            self.secondary_positive = !self.secondary_positive;
+            self.forward =
+                (self.primary_positive == self.secondary_positive) != self.def.secondary_leads;
+            // Once a cycle, at a peak of the primary
+            if self.primary_positive != self.def.negative_bits {
+                self.bit(primary.abs());
+            }
+        }
+        if self.since_crossing as f64 > STOPPED_SECONDS * self.sample_rate {
+            self.speed = 0.0;
+        }
+    }
+
+    fn bit(&mut self, amplitude: f32) {
+        let one = amplitude > self.level;
+        self.level += (amplitude - self.level) * LEVEL_WEIGHT;
+        let predicted = if self.forward {
+            self.def.forward(self.state)
+        } else {
+            self.def.backward(self.state)
+        };
+        self.state = if self.forward {
+            (self.state >> 1) | ((one as u32) << (self.def.bits - 1))
+        } else {
+            ((self.state << 1) & self.def.mask()) | one as u32
+        };
+        self.valid = if predicted == self.state {
+            self.valid + 1
+        } else {
+            0
+        };
+    }
+
+    pub fn reading(&self) -> Reading {
+        let position = (self.valid >= self.def.bits)
+            .then(|| self.positions[self.state as usize])
+            .filter(|&cycle| cycle != u32::MAX)
+            .and_then(|cycle| {
+                // A cycle's bit is the top one of its state, backwards the
+                // newest bit is the bottom one
+                if self.forward {
+                    Some(cycle)
+                } else {
+                    cycle.checked_sub(self.def.bits - 1)
+                }
+            })
+            .map(|cycle| cycle as f64 / self.def.resolution);
+        Reading {
+            position,
+            speed: self.speed,
+        }
+    }
+}
+
+/// Plays a timecode record, for tests and for checking a setup without one.
+pub struct Generator {
+    def: &'static TimecodeDef,
+    sample_rate: f64,
+    /// Carrier cycles from the start of the sequence
+    phase: f64,
+    cycle: i64,
+    state: u32,
+}
+
+impl Generator {
+    pub fn new(def: &'static TimecodeDef, sample_rate: f64, position: f64) -> Self {
+        let mut generator = Self {
+            def,
+            sample_rate,
+            phase: position * def.resolution,
+            cycle: 0,
+            state: def.seed,
+        };
+        generator.seek_cycle(generator.phase.floor() as i64);
+        generator
+    }
+
+    fn seek_cycle(&mut self, cycle: i64) {
+        while self.cycle < cycle {
+            self.state = self.def.forward(self.state);
+            self.cycle += 1;
+        }
+        while self.cycle > cycle {
+            self.state = self.def.backward(self.state);
+            self.cycle -= 1;
+        }
+    }
+
+    /// Fills `out` with the needle moving at `speed`.
+    pub fn generate(&mut self, speed: f64, out: &mut [Frame]) {
+        for frame in out {
+            self.seek_cycle(self.phase.floor() as i64);
+            let one = self.state >> (self.def.bits - 1) == 1;
+            let amplitude = if one { 0.5 } else { 0.375 };
+            let angle = self.phase * std::f64::consts::TAU;
+            let primary = (angle.sin() * amplitude) as f32;
+            let secondary = if self.def.secondary_leads {
+                angle.cos() * amplitude
+            } else {
+                -angle.cos() * amplitude
+            } as f32;
+            *frame = if self.def.primary_left {
+                (primary, secondary)
+            } else {
+                (secondary, primary)
+            };
+            self.phase += speed * self.def.resolution / self.sample_rate;
+        }
+    }
+}
+
+/// Decodes a recording of a timecode record, one reading every `interval`
+/// seconds with the time it was taken.
+pub fn decode_wav(
+    reader: impl Read,
+    def: &'static TimecodeDef,
+    interval: f64,
+) -> Result<Vec<(f64, Reading)>, hound::Error> {
+    let mut wav = hound::WavReader::new(reader)?;
+    let spec = wav.spec();
+    if spec.channels != 2 {
+        return Err(hound::Error::Unsupported);
+    }
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => wav.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            wav.samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let sample_rate = spec.sample_rate as f64;
+    let mut decoder = Decoder::new(def, sample_rate);
+    let step = ((interval * sample_rate) as usize).max(1);
+    let mut readings = Vec::new();
+    for (index, block) in samples.chunks(step * 2).enumerate() {
+        let (left, right): (Vec<f32>, Vec<f32>) =
+            block.chunks_exact(2).map(|pair| (pair[0], pair[1])).unzip();
+        decoder.process(&left, &right);
+        let time = ((index + 1) * step) as f64 / sample_rate;
+        readings.push((time, decoder.reading()));
+    }
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn wav(frames: &[Frame]) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE as u32,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut out, spec).unwrap();
+        for &(left, right) in frames {
+            writer.write_sample((left * 32767.0) as i16).unwrap();
+            writer.write_sample((right * 32767.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn sequence_runs_both_ways() {
+        for def in &DEFINITIONS {
+            let state = def.forward(def.forward(def.seed));
+            assert_eq!(def.seed, def.backward(def.backward(state)), "{}", def.name);
+        }
+    }
+
+    #[test]
+    fn decodes_a_generated_recording() {
+        let def = definition("serato_2a").unwrap();
+        let mut generator = Generator::new(def, SAMPLE_RATE, 60.0);
+        let mut frames = vec![(0.0, 0.0); SAMPLE_RATE as usize];
+        // A second forwards, half a second backwards at half speed, then
+        // a stop
+        generator.generate(1.0, &mut frames);
+        let mut backwards = vec![(0.0, 0.0); SAMPLE_RATE as usize / 2];
+        generator.generate(-0.5, &mut backwards);
+        frames.extend(backwards);
+        frames.extend(vec![(0.0, 0.0); SAMPLE_RATE as usize / 10]);
+
+        let readings = decode_wav(Cursor::new(wav(&frames)), def, 0.1).unwrap();
+        let at = |time: f64| {
+            readings
+                .iter()
+                .find(|(at, _)| (at - time).abs() < 1e-6)
+                .unwrap()
+                .1
+        };
+        let forwards = at(1.0);
+        assert!((forwards.speed - 1.0).abs() < 0.01, "{:?}", forwards);
+        assert!(
+            (forwards.position.unwrap() - 61.0).abs() < 0.01,
+            "{:?}",
+            forwards
+        );
+        let backwards = at(1.5);
+        assert!((backwards.speed + 0.5).abs() < 0.01, "{:?}", backwards);
+        assert!(
+            (backwards.position.unwrap() - 60.75).abs() < 0.01,
+            "{:?}",
+            backwards
+        );
+        assert_eq!(0.0, at(1.6).speed);
+    }
+
+    // xwax's timecoder.c process_sample() and process_bitstream(), cut
+    // down to what decides direction and bits and with its integer maths in
+    // floats. Zero tracking is left out, the generator has no DC.
+    struct Xwax {
+        def: &'static TimecodeDef,
+        primary_positive: bool,
+        secondary_positive: bool,
+        forwards: bool,
+        bitstream: u32,
+        timecode: u32,
+        valid_counter: u32,
+        ref_level: f32,
+    }
+
+    impl Xwax {
+        const THRESHOLD: f32 = 0.01;
+        const REF_PEAKS_AVG: f32 = 48.0;
+
+        fn new(def: &'static TimecodeDef) -> Self {
+            Self {
+                def,
+                primary_positive: false,
+                secondary_positive: false,
+                forwards: true,
+                bitstream: 0,
+                timecode: 0,
+                valid_counter: 0,
+                ref_level: 0.0,
+            }
+        }
+
+        fn lfsr(code: u32, taps: u32) -> u32 {
+            let mut taken = code & taps;
+            let mut xrs = 0;
+            while taken != 0 {
+                xrs += taken & 1;
+                taken >>= 1;
+            }
+            xrs & 1
+        }
+
+        fn submit(&mut self, frames: &[Frame]) {
+            let bits = self.def.bits;
+            for &(left, right) in frames {
+                let (primary, secondary) = if self.def.primary_left {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                let swap = |v: f32, positive: &mut bool| {
+                    let swapped =
+                        (v > Self::THRESHOLD && !*positive) || (v < -Self::THRESHOLD && *positive);
+                    if swapped {
+                        *positive = !*positive;
+                    }
+                    swapped
+                };
+                let primary_swapped = swap(primary, &mut self.primary_positive);
+                let secondary_swapped = swap(secondary, &mut self.secondary_positive);
+
+                if primary_swapped || secondary_swapped {
+                    let mut forwards = if primary_swapped {
+                        self.primary_positive != self.secondary_positive
+                    } else {
+                        self.primary_positive == self.secondary_positive
+                    };
+                    if self.def.secondary_leads {
+                        forwards = !forwards;
+                    }
+                    if forwards != self.forwards {
+                        self.forwards = forwards;
+                        self.valid_counter = 0;
+                    }
+                }
+
+                if secondary_swapped && self.primary_positive != self.def.negative_bits {
+                    let m = primary.abs();
+                    let b = (m > self.ref_level) as u32;
+                    let mask = (1 << bits) - 1;
+                    if self.forwards {
+                        let l = Self::lfsr(self.timecode, self.def.taps | 1);
+                        self.timecode = (self.timecode >> 1) | (l << (bits - 1));
+                        self.bitstream = (self.bitstream >> 1) + (b << (bits - 1));
+                    } else {
+                        let l = Self::lfsr(self.timecode, (self.def.taps >> 1) | (1 << (bits - 1)));
+                        self.timecode = ((self.timecode << 1) & mask) | l;
+                        self.bitstream = ((self.bitstream << 1) & mask) + b;
+                    }
+                    if self.timecode == self.bitstream {
+                        self.valid_counter += 1;
+                    } else {
+                        self.timecode = self.bitstream;
+                        self.valid_counter = 0;
+                    }
+                    self.ref_level -= self.ref_level / Self::REF_PEAKS_AVG;
+                    self.ref_level += m / Self::REF_PEAKS_AVG;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_records_like_xwax() {
+        for def in &DEFINITIONS {
+            let mut generator = Generator::new(def, SAMPLE_RATE, 30.0);
+            let mut decoder = Decoder::new(def, SAMPLE_RATE);
+            let mut xwax = Xwax::new(def);
+            for speed in [1.0, -0.5] {
+                let mut frames = vec![(0.0, 0.0); SAMPLE_RATE as usize / 2];
+                generator.generate(speed, &mut frames);
+                decoder.process(
+                    &frames.iter().map(|frame| frame.0).collect::<Vec<_>>(),
+                    &frames.iter().map(|frame| frame.1).collect::<Vec<_>>(),
+                );
+                xwax.submit(&frames);
+
+                assert_eq!(xwax.forwards, decoder.forward, "{} at {}", def.name, speed);
+                assert_eq!(xwax.bitstream, decoder.state, "{} at {}", def.name, speed);
+                assert!(xwax.valid_counter > def.bits, "{} at {}", def.name, speed);
+                // xwax looks the bitstream up as is, which backwards is
+                // `bits` - 1 cycles ahead of the newest bit
+                let looked_up = decoder.positions[xwax.bitstream as usize] as f64;
+                let behind = if speed > 0.0 {
+                    0.0
+                } else {
+                    (def.bits - 1) as f64
+                };
+                assert_eq!(
+                    Some((looked_up - behind) / def.resolution),
+                    decoder.reading().position,
+                    "{} at {}",
+                    def.name,
+                    speed
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::decode::{decode_flac_to_vec, DecodedTrack};
+use crate::error::LoadError;
+use my_common::analysis::{Analysis, AnalysisCache};
+use my_common::key::{self, Key};
+use my_common::metadata::TrackMetadata;
+use my_common::waveform::{generate_detail, generate_waveform};
+use rayon::prelude::*;
+use std::path::Path;
+
+/// A decoded track, its tags and its cached analysis, if the cache had one.
+#[derive(Debug)]
+pub struct LoadedTrack {
+    pub hash: Option<String>,
+    pub cached: Option<Analysis>,
+    pub metadata: Option<TrackMetadata>,
+    pub decoded: DecodedTrack,
+}
+
+pub fn load_track(path: &Path, cache: Option<&AnalysisCache>) -> Result<LoadedTrack, LoadError> {
+    let hash = cache.and_then(|cache| match cache.hash_file(path) {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            eprintln!("Failed to hash {}: {}", path.display(), e);
+            None
+        }
+    });
+    let cached = cache
+        .zip(hash.as_ref())
+        .and_then(|(cache, hash)| cache.load(hash));
+    let decoded = decode_flac_to_vec(path)?;
+    Ok(LoadedTrack {
+        hash,
+        cached,
+        metadata: TrackMetadata::read(path),
+        decoded,
+    })
+}
+
+/// The analysis of a track from the cache, or worked out and cached now.
+/// The library runs this while scanning so keys show up before any deck has
+/// loaded the track, and the deck that does finds the analysis waiting.
+pub fn analyse_file(path: &Path, cache: &AnalysisCache) -> Result<Analysis, LoadError> {
+    let hash = cache.hash_file(path).map_err(LoadError::Open)?;
+    let mut stale = match cache.load(&hash) {
+        Some(analysis) if analysis.is_current() => return Ok(analysis),
+        stale => stale.unwrap_or_default(),
+    };
+    let decoded = decode_flac_to_vec(path)?;
+    let tagged_key = TrackMetadata::read(path).and_then(|metadata| metadata.key);
+    let mut analysis = analyse(&decoded.samples, tagged_key, decoded.sample_rate as f32);
+    analysis.keep_external(&mut stale);
+    // A deck may have loaded it meanwhile, with a beatgrid from DRISHTI
+    if let Some(stored) = cache.load(&hash).filter(Analysis::is_current) {
+        return Ok(stored);
+    }
+    if let Err(e) = cache.store(&hash, &analysis) {
+        eprintln!("Failed to cache analysis of {}: {}", path.display(), e);
+    }
+    Ok(analysis)
+}
+
+/// Waveforms, key and loudness. Beats come from DRISHTI.
+pub fn analyse(song: &[(f32, f32)], tagged_key: Option<Key>, sample_rate: f32) -> Analysis {
+    let sum_squares: f64 = song
+        .par_iter()
+        .map(|(l, r)| (*l as f64 * *l as f64 + *r as f64 * *r as f64) * 0.5)
+        .sum();
+    let loudness_db = (!song.is_empty())
+        .then(|| 10.0 * (sum_squares / song.len() as f64).max(1e-12).log10() as f32);
+
+    Analysis {
+        waveform: generate_waveform(song, 20000, sample_rate as f64),
+        detail: generate_detail(song, sample_rate as f64),
+        // Trust the tag if there is one, it is usually better than our guess
+        key: tagged_key.or_else(|| detect_key(song, sample_rate)),
+        loudness_db,
+        ..Default::default()
+    }
+}
+
+// Chroma only cares about pitches below ~2kHz, so work at a quarter of the rate
+fn detect_key(song: &[(f32, f32)], sample_rate: f32) -> Option<Key> {
+    const DECIMATION: usize = 4;
+    const BLOCK: usize = 8192 * 16;
+
+    let mono: Vec<f32> = song
+        .par_chunks(DECIMATION)
+        .map(|frames| frames.iter().map(|(l, r)| (l + r) * 0.5).sum::<f32>() / frames.len() as f32)
+        .collect();
+
+    let chroma = mono
+        .par_chunks(BLOCK)
+        .map(|block| key::chroma(block, sample_rate / DECIMATION as f32))
+        .reduce(
+            || [0.0; 12],
+            |mut acc, c| {
+                acc.iter_mut().zip(c).for_each(|(a, c)| *a += c);
+                acc
+            },
+        );
+
+    Key::from_chroma(&chroma)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn loads_tags_with_the_audio() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("tone.flac");
+        let loaded = load_track(&path, None).unwrap();
+        let metadata = loaded.metadata.unwrap();
+        assert_eq!(Some("Tone"), metadata.title.as_deref());
+        assert_eq!(Some("Fixture"), metadata.artist.as_deref());
+        assert_eq!(Key::parse("8A"), metadata.key);
+        assert_eq!(48000, loaded.decoded.sample_rate);
+    }
+
+    #[test]
+    fn analyses_files_once() {
+        let dir = std::env::temp_dir().join(format!("analyse-file-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = AnalysisCache::open(&dir).unwrap();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("tone.flac");
+
+        let analysis = analyse_file(&path, &cache).unwrap();
+        assert_eq!(Key::parse("8A"), analysis.key, "the tag wins");
+        assert!(!analysis.detail.is_empty());
+        let cached = cache.lookup(&path).unwrap();
+        assert!(cached.is_current());
+        assert_eq!(analysis.detail.len(), cached.detail.len());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,106 @@
+#!/usr/bin/env python3
+"""Writes the FLAC fixtures used by the ANAHATA decoder tests.
+
+Frames are stored verbatim (no prediction) so no encoder is needed. Run from
+this directory; the output is deterministic.
+"""
+import math
+import random
+import struct
+
+SAMPLE_RATE = 48000
+BLOCK_SIZE = 576
+FRAMES = 4
+
+
+def crc8(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x07) & 0xFF if crc & 0x80 else (crc << 1) & 0xFF
+    return crc
+
+
+def crc16(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x8005) & 0xFFFF if crc & 0x8000 else (crc << 1) & 0xFFFF
+    return crc
+
+
+def block_header(last, kind, length):
+    return bytes([(0x80 if last else 0) | kind]) + length.to_bytes(3, "big")
+
+
+def streaminfo(channels, total_samples, frame_size):
+    bits = SAMPLE_RATE << 44 | (channels - 1) << 41 | (16 - 1) << 36 | total_samples
+    return (
+        struct.pack(">HH", BLOCK_SIZE, BLOCK_SIZE)
+        + frame_size.to_bytes(3, "big") * 2
+        + bits.to_bytes(8, "big")
+        + bytes(16)
+    )
+
+
+def vorbis_comment(tags):
+    vendor = b"k2-midi fixtures"
+    data = struct.pack("<I", len(vendor)) + vendor + struct.pack("<I", len(tags))
+    for tag in tags:
+        data += struct.pack("<I", len(tag)) + tag
+    return data
+
+
+def frame(number, channels):
+    # Fixed blocking, 576 samples, 48kHz, independent channels, 16 bit
+    header = bytes([0xFF, 0xF8, 0x2A, (channels - 1) << 4 | 0x08, number])
+    header += bytes([crc8(header)])
+    body = b""
+    for channel in range(channels):
+        body += b"\x02"  # verbatim subframe, no wasted bits
+        for i in range(BLOCK_SIZE):
+            t = (number * BLOCK_SIZE + i) / SAMPLE_RATE
+            sample = int(16000 * math.sin(2 * math.pi * 440 * (channel + 1) * t))
+            body += struct.pack(">h", sample)
+    data = header + body
+    return data + struct.pack(">H", crc16(data))
+
+
+def flac(channels, tags):
+    frames = [frame(n, channels) for n in range(FRAMES)]
+    comment = vorbis_comment(tags)
+    return (
+        b"fLaC"
+        + block_header(False, 0, 34)
+        + streaminfo(channels, BLOCK_SIZE * FRAMES, len(frames[0]))
+        + block_header(True, 4, len(comment))
+        + comment
+        + b"".join(frames)
+    )
+
+
+def write(name, data):
+    with open(name, "wb") as f:
+        f.write(data)
+
+
+tags = [b"TITLE=Tone", b"ARTIST=Fixture", b"INITIALKEY=8A"]
+tone = flac(2, tags)
+audio_start = len(tone) - sum(len(frame(n, 2)) for n in range(FRAMES))
+frame_len = len(frame(0, 2))
+
+write("tone.flac", tone)
+write("mono.flac", flac(1, tags))
+# Cut off halfway through the third frame
+write("truncated.flac", tone[: audio_start + frame_len * 2 + frame_len // 2])
+# The middle frames have a broken sample, failing their CRC, the last one
+# is intact and must still land after them
+corrupt = bytearray(tone)
+for n in range(1, FRAMES - 1):
+    corrupt[audio_start + frame_len * n + 100] ^= 0xFF
+write("corrupt_frames.flac", bytes(corrupt))
+# Stream header promises 34 bytes of STREAMINFO and stops after 10
+write("bad_streaminfo.flac", tone[:18])
+write("garbage.flac", random.Random(1).randbytes(4096))
@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use my_common::waveform::generate_waveform;
+use std::f32::consts::PI;
+
+const SAMPLE_RATE: f32 = 48000.0;
+
+// Ten minutes of a kick, a bassline, a chord and some noise, roughly the
+// spread of energy a real track has across the three bands
+fn ten_minute_track() -> Vec<(f32, f32)> {
+    let mut seed = 0x2545f491u32;
+    (0..(SAMPLE_RATE * 600.0) as usize)
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE;
+            let beat = t * 2.0 % 1.0;
+            let kick = (2.0 * PI * 55.0 * t).sin() * (-beat * 12.0).exp();
+            let bass = (2.0 * PI * 110.0 * t).sin() * 0.3;
+            let chord = [440.0, 554.37, 659.25]
+                .iter()
+                .map(|f| (2.0 * PI * f * t).sin())
+                .sum::<f32>()
+                * 0.1;
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let noise = (seed as f32 / u32::MAX as f32 - 0.5) * 0.05;
+            let mono = kick + bass + chord;
+            (mono + noise, mono - noise)
+        })
+        .collect()
+}
+
+fn waveform(c: &mut Criterion) {
+    let song = ten_minute_track();
+    let mut group = c.benchmark_group("generate_waveform");
+    group.sample_size(10);
+    group.bench_function("10 minutes, 20000 bins", |b| {
+        b.iter(|| generate_waveform(&song, 20000, SAMPLE_RATE as f64))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, waveform);
+criterion_main!(benches);
@@ -0,0 +1,275 @@
+use crate::key::Key;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FrequencyBand {
+    pub rms_left: f32,
+    pub rms_right: f32,
+    pub peak_left: f32,
+    pub peak_right: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WaveformBin {
+    pub low: FrequencyBand,  // 20-200Hz (sub bass and bass)
+    pub mid: FrequencyBand,  // 200-3000Hz (kicks, snares, vocals)
+    pub high: FrequencyBand, // 3000-20000Hz (hi-hats, cymbals, air)
+}
+
+/// Bump whenever an analysis algorithm changes so stale results get redone.
+/// The beatgrid and cues come from outside and survive a bump.
+pub const ANALYSIS_VERSION: u32 = 2;
+
+/// Everything we know about a track that is expensive to work out again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analysis {
+    #[serde(default)]
+    pub version: u32,
+    pub waveform: Vec<WaveformBin>,
+    /// Fixed size bins for the zoomable view, see `waveform::DETAIL_SAMPLES_PER_BIN`
+    #[serde(default)]
+    pub detail: Vec<WaveformBin>,
+    pub bpm: Option<f64>,
+    /// Beat positions in seconds from the start of the track
+    pub beat_times: Vec<f64>,
+    pub key: Option<Key>,
+    /// Mean RMS level over the whole track in dBFS
+    pub loudness_db: Option<f32>,
+    /// Cue points as sample positions
+    pub cues: Vec<u64>,
+}
+
+impl Default for Analysis {
+    fn default() -> Self {
+        Self {
+            version: ANALYSIS_VERSION,
+            waveform: Vec::new(),
+            detail: Vec::new(),
+            bpm: None,
+            beat_times: Vec::new(),
+            key: None,
+            loudness_db: None,
+            cues: Vec::new(),
+        }
+    }
+}
+
+impl Analysis {
+    /// False for what an older `ANALYSIS_VERSION` left, which only has the
+    /// beatgrid and cues and needs the rest worked out again.
+    pub fn is_current(&self) -> bool {
+        self.version == ANALYSIS_VERSION
+    }
+
+    /// Takes over the beatgrid and cues from `other`, we can't work those
+    /// out again.
+    pub fn keep_external(&mut self, other: &mut Analysis) {
+        if other.bpm.is_some() {
+            self.bpm = other.bpm;
+            self.beat_times = std::mem::take(&mut other.beat_times);
+        }
+        if !other.cues.is_empty() {
+            self.cues = std::mem::take(&mut other.cues);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    len: u64,
+    modified: u64,
+    hash: String,
+}
+
+/// On disk analysis results, one file per track keyed by the hash of the
+/// file contents. A path index remembers the last hash seen for a path so
+/// that readers which cannot afford to hash (the library) can still find
+/// results, as long as size and mtime have not changed.
+pub struct AnalysisCache {
+    dir: PathBuf,
+    index: Mutex<HashMap<PathBuf, IndexEntry>>,
+}
+
+impl AnalysisCache {
+    /// Opens the cache in `$XDG_CACHE_HOME/k2-midi/analysis`, falling back
+    /// to `~/.cache`.
+    pub fn open_default() -> io::Result<Self> {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cache directory"))?;
+        Self::open(base.join("k2-midi").join("analysis"))
+    }
+
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let index = read_index(&dir);
+        Ok(Self {
+            dir,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Hashes the file and records the hash in the path index.
+    pub fn hash_file(&self, path: &Path) -> io::Result<String> {
+        let (len, modified) = file_stamp(path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let hash = hasher.finalize().to_hex().to_string();
+
+        // Several decks share the index, pick up what they wrote since we opened it
+        let mut index = self.index.lock().unwrap();
+        index.extend(read_index(&self.dir));
+        index.insert(
+            canonical(path),
+            IndexEntry {
+                len,
+                modified,
+                hash: hash.clone(),
+            },
+        );
+        let entries: Vec<_> = index.iter().collect();
+        write_atomic(&self.dir.join("index.json"), &serde_json::to_vec(&entries)?)?;
+        Ok(hash)
+    }
+
+    /// Results from an older `ANALYSIS_VERSION` come back with only the
+    /// beatgrid and cues, see `Analysis::is_current`.
+    pub fn load(&self, hash: &str) -> Option<Analysis> {
+        let data = fs::read(self.entry_path(hash)).ok()?;
+        let analysis = serde_json::from_slice::<Analysis>(&data).ok()?;
+        if analysis.is_current() {
+            return Some(analysis);
+        }
+        Some(Analysis {
+            version: analysis.version,
+            bpm: analysis.bpm,
+            beat_times: analysis.beat_times,
+            cues: analysis.cues,
+            ..Default::default()
+        })
+    }
+
+    pub fn store(&self, hash: &str, analysis: &Analysis) -> io::Result<()> {
+        write_atomic(&self.entry_path(hash), &serde_json::to_vec(analysis)?)
+    }
+
+    /// Looks up a track by path without reading it. Returns nothing if the
+    /// file changed since it was last hashed.
+    pub fn lookup(&self, path: &Path) -> Option<Analysis> {
+        let (len, modified) = file_stamp(path).ok()?;
+        let hash = {
+            let index = self.index.lock().unwrap();
+            let entry = index.get(&canonical(path))?;
+            if entry.len != len || entry.modified != modified {
+                return None;
+            }
+            entry.hash.clone()
+        };
+        self.load(&hash)
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", hash))
+    }
+}
+
+fn read_index(dir: &Path) -> HashMap<PathBuf, IndexEntry> {
+    fs::read(dir.join("index.json"))
+        .ok()
+        .and_then(|data| serde_json::from_slice::<Vec<(PathBuf, IndexEntry)>>(&data).ok())
+        .map(|entries| entries.into_iter().collect())
+        .unwrap_or_default()
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn file_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let modified = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((meta.len(), modified))
+}
+
+// Both ANAHATA and AKASHA read these, never let them see half a file
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip_and_invalidation() {
+        let dir = std::env::temp_dir().join(format!("analysis-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = AnalysisCache::open(dir.join("cache")).unwrap();
+
+        let track = dir.join("track.flac");
+        fs::write(&track, b"not really a flac").unwrap();
+        let hash = cache.hash_file(&track).unwrap();
+        let analysis = Analysis {
+            bpm: Some(174.0),
+            waveform: vec![WaveformBin::default(); 3],
+            ..Default::default()
+        };
+        cache.store(&hash, &analysis).unwrap();
+
+        let reopened = AnalysisCache::open(dir.join("cache")).unwrap();
+        let found = reopened.lookup(&track).unwrap();
+        assert_eq!(Some(174.0), found.bpm);
+        assert_eq!(3, found.waveform.len());
+
+        // Different contents and size, the old entry must not be returned
+        fs::write(&track, b"a different, longer file").unwrap();
+        assert!(reopened.lookup(&track).is_none());
+        assert_ne!(hash, reopened.hash_file(&track).unwrap());
+
+        // An older version keeps the beatgrid DRISHTI gave it and nothing else
+        let stale = Analysis {
+            version: ANALYSIS_VERSION - 1,
+            bpm: Some(128.0),
+            beat_times: vec![0.5, 0.96875],
+            key: Key::parse("8A"),
+            waveform: vec![WaveformBin::default(); 3],
+            ..Default::default()
+        };
+        reopened.store(&hash, &stale).unwrap();
+        let found = reopened.load(&hash).unwrap();
+        assert!(!found.is_current());
+        assert_eq!(Some(128.0), found.bpm);
+        assert_eq!(vec![0.5, 0.96875], found.beat_times);
+        assert_eq!(None, found.key);
+        assert!(found.waveform.is_empty());
+
+        let mut fresh = Analysis::default();
+        fresh.keep_external(&mut found.clone());
+        assert!(fresh.is_current());
+        assert_eq!(found.beat_times, fresh.beat_times);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
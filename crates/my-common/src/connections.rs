@@ -0,0 +1,275 @@
+//! Which JACK ports get connected to which, for every JACK client in the
+//! workspace. Rules live in `$XDG_CONFIG_HOME/k2-midi/connections` (or
+//! `~/.config`), one per line:
+//!
+//! ```text
+//! # <output> -> <input>, globs unless written /like this/ as a regex
+//! ANAHATA*:out_left -> system:playback_1
+//! /^a2j:XONE:K2 \[\d+\] \(capture\)/ -> XoneK2-midi:midi_in
+//! ```
+//!
+//! Without the file `DEFAULT_RULES` apply, an empty file connects nothing.
+//! Each program only makes the connections that involve its own ports.
+
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// What the rig is wired as when there is no rules file.
+pub const DEFAULT_RULES: &str = "\
+# Decks to the first two outputs of the sound card and into the recorder
+ANAHATA*:out_left -> system:playback_1
+ANAHATA*:out_right -> system:playback_2
+ANAHATA*:out_left -> SMRITI:master_left
+ANAHATA*:out_right -> SMRITI:master_right
+# The controller, through a2jmidid or the ALSA MIDI bridge
+*XONE:K2*capture* -> XoneK2-midi:midi_in
+XoneK2-midi:midi_out -> *XONE:K2*playback*
+";
+
+#[derive(Debug)]
+pub struct RuleError {
+    /// From 1, 0 for rules added in code
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+// Globs match the whole port name, regexes anywhere in it unless anchored
+fn pattern(text: &str) -> Result<Regex, regex::Error> {
+    if let Some(regex) = text
+        .strip_prefix('/')
+        .and_then(|text| text.strip_suffix('/'))
+    {
+        return Regex::new(regex);
+    }
+    let mut regex = String::from("^");
+    for c in text.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex)
+}
+
+struct Rule {
+    output: Regex,
+    input: Regex,
+}
+
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn parse(text: &str) -> Result<Self, RuleError> {
+        let mut rules = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((output, input)) = line.split_once("->") else {
+                return Err(RuleError {
+                    line: i + 1,
+                    message: String::from("expected <output> -> <input>"),
+                });
+            };
+            rules
+                .push(output.trim(), input.trim())
+                .map_err(|e| RuleError { line: i + 1, ..e })?;
+        }
+        Ok(rules)
+    }
+
+    /// The rules file, or the defaults if there isn't one. A file that
+    /// doesn't parse connects nothing rather than something unexpected.
+    pub fn load_default() -> Self {
+        let path = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .map(|config| config.join("k2-midi").join("connections"));
+        let text = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(text)) => text,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("Not connecting any ports, could not read rules: {}", e);
+                return Self::default();
+            }
+            _ => DEFAULT_RULES.to_string(),
+        };
+        Self::parse(&text).unwrap_or_else(|e| {
+            eprintln!("Not connecting any ports, bad connection rule on {}", e);
+            Self::default()
+        })
+    }
+
+    /// Adds a rule, the patterns written as in the file.
+    pub fn push(&mut self, output: &str, input: &str) -> Result<(), RuleError> {
+        let compile = |text: &str| {
+            pattern(text).map_err(|e| RuleError {
+                line: 0,
+                message: format!("{}: {}", text, e),
+            })
+        };
+        self.rules.push(Rule {
+            output: compile(output)?,
+            input: compile(input)?,
+        });
+        Ok(())
+    }
+
+    /// Every output and input pair some rule matches where either end is a
+    /// port of the client named `owner`.
+    pub fn connections<'a>(
+        &self,
+        owner: &str,
+        outputs: &'a [String],
+        inputs: &'a [String],
+    ) -> Vec<(&'a str, &'a str)> {
+        let prefix = format!("{}:", owner);
+        let mut connections = Vec::new();
+        for output in outputs {
+            for input in inputs {
+                let owned = output.starts_with(&prefix) || input.starts_with(&prefix);
+                let wanted = self
+                    .rules
+                    .iter()
+                    .any(|rule| rule.output.is_match(output) && rule.input.is_match(input));
+                if owned && wanted {
+                    connections.push((output.as_str(), input.as_str()));
+                }
+            }
+        }
+        connections
+    }
+}
+
+#[cfg(feature = "jack")]
+pub use self::watch::spawn;
+
+#[cfg(feature = "jack")]
+mod watch {
+    use super::Rules;
+    use jack::{Client, ClientOptions, PortFlags, PortId};
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    // Clients register their ports one after another, catch them all at once
+    const SETTLE: Duration = Duration::from_millis(100);
+
+    struct PortWatcher {
+        changed: Sender<()>,
+    }
+
+    // Connecting from inside a notification isn't allowed, hand it off
+    impl jack::NotificationHandler for PortWatcher {
+        fn port_registration(&mut self, _: &Client, _: PortId, is_registered: bool) {
+            if is_registered {
+                let _ = self.changed.send(());
+            }
+        }
+    }
+
+    fn apply(client: &Client, owner: &str, rules: &Rules) {
+        // JACK won't connect audio to MIDI, only pair ports of one type
+        for port_type in ["audio", "midi"] {
+            let outputs = client.ports(None, Some(port_type), PortFlags::IS_OUTPUT);
+            let inputs = client.ports(None, Some(port_type), PortFlags::IS_INPUT);
+            for (output, input) in rules.connections(owner, &outputs, &inputs) {
+                let Some(port) = client.port_by_name(output) else {
+                    continue;
+                };
+                if port.is_connected_to(input).unwrap_or(true) {
+                    continue;
+                }
+                match client.connect_ports_by_name(output, input) {
+                    Ok(()) => println!("Connected {} to {}", output, input),
+                    Err(e) => eprintln!("Failed to connect {} to {}: {}", output, input, e),
+                }
+            }
+        }
+    }
+
+    /// Connects the ports of the client named `owner` by `rules` now and
+    /// again whenever a port appears, from a JACK client of its own. Call it
+    /// once `owner` is active, JACK only connects ports of active clients.
+    pub fn spawn(owner: String, rules: Rules) -> Result<(), jack::Error> {
+        let (client, _status) = Client::new(
+            &format!("{}-connect", owner),
+            ClientOptions::NO_START_SERVER,
+        )?;
+        let (changed, ports_changed) = channel();
+        let active_client = client.activate_async(PortWatcher { changed }, ())?;
+        thread::spawn(move || {
+            apply(active_client.as_client(), &owner, &rules);
+            while ports_changed.recv().is_ok() {
+                thread::sleep(SETTLE);
+                while ports_changed.try_recv().is_ok() {}
+                apply(active_client.as_client(), &owner, &rules);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn rules_pick_connections_for_their_owner() {
+        let rules = Rules::parse(DEFAULT_RULES).unwrap();
+        let outputs = names(&[
+            "ANAHATA:out_left",
+            "ANAHATA-01:out_left",
+            "ANAHATA:out_right",
+            "Midi-Bridge:XONE:K2 5:(capture_0) XONE:K2 MIDI 1",
+        ]);
+        let inputs = names(&[
+            "system:playback_1",
+            "SMRITI:master_left",
+            "XoneK2-midi:midi_in",
+        ]);
+        assert_eq!(
+            vec![
+                ("ANAHATA-01:out_left", "system:playback_1"),
+                ("ANAHATA-01:out_left", "SMRITI:master_left"),
+            ],
+            rules.connections("ANAHATA-01", &outputs, &inputs)
+        );
+        assert_eq!(
+            vec![(&*outputs[3], "XoneK2-midi:midi_in")],
+            rules.connections("XoneK2-midi", &outputs, &inputs)
+        );
+
+        let mut rules = Rules::parse("  # nothing\n\n/^TALA:/ -> Digi?akt*\n").unwrap();
+        rules.push("SMRITI:*", "/.*/").unwrap();
+        let inputs = names(&["Digitakt:midi_in", "system:playback_1"]);
+        assert_eq!(
+            vec![("TALA:clock_out", "Digitakt:midi_in")],
+            rules.connections("TALA", &names(&["TALA:clock_out"]), &inputs)
+        );
+
+        let error = Rules::parse("a -> b\nno arrow here").err().unwrap();
+        assert_eq!(2, error.line);
+        assert!(Rules::parse("/(/ -> b").is_err());
+    }
+}
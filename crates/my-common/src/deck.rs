@@ -0,0 +1,477 @@
+use crate::analysis::WaveformBin;
+use crate::waveform::{downsample, DETAIL_SAMPLES_PER_BIN};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// NATS servers refuse bigger payloads unless configured otherwise.
+pub const MAX_PAYLOAD: usize = 1 << 20;
+
+// Path length, sample rate and bin size, each a little endian u32
+const WAVEFORM_HEADER: usize = 12;
+
+/// Waveform a deck publishes on `anahata.N.waveform` for remote views, in
+/// the binary form of `to_bytes`. NATS caps payloads at 1MB, so bands are
+/// quantised mono RMS and long files get coarser bins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeckWaveform {
+    pub path: String,
+    pub sample_rate: u32,
+    pub samples_per_bin: usize,
+    /// Low, mid and high RMS per bin, 0..=255
+    pub bands: Vec<[u8; 3]>,
+}
+
+impl DeckWaveform {
+    pub fn from_detail(path: String, detail: &[WaveformBin], sample_rate: u32) -> Self {
+        Self::fitting(path, detail, sample_rate, MAX_PAYLOAD)
+    }
+
+    fn fitting(path: String, detail: &[WaveformBin], sample_rate: u32, max_payload: usize) -> Self {
+        let quantise =
+            |left: f32, right: f32| ((left + right) * 0.5 * 255.0).clamp(0.0, 255.0) as u8;
+        // Half the detail resolution, about 20ms per bin at 48kHz, and
+        // halved again for as long as it's too big to publish. That's past
+        // an hour and a half, a recorded set rather than a track.
+        let mut bins = downsample(detail);
+        let mut samples_per_bin = DETAIL_SAMPLES_PER_BIN * 2;
+        while WAVEFORM_HEADER + path.len() + bins.len() * 3 > max_payload && bins.len() > 1 {
+            bins = downsample(&bins);
+            samples_per_bin *= 2;
+        }
+        let bands = bins
+            .iter()
+            .map(|bin| {
+                [
+                    quantise(bin.low.rms_left, bin.low.rms_right),
+                    quantise(bin.mid.rms_left, bin.mid.rms_right),
+                    quantise(bin.high.rms_left, bin.high.rms_right),
+                ]
+            })
+            .collect();
+        Self {
+            path,
+            sample_rate,
+            samples_per_bin,
+            bands,
+        }
+    }
+
+    /// The path length, path, sample rate and bin size, then three bytes a
+    /// bin. JSON took four times the space.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(WAVEFORM_HEADER + self.path.len() + self.bands.len() * 3);
+        bytes.extend_from_slice(&(self.path.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.path.as_bytes());
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&(self.samples_per_bin as u32).to_le_bytes());
+        bytes.extend(self.bands.iter().flatten());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |at: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+        };
+        let path_len = u32_at(0)? as usize;
+        let path = String::from_utf8(bytes.get(4..4 + path_len)?.to_vec()).ok()?;
+        let sample_rate = u32_at(4 + path_len)?;
+        let samples_per_bin = u32_at(8 + path_len)? as usize;
+        let bands = &bytes[WAVEFORM_HEADER + path_len..];
+        if !bands.len().is_multiple_of(3) {
+            return None;
+        }
+        Some(Self {
+            path,
+            sample_rate,
+            samples_per_bin,
+            bands: bands
+                .chunks_exact(3)
+                .map(|bin| [bin[0], bin[1], bin[2]])
+                .collect(),
+        })
+    }
+}
+
+/// `anahata.N.position` is `"<playhead in samples>,<0|1 playing>"`, then
+/// `",<speed>"` when the deck isn't playing at 1.0, as under timecode.
+pub fn format_position(playhead: u64, playing: bool, speed: f64) -> String {
+    if speed == 1.0 {
+        format!("{},{}", playhead, playing as u8)
+    } else {
+        format!("{},{},{}", playhead, playing as u8, speed)
+    }
+}
+
+pub fn parse_position(payload: &str) -> Option<(u64, bool, f64)> {
+    let mut fields = payload.split(',');
+    let playhead = fields.next()?.parse().ok()?;
+    let playing = fields.next()? == "1";
+    let speed = match fields.next() {
+        Some(speed) => speed.parse().ok().filter(|speed: &f64| speed.is_finite())?,
+        None => 1.0,
+    };
+    Some((playhead, playing, speed))
+}
+
+/// Snapshot of a deck, published as JSON on `anahata.N.state` and returned
+/// from `anahata.N.state.get`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeckState {
+    pub path: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub position_ms: u64,
+    pub duration_ms: u64,
+    pub playing: bool,
+    /// Playback rate, 1.0 is the original speed
+    pub tempo: f64,
+    /// Track BPM scaled by `tempo`
+    pub bpm: Option<f64>,
+    /// Loop start and end in samples while a loop is engaged
+    pub loop_range: Option<(u64, u64)>,
+    /// A new track is being decoded in the background
+    pub loading: bool,
+    /// Loads are refused while playing unless forced
+    pub load_lock: bool,
+    /// Play starts and skips land on the beatgrid
+    #[serde(default)]
+    pub quantize: bool,
+    pub end_of_track: EndOfTrack,
+    /// Less than `END_WARNING_SECONDS` left while playing
+    pub end_warning: bool,
+}
+
+pub const END_WARNING_SECONDS: u64 = 30;
+
+/// Published once a second on `anahata.N.health`. Underruns are the deck
+/// not keeping up, xruns and DSP load are JACK and the rest of the system.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeckHealth {
+    /// Process cycles where the ring buffer ran dry while playing
+    pub underruns: u64,
+    pub xruns: u64,
+    /// JACK DSP load, percent
+    pub dsp_load: f32,
+    /// This process, percent of one core
+    pub cpu_percent: f32,
+    pub rss_bytes: u64,
+    pub threads: i64,
+}
+
+/// What a deck does when it plays past the last sample. Set with
+/// `anahata.N.endoftrack` and a payload of `stop`, `loop` or `next`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndOfTrack {
+    #[default]
+    Stop,
+    Loop,
+    /// Ask AKASHA for the track after this one on `akasha.N.next`
+    Next,
+}
+
+impl EndOfTrack {
+    pub fn parse(s: &str) -> Option<EndOfTrack> {
+        match s.trim() {
+            "stop" => Some(EndOfTrack::Stop),
+            "loop" => Some(EndOfTrack::Loop),
+            "next" => Some(EndOfTrack::Next),
+            _ => None,
+        }
+    }
+}
+
+/// How far a skip or nudge moves the playhead. Payloads on the skip and
+/// nudge subjects are written as `5s`, `4b` or `480smp`, a bare number is
+/// seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jump {
+    Seconds(f64),
+    Samples(i64),
+    Beats(f64),
+}
+
+impl Jump {
+    pub fn parse(s: &str) -> Option<Jump> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        match unit.trim() {
+            "" | "s" | "sec" | "seconds" => number.parse().ok().map(Jump::Seconds),
+            "b" | "beat" | "beats" => number.parse().ok().map(Jump::Beats),
+            "smp" | "samples" => number.parse().ok().map(Jump::Samples),
+            _ => None,
+        }
+    }
+
+    pub fn reversed(self) -> Jump {
+        match self {
+            Jump::Seconds(s) => Jump::Seconds(-s),
+            Jump::Samples(n) => Jump::Samples(-n),
+            Jump::Beats(b) => Jump::Beats(-b),
+        }
+    }
+
+    /// Playhead in samples after the jump. Beat jumps keep the distance to
+    /// the nearest beat and need a beatgrid of at least two beats.
+    pub fn target(self, playhead: u64, sample_rate: f64, beat_times: &[f64]) -> Option<u64> {
+        let target = match self {
+            Jump::Seconds(s) => playhead as f64 + s * sample_rate,
+            Jump::Samples(n) => playhead as f64 + n as f64,
+            Jump::Beats(b) => {
+                let beat = beat_at(beat_times, playhead as f64 / sample_rate)?;
+                time_at_beat(beat_times, beat + b)? * sample_rate
+            }
+        };
+        Some(target.max(0.0).round() as u64)
+    }
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Jump::Seconds(s) => write!(f, "{}s", s),
+            Jump::Samples(n) => write!(f, "{}smp", n),
+            Jump::Beats(b) => write!(f, "{}b", b),
+        }
+    }
+}
+
+/// Fractional beat number at `time` seconds, extrapolated from the first or
+/// last beat interval outside the grid.
+pub fn beat_at(beat_times: &[f64], time: f64) -> Option<f64> {
+    let interval = grid_interval(beat_times, time)?;
+    let (start, end) = (beat_times[interval], beat_times[interval + 1]);
+    Some(interval as f64 + (time - start) / (end - start))
+}
+
+/// Inverse of `beat_at`.
+pub fn time_at_beat(beat_times: &[f64], beat: f64) -> Option<f64> {
+    if beat_times.len() < 2 {
+        return None;
+    }
+    let interval = (beat.floor().max(0.0) as usize).min(beat_times.len() - 2);
+    let (start, end) = (beat_times[interval], beat_times[interval + 1]);
+    (end > start).then_some(start + (beat - interval as f64) * (end - start))
+}
+
+/// Time of the beat closest to `time` seconds, where a quantized deck snaps.
+pub fn nearest_beat(beat_times: &[f64], time: f64) -> Option<f64> {
+    time_at_beat(beat_times, beat_at(beat_times, time)?.round())
+}
+
+/// Time of the first beat at or after `time` seconds.
+pub fn next_beat(beat_times: &[f64], time: f64) -> Option<f64> {
+    // Rounding error in beat_at shouldn't push a beat a whole beat away
+    let beat = beat_at(beat_times, time)?;
+    let next = if (beat - beat.round()).abs() < 1e-9 {
+        beat.round()
+    } else {
+        beat.ceil()
+    };
+    time_at_beat(beat_times, next)
+}
+
+/// Tempo of the beat interval around `time` seconds.
+pub fn tempo_at(beat_times: &[f64], time: f64) -> Option<f64> {
+    let interval = grid_interval(beat_times, time)?;
+    Some(60.0 / (beat_times[interval + 1] - beat_times[interval]))
+}
+
+/// Beats in a bar. Grids carry no downbeats, so bars are counted in 4/4
+/// from the first beat of the grid.
+pub const BEATS_PER_BAR: u32 = 4;
+/// Resolution of `BarBeat::tick`, what most JACK timebase masters use.
+pub const TICKS_PER_BEAT: u32 = 1920;
+
+/// A position the way JACK transport and sequencers count it: bar and beat
+/// from 1, ticks from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarBeat {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+impl BarBeat {
+    /// From a fractional beat number as `beat_at` returns it. `None` before
+    /// the first beat, which has no bar to be in.
+    pub fn from_beat(beat: f64) -> Option<Self> {
+        if beat < 0.0 {
+            return None;
+        }
+        let whole = beat.floor() as u64;
+        Some(Self {
+            bar: (whole / BEATS_PER_BAR as u64) as u32 + 1,
+            beat: (whole % BEATS_PER_BAR as u64) as u32 + 1,
+            tick: ((beat - beat.floor()) * TICKS_PER_BEAT as f64) as u32,
+        })
+    }
+}
+
+/// Frames until the next beat from `beat` beats into the timeline, 0 on a
+/// beat. How long a quantized start waits for the JACK timebase master.
+pub fn frames_to_next_beat(beat: f64, bpm: f64, sample_rate: f64) -> usize {
+    let to_beat = (1.0 - beat.rem_euclid(1.0)).fract();
+    (to_beat * 60.0 / bpm * sample_rate).round() as usize
+}
+
+fn grid_interval(beat_times: &[f64], time: f64) -> Option<usize> {
+    if beat_times.len() < 2 {
+        return None;
+    }
+    let interval = beat_times
+        .partition_point(|&beat| beat <= time)
+        .clamp(1, beat_times.len() - 1)
+        - 1;
+    (beat_times[interval + 1] > beat_times[interval]).then_some(interval)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn positions_roundtrip() {
+        assert_eq!("48000,1", format_position(48000, true, 1.0));
+        assert_eq!(Some((48000, true, 1.0)), parse_position("48000,1"));
+        assert_eq!(
+            Some((0, false, 1.0)),
+            parse_position(&format_position(0, false, 1.0))
+        );
+        assert_eq!(
+            Some((96, true, -0.5)),
+            parse_position(&format_position(96, true, -0.5))
+        );
+        assert_eq!(None, parse_position("48000"));
+        assert_eq!(None, parse_position("-1,1"));
+        assert_eq!(None, parse_position("48000,1,fast"));
+    }
+
+    #[test]
+    fn waveforms_fit_in_a_message() {
+        let bin = |rms: f32| {
+            let band = |rms| crate::analysis::FrequencyBand {
+                rms_left: rms,
+                rms_right: rms,
+                ..Default::default()
+            };
+            WaveformBin {
+                low: band(rms),
+                mid: band(0.0),
+                high: band(2.0),
+            }
+        };
+        let detail = vec![bin(0.5); 8];
+        let waveform = DeckWaveform::from_detail(String::from("a.flac"), &detail, 48000);
+        assert_eq!(4, waveform.bands.len(), "half the detail resolution");
+        assert_eq!([127, 0, 255], waveform.bands[0], "clamped to a byte");
+        assert_eq!(DETAIL_SAMPLES_PER_BIN * 2, waveform.samples_per_bin);
+
+        let bytes = waveform.to_bytes();
+        assert_eq!(12 + 6 + 4 * 3, bytes.len());
+        assert_eq!(Some(waveform), DeckWaveform::from_bytes(&bytes));
+        assert_eq!(None, DeckWaveform::from_bytes(&bytes[..bytes.len() - 1]));
+        assert_eq!(None, DeckWaveform::from_bytes(b"{}"));
+
+        // Too long for the limit, coarser bins instead
+        let long = DeckWaveform::fitting(String::from("a.flac"), &detail, 48000, 12 + 6 + 6);
+        assert_eq!(2, long.bands.len());
+        assert_eq!(DETAIL_SAMPLES_PER_BIN * 4, long.samples_per_bin);
+    }
+
+    #[test]
+    fn parse_jumps() {
+        assert_eq!(Some(Jump::Seconds(5.0)), Jump::parse("5"));
+        assert_eq!(Some(Jump::Seconds(0.5)), Jump::parse("0.5s"));
+        assert_eq!(Some(Jump::Beats(-4.0)), Jump::parse("-4b"));
+        assert_eq!(Some(Jump::Beats(16.0)), Jump::parse("16 beats"));
+        assert_eq!(Some(Jump::Samples(480)), Jump::parse("480smp"));
+        assert_eq!(None, Jump::parse("na"));
+        assert_eq!(None, Jump::parse(""));
+        assert_eq!(
+            Some(Jump::Beats(4.0)),
+            Jump::parse(&Jump::Beats(4.0).to_string())
+        );
+    }
+
+    #[test]
+    fn jumps_by_time() {
+        assert_eq!(
+            Some(48000 * 6),
+            Jump::Seconds(5.0).target(48000, 48000.0, &[])
+        );
+        assert_eq!(Some(0), Jump::Seconds(-5.0).target(48000, 48000.0, &[]));
+        assert_eq!(Some(1000), Jump::Samples(-480).target(1480, 48000.0, &[]));
+    }
+
+    #[test]
+    fn jumps_by_beats_keeping_phase() {
+        // 120 BPM with the first beat at 1s
+        let beats: Vec<f64> = (0..8).map(|i| 1.0 + i as f64 * 0.5).collect();
+        let at = |t: f64| (t * 1000.0) as u64;
+        assert_eq!(
+            Some(at(3.1)),
+            Jump::Beats(4.0).target(at(1.1), 1000.0, &beats)
+        );
+        assert_eq!(
+            Some(at(1.1)),
+            Jump::Beats(-4.0).target(at(3.1), 1000.0, &beats)
+        );
+        // Past the end of the grid keeps the last tempo
+        assert_eq!(
+            Some(at(5.5)),
+            Jump::Beats(4.0).target(at(3.5), 1000.0, &beats)
+        );
+        assert_eq!(
+            Some(at(0.5)),
+            Jump::Beats(-1.0).target(at(1.0), 1000.0, &beats)
+        );
+        assert_eq!(None, Jump::Beats(4.0).target(0, 1000.0, &[1.0]));
+    }
+
+    #[test]
+    fn snaps_to_the_grid() {
+        let beats = [1.0, 1.5, 2.0, 2.5];
+        assert_eq!(Some(1.5), nearest_beat(&beats, 1.7));
+        assert_eq!(Some(2.0), nearest_beat(&beats, 1.8));
+        assert_eq!(Some(2.0), next_beat(&beats, 1.6));
+        assert_eq!(Some(2.0), next_beat(&beats, 2.0));
+        // Outside the grid the first and last intervals carry on
+        assert_eq!(Some(0.5), nearest_beat(&beats, 0.6));
+        assert_eq!(Some(3.0), next_beat(&beats, 2.7));
+        assert_eq!(None, next_beat(&[1.0], 0.0));
+    }
+
+    #[test]
+    fn tempo_and_bars_from_the_grid() {
+        // 120 BPM speeding up to 150 after the fourth beat
+        let beats = [1.0, 1.5, 2.0, 2.5, 3.0, 3.4, 3.8];
+        assert_eq!(Some(120.0), tempo_at(&beats, 0.0));
+        assert_eq!(Some(120.0), tempo_at(&beats, 2.9));
+        assert!((tempo_at(&beats, 3.5).unwrap() - 150.0).abs() < 1e-9);
+        assert_eq!(None, tempo_at(&[1.0], 1.0));
+
+        let at = |time| BarBeat::from_beat(beat_at(&beats, time).unwrap());
+        assert_eq!(None, at(0.5));
+        let first = BarBeat {
+            bar: 1,
+            beat: 1,
+            tick: 0,
+        };
+        assert_eq!(Some(first), at(1.0));
+        let second_bar = BarBeat {
+            bar: 2,
+            beat: 1,
+            tick: TICKS_PER_BEAT / 2,
+        };
+        assert_eq!(Some(second_bar), at(3.2));
+
+        // A quarter beat left at 120 BPM
+        assert_eq!(6000, frames_to_next_beat(4.75, 120.0, 48000.0));
+        assert_eq!(0, frames_to_next_beat(5.0, 120.0, 48000.0));
+    }
+}
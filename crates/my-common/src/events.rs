@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead};
+
+/// One line of a control event log, the JSON lines file SMRITI writes next
+/// to a recording and `ANAHATA --render` plays back.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    /// Seconds since the log started
+    pub time: f64,
+    pub subject: String,
+    pub payload: String,
+}
+
+/// Deck subjects that change what a deck plays, as opposed to what it
+/// reports about itself.
+const DECK_CONTROLS: [&str; 19] = [
+    "select",
+    "select.force",
+    "select.next",
+    "seek",
+    "cue",
+    "cue.set",
+    "loopin",
+    "loopout",
+    "loopexit",
+    "stop",
+    "endoftrack",
+    "loadlock",
+    "quantize",
+    "skip.default",
+    "nudge.default",
+    "skipforward",
+    "skipbackward",
+    "nudgeforward",
+    "nudgebackward",
+];
+
+/// Whether a subject belongs in the log: deck controls, beatgrids and fader
+/// moves. The log starts with an `anahata.N.state` snapshot per deck, later
+/// ones are output and left out.
+pub fn is_control_subject(subject: &str) -> bool {
+    if subject == "drishti.result" || subject == "xone.fader" {
+        return true;
+    }
+    subject
+        .strip_prefix("anahata.")
+        .and_then(|rest| rest.split_once('.'))
+        .is_some_and(|(number, control)| {
+            number.parse::<u32>().is_ok() && DECK_CONTROLS.contains(&control)
+        })
+}
+
+/// Reads a log, skipping lines that don't parse such as a last line cut
+/// short by a crash.
+pub fn read_log(reader: impl BufRead) -> io::Result<Vec<LoggedEvent>> {
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        if let Ok(event) = serde_json::from_str(&line?) {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn control_subjects_and_log_lines() {
+        assert!(is_control_subject("anahata.1.select"));
+        assert!(is_control_subject("anahata.2.select.force"));
+        assert!(is_control_subject("anahata.2.select.next"));
+        assert!(is_control_subject("anahata.1.seek"));
+        assert!(is_control_subject("anahata.1.cue.set"));
+        assert!(is_control_subject("anahata.2.loopout"));
+        assert!(is_control_subject("anahata.12.nudgebackward"));
+        assert!(is_control_subject("xone.fader"));
+        assert!(!is_control_subject("anahata.1.position"));
+        assert!(!is_control_subject("anahata.1.state"));
+        assert!(!is_control_subject("anahata.refresh"));
+        assert!(!is_control_subject("anahata.x.stop"));
+
+        let event = LoggedEvent {
+            time: 1.5,
+            subject: "anahata.1.stop".into(),
+            payload: "na".into(),
+        };
+        let log = format!(
+            "{}\n{{\"time\":2.0,\"subj",
+            serde_json::to_string(&event).unwrap()
+        );
+        assert_eq!(vec![event], read_log(log.as_bytes()).unwrap());
+    }
+}
@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::fmt;
+
+const PITCH_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+];
+
+// Krumhansl-Kessler key profiles, index 0 is the tonic.
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+/// A musical key, `tonic` is a pitch class where 0 is C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Key {
+    pub tonic: u8,
+    pub mode: Mode,
+}
+
+impl Key {
+    pub fn new(tonic: u8, mode: Mode) -> Self {
+        Self {
+            tonic: tonic % 12,
+            mode,
+        }
+    }
+
+    /// Position on the Camelot wheel, 1..=12.
+    pub fn camelot_number(&self) -> u8 {
+        // Minor keys sit on the same number as their relative major
+        let major_tonic = match self.mode {
+            Mode::Major => self.tonic,
+            Mode::Minor => (self.tonic + 3) % 12,
+        };
+        // Walking the circle of fifths, C major is 8B
+        ((major_tonic as u32 * 7 + 7) % 12) as u8 + 1
+    }
+
+    /// Camelot notation, e.g. `8A` for A minor and `8B` for C major.
+    pub fn camelot(&self) -> String {
+        let letter = match self.mode {
+            Mode::Major => 'B',
+            Mode::Minor => 'A',
+        };
+        format!("{}{}", self.camelot_number(), letter)
+    }
+
+    /// Open Key notation, e.g. `1m` for A minor and `1d` for C major.
+    pub fn open_key(&self) -> String {
+        let letter = match self.mode {
+            Mode::Major => 'd',
+            Mode::Minor => 'm',
+        };
+        format!("{}{}", (self.camelot_number() + 4) % 12 + 1, letter)
+    }
+
+    /// Keys that mix harmonically: the same key, one step around the
+    /// wheel, or the relative major/minor.
+    pub fn is_compatible(&self, other: &Key) -> bool {
+        let a = self.camelot_number() as i32;
+        let b = other.camelot_number() as i32;
+        let distance = (a - b).rem_euclid(12).min((b - a).rem_euclid(12));
+        if self.mode == other.mode {
+            distance <= 1
+        } else {
+            distance == 0
+        }
+    }
+
+    /// Parses the notations taggers put in `INITIALKEY`: Camelot (`8A`),
+    /// Open Key (`1m`) or a plain name (`Am`, `C#`, `Bb minor`).
+    pub fn parse(s: &str) -> Option<Key> {
+        let s = s.trim();
+        let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+        if !digits.is_empty() {
+            let number: u8 = digits.parse().ok().filter(|n| (1..=12).contains(n))?;
+            let rest = &s[digits.len()..];
+            let (camelot, mode) = match rest {
+                "A" | "a" => (number, Mode::Minor),
+                "B" | "b" => (number, Mode::Major),
+                "m" => ((number + 6) % 12 + 1, Mode::Minor),
+                "d" => ((number + 6) % 12 + 1, Mode::Major),
+                _ => return None,
+            };
+            return (0..12)
+                .map(|tonic| Key::new(tonic, mode))
+                .find(|key| key.camelot_number() == camelot);
+        }
+
+        let mut chars = s.chars();
+        let mut tonic: i32 = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        let mut rest = chars.as_str();
+        if let Some(r) = rest.strip_prefix(['#', '♯']) {
+            tonic += 1;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix(['b', '♭']) {
+            tonic -= 1;
+            rest = r;
+        }
+        let mode = match rest.trim().to_ascii_lowercase().as_str() {
+            "" | "maj" | "major" => Mode::Major,
+            "m" | "min" | "minor" => Mode::Minor,
+            _ => return None,
+        };
+        Some(Key::new(tonic.rem_euclid(12) as u8, mode))
+    }
+
+    /// Picks the key whose profile correlates best with a 12 bin chroma
+    /// vector (index 0 is C).
+    pub fn from_chroma(chroma: &[f32; 12]) -> Option<Key> {
+        if chroma.iter().all(|&c| c <= 0.0) {
+            return None;
+        }
+
+        let mut best = None;
+        let mut best_score = f32::MIN;
+        for tonic in 0..12u8 {
+            for (mode, profile) in [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)] {
+                let rotated: [f32; 12] =
+                    std::array::from_fn(|i| profile[(i + 12 - tonic as usize) % 12]);
+                let score = correlation(chroma, &rotated);
+                if score > best_score {
+                    best_score = score;
+                    best = Some(Key::new(tonic, mode));
+                }
+            }
+        }
+        best
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = PITCH_NAMES[self.tonic as usize];
+        match self.mode {
+            Mode::Major => write!(f, "{}", name),
+            Mode::Minor => write!(f, "{}m", name),
+        }
+    }
+}
+
+fn correlation(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / 12.0;
+    let mean_b = b.iter().sum::<f32>() / 12.0;
+    let mut num = 0.0;
+    let mut den_a = 0.0;
+    let mut den_b = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        num += (x - mean_a) * (y - mean_b);
+        den_a += (x - mean_a) * (x - mean_a);
+        den_b += (y - mean_b) * (y - mean_b);
+    }
+    if den_a == 0.0 || den_b == 0.0 {
+        return 0.0;
+    }
+    num / (den_a * den_b).sqrt()
+}
+
+/// Accumulates a chroma vector from a mono signal by running Goertzel
+/// filters at every semitone between C2 and B6 over Hann windowed frames.
+pub fn chroma(mono: &[f32], sample_rate: f32) -> [f32; 12] {
+    const FRAME: usize = 8192;
+    const LOWEST_MIDI: u32 = 36; // C2
+    const HIGHEST_MIDI: u32 = 95; // B6
+
+    let window: Vec<f32> = (0..FRAME)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME as f32).cos())
+        .collect();
+    let coeffs: Vec<(usize, f32)> = (LOWEST_MIDI..=HIGHEST_MIDI)
+        .map(|midi| {
+            let freq = 440.0 * 2f32.powf((midi as f32 - 69.0) / 12.0);
+            (
+                (midi % 12) as usize,
+                2.0 * (2.0 * PI * freq / sample_rate).cos(),
+            )
+        })
+        .collect();
+
+    let mut chroma = [0.0f32; 12];
+    let mut frame = vec![0.0f32; FRAME];
+    for chunk in mono.chunks_exact(FRAME) {
+        for ((f, s), w) in frame.iter_mut().zip(chunk).zip(&window) {
+            *f = s * w;
+        }
+        for &(pitch_class, coeff) in &coeffs {
+            let (mut s1, mut s2) = (0.0f32, 0.0f32);
+            for &x in &frame {
+                let s0 = x + coeff * s1 - s2;
+                s2 = s1;
+                s1 = s0;
+            }
+            let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+            chroma[pitch_class] += power.max(0.0).sqrt();
+        }
+    }
+    chroma
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn camelot_and_open_key() {
+        let c_major = Key::new(0, Mode::Major);
+        let a_minor = Key::new(9, Mode::Minor);
+        assert_eq!("8B", c_major.camelot());
+        assert_eq!("8A", a_minor.camelot());
+        assert_eq!("1d", c_major.open_key());
+        assert_eq!("1m", a_minor.open_key());
+        assert_eq!("1B", Key::new(11, Mode::Major).camelot());
+        assert_eq!("6d", Key::new(11, Mode::Major).open_key());
+    }
+
+    #[test]
+    fn parse_notations() {
+        let a_minor = Some(Key::new(9, Mode::Minor));
+        assert_eq!(a_minor, Key::parse("8A"));
+        assert_eq!(a_minor, Key::parse("1m"));
+        assert_eq!(a_minor, Key::parse("Am"));
+        assert_eq!(a_minor, Key::parse("A minor"));
+        assert_eq!(Some(Key::new(1, Mode::Major)), Key::parse("C#"));
+        assert_eq!(Some(Key::new(10, Mode::Major)), Key::parse("Bb"));
+        assert_eq!(None, Key::parse("13A"));
+        assert_eq!(None, Key::parse("H"));
+    }
+
+    #[test]
+    fn compatibility() {
+        let a_minor = Key::new(9, Mode::Minor);
+        assert!(a_minor.is_compatible(&Key::new(0, Mode::Major))); // 8B
+        assert!(a_minor.is_compatible(&Key::new(4, Mode::Minor))); // 9A
+        assert!(a_minor.is_compatible(&Key::new(2, Mode::Minor))); // 7A
+        assert!(!a_minor.is_compatible(&Key::new(7, Mode::Major))); // 9B
+        assert!(Key::new(11, Mode::Major).is_compatible(&Key::new(4, Mode::Major)));
+        // 1B/12B
+    }
+
+    #[test]
+    fn detects_a_minor_triad() {
+        let sample_rate = 12000.0;
+        let freqs = [220.0, 261.63, 329.63, 440.0];
+        let mono: Vec<f32> = (0..sample_rate as usize * 4)
+            .map(|i| {
+                let t = i as f32 / sample_rate;
+                freqs.iter().map(|f| (2.0 * PI * f * t).sin()).sum::<f32>() * 0.25
+            })
+            .collect();
+        let key = Key::from_chroma(&chroma(&mono, sample_rate));
+        assert_eq!(Some(Key::new(9, Mode::Minor)), key);
+    }
+}
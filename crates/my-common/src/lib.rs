@@ -1,3 +1,12 @@
+pub mod analysis;
+pub mod connections;
+pub mod deck;
+pub mod events;
+pub mod key;
+pub mod metadata;
+pub mod session;
+pub mod waveform;
+
 use std::{borrow::Cow, future::Future};
 use tokio::sync::mpsc;
 
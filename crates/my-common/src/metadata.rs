@@ -0,0 +1,98 @@
+use crate::key::Key;
+use image::{ImageReader, RgbaImage};
+use metaflac::block::PictureType;
+use metaflac::Tag;
+use std::io::Cursor;
+use std::path::Path;
+
+/// What the tags say about a track, read the same way for the library
+/// listing and the deck header.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub label: Option<String>,
+    pub year: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    pub bpm: Option<f64>,
+    pub key: Option<Key>,
+    /// The front cover as stored, JPEG usually
+    pub cover: Option<Vec<u8>>,
+}
+
+impl TrackMetadata {
+    /// Nothing if the file has no tags or isn't a FLAC file.
+    pub fn read(path: &Path) -> Option<Self> {
+        Tag::read_from_path(path)
+            .ok()
+            .map(|tag| Self::from_tag(&tag))
+    }
+
+    pub fn from_tag(tag: &Tag) -> Self {
+        // The first of several names taggers use that has a value
+        let first = |keys: &[&str]| {
+            keys.iter()
+                .filter_map(|key| tag.get_vorbis(key)?.next())
+                .map(str::trim)
+                .find(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            title: first(&["TITLE"]),
+            artist: first(&["ARTIST"]),
+            album: first(&["ALBUM"]),
+            label: first(&["LABEL", "ORGANIZATION", "PUBLISHER"]),
+            // DATE is often a full date, only the year fits the header
+            year: first(&["DATE", "YEAR"]).map(|date| date.chars().take(4).collect()),
+            genre: first(&["GENRE"]),
+            comment: first(&["COMMENT", "DESCRIPTION"]),
+            bpm: first(&["BPM", "TEMPO"])
+                .and_then(|bpm| bpm.parse().ok())
+                .filter(|bpm: &f64| *bpm > 0.0),
+            // Taggers disagree on the name, Traktor and Mixxx use INITIALKEY
+            key: first(&["INITIALKEY", "KEY"]).and_then(|key| Key::parse(&key)),
+            cover: tag
+                .pictures()
+                .find(|picture| picture.picture_type == PictureType::CoverFront)
+                .map(|picture| picture.data.clone()),
+        }
+    }
+}
+
+/// Decodes cover art for display, sizing is up to the view.
+pub fn decode_cover(data: &[u8]) -> image::ImageResult<RgbaImage> {
+    Ok(ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .decode()?
+        .into_rgba8())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_what_taggers_write() {
+        let mut tag = Tag::new();
+        tag.set_vorbis("title", vec!["Tone"]);
+        tag.set_vorbis("ARTIST", vec!["  "]);
+        tag.set_vorbis("ORGANIZATION", vec!["Fixture Records"]);
+        tag.set_vorbis("DATE", vec!["2019-05-03"]);
+        tag.set_vorbis("BPM", vec!["124.00"]);
+        tag.set_vorbis("KEY", vec!["8A"]);
+        tag.add_picture("image/jpeg", PictureType::Other, vec![1]);
+        tag.add_picture("image/jpeg", PictureType::CoverFront, vec![2]);
+
+        let metadata = TrackMetadata::from_tag(&tag);
+        assert_eq!(Some("Tone"), metadata.title.as_deref());
+        assert_eq!(None, metadata.artist, "blank tags are no tags");
+        assert_eq!(Some("Fixture Records"), metadata.label.as_deref());
+        assert_eq!(Some("2019"), metadata.year.as_deref());
+        assert_eq!(Some(124.0), metadata.bpm);
+        assert_eq!(Key::parse("8A"), metadata.key);
+        assert_eq!(Some(vec![2]), metadata.cover);
+        assert!(decode_cover(&[2]).is_err());
+    }
+}
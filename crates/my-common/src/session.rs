@@ -0,0 +1,115 @@
+use crate::analysis::write_atomic;
+use crate::deck::EndOfTrack;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// What a deck was doing, saved every few seconds so a restart after a crash
+/// or a reboot can pick up where it left off. Cues and the beatgrid come
+/// back with the track from the analysis cache.
+///
+/// Not everything is kept. The speed comes back at 1.0 until a timecode
+/// record sets it again, and a loop or hot cue set while playing is lost,
+/// decks have neither yet. They belong here once they do.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeckSession {
+    pub path: Option<String>,
+    /// Samples from the start of the track
+    pub playhead: u64,
+    pub playing: bool,
+    #[serde(default)]
+    pub end_of_track: EndOfTrack,
+    #[serde(default)]
+    pub load_lock: bool,
+    #[serde(default)]
+    pub quantize: bool,
+}
+
+/// The folder AKASHA was browsing and the entry it had selected.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LibrarySession {
+    pub dir: PathBuf,
+    pub selected: Option<PathBuf>,
+}
+
+/// One JSON file per program (`anahata-1`, `akasha`, ...), replaced whole on
+/// every save so a crash mid-write leaves the previous one.
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    /// Opens `$XDG_STATE_HOME/k2-midi/session`, falling back to
+    /// `~/.local/state`.
+    pub fn open_default() -> io::Result<Self> {
+        let base = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no state directory"))?;
+        Self::open(base.join("k2-midi").join("session"))
+    }
+
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Nothing if it was never saved or can't be read any more.
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        let data = fs::read(self.path(name)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save<T: Serialize>(&self, name: &str, session: &T) -> io::Result<()> {
+        write_atomic(&self.path(name), &serde_json::to_vec_pretty(session)?)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn saves_and_restores() {
+        let dir = std::env::temp_dir().join(format!("session-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = SessionStore::open(&dir).unwrap();
+        assert_eq!(None, store.load::<DeckSession>("anahata-1"));
+
+        let deck = DeckSession {
+            path: Some(String::from("music/track.flac")),
+            playhead: 480_000,
+            playing: true,
+            end_of_track: EndOfTrack::Next,
+            load_lock: true,
+            quantize: true,
+        };
+        store.save("anahata-1", &deck).unwrap();
+        let reopened = SessionStore::open(&dir).unwrap();
+        assert_eq!(Some(deck), reopened.load("anahata-1"));
+
+        // Sessions written before a setting existed still load
+        fs::write(
+            dir.join("anahata-2.json"),
+            r#"{"path": null, "playhead": 0, "playing": false}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(DeckSession::default()),
+            reopened.load::<DeckSession>("anahata-2")
+        );
+        fs::write(dir.join("akasha.json"), b"{ half a fi").unwrap();
+        assert_eq!(None, reopened.load::<LibrarySession>("akasha"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
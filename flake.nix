@@ -118,6 +118,7 @@
               ./Cargo.lock
               ./crates/my-common
               ./crates/my-workspace-hack
+              ./crates/anahata-engine
               crate
             ];
           };
@@ -146,10 +147,25 @@
           cargoExtraArgs = "-p AKASHA";
           src = fileSetForCrate ./crates/AKASHA;
         });
+        DARSHANA = craneLib.buildPackage (individualCrateArgs // {
+          pname = "DARSHANA";
+          cargoExtraArgs = "-p DARSHANA";
+          src = fileSetForCrate ./crates/DARSHANA;
+        });
+        SMRITI = craneLib.buildPackage (individualCrateArgs // {
+          pname = "SMRITI";
+          cargoExtraArgs = "-p SMRITI";
+          src = fileSetForCrate ./crates/SMRITI;
+        });
+        TALA = craneLib.buildPackage (individualCrateArgs // {
+          pname = "TALA";
+          cargoExtraArgs = "-p TALA";
+          src = fileSetForCrate ./crates/TALA;
+        });
       in {
         checks = {
           # Build the crates as part of `nix flake check` for convenience
-          inherit SARASVATI AKASHA ANAHATA;
+          inherit SARASVATI AKASHA ANAHATA DARSHANA SMRITI TALA;
 
           # Run clippy (and deny all warnings) on the workspace source,
           # again, reusing the dependency artifacts from above.
@@ -208,7 +224,7 @@
         };
 
         packages = {
-          inherit SARASVATI AKASHA ANAHATA;
+          inherit SARASVATI AKASHA ANAHATA DARSHANA SMRITI TALA;
         } // lib.optionalAttrs (!pkgs.stdenv.isDarwin) {
           my-workspace-llvm-coverage = craneLibLLvmTools.cargoLlvmCov
             (commonArgs // { inherit cargoArtifacts; });
@@ -218,6 +234,9 @@
           SARASVATI = flake-utils.lib.mkApp { drv = SARASVATI; };
           AKASHA = flake-utils.lib.mkApp { drv = AKASHA; };
           ANAHATA = flake-utils.lib.mkApp { drv = ANAHATA; };
+          DARSHANA = flake-utils.lib.mkApp { drv = DARSHANA; };
+          SMRITI = flake-utils.lib.mkApp { drv = SMRITI; };
+          TALA = flake-utils.lib.mkApp { drv = TALA; };
         };
 
         devShells.default = craneLib.devShell {